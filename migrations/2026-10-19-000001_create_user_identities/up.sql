@@ -0,0 +1,10 @@
+CREATE TABLE "user_identities"(
+  "id" SERIAL PRIMARY KEY,
+  "user_id" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  "provider" VARCHAR NOT NULL,
+  "subject" VARCHAR NOT NULL,
+  "email" VARCHAR,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  UNIQUE (provider, subject),
+  UNIQUE (user_id, provider)
+);
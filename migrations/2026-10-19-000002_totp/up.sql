@@ -0,0 +1,8 @@
+ALTER TABLE "users" ADD COLUMN "totp_secret" VARCHAR;
+
+CREATE TABLE "recovery_codes"(
+  "id" SERIAL PRIMARY KEY,
+  "user_id" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  "code_hash" VARCHAR NOT NULL,
+  "used_at" TIMESTAMPTZ
+);
@@ -0,0 +1,2 @@
+ALTER TABLE "users" ADD COLUMN "failed_login_count" INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE "users" ADD COLUMN "locked_until" TIMESTAMPTZ;
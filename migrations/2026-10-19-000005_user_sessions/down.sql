@@ -0,0 +1,4 @@
+DROP TABLE "user_sessions";
+
+ALTER TABLE "users" DROP COLUMN "password_reset_required";
+ALTER TABLE "users" DROP COLUMN "disabled_at";
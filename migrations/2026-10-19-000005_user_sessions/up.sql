@@ -0,0 +1,12 @@
+ALTER TABLE "users" ADD COLUMN "disabled_at" TIMESTAMPTZ;
+ALTER TABLE "users" ADD COLUMN "password_reset_required" BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE "user_sessions"(
+  "id" SERIAL PRIMARY KEY,
+  "user_id" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  "token_hash" VARCHAR NOT NULL UNIQUE,
+  "ip" VARCHAR,
+  "user_agent" VARCHAR,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  "last_seen_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,3 @@
+DROP TABLE "audit_log";
+
+ALTER TABLE "user_sessions" DROP COLUMN "impersonator_id";
@@ -0,0 +1,14 @@
+-- set on sessions an admin started as another user
+ALTER TABLE "user_sessions" ADD COLUMN "impersonator_id" INTEGER REFERENCES users(id) ON DELETE CASCADE;
+
+-- usernames are copied so entries still read after a user is deleted
+CREATE TABLE "audit_log"(
+  "id" SERIAL PRIMARY KEY,
+  "actor_id" INTEGER REFERENCES users(id) ON DELETE SET NULL,
+  "actor_username" VARCHAR NOT NULL,
+  "target_id" INTEGER REFERENCES users(id) ON DELETE SET NULL,
+  "target_username" VARCHAR,
+  "action" VARCHAR NOT NULL,
+  "ip" VARCHAR,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
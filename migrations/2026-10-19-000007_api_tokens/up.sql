@@ -0,0 +1,10 @@
+CREATE TABLE "api_tokens"(
+  "id" SERIAL PRIMARY KEY,
+  "user_id" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  "name" VARCHAR NOT NULL,
+  "token_hash" VARCHAR NOT NULL UNIQUE,
+  "scope" VARCHAR NOT NULL CHECK ("scope" IN ('read', 'write')),
+  "expires_at" TIMESTAMPTZ,
+  "last_used_at" TIMESTAMPTZ,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,23 @@
+CREATE TABLE "webhooks"(
+  "id" SERIAL PRIMARY KEY,
+  "user_id" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  "url" VARCHAR NOT NULL,
+  "secret" VARCHAR NOT NULL,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE "webhook_deliveries"(
+  "id" SERIAL PRIMARY KEY,
+  "webhook_id" INTEGER NOT NULL REFERENCES webhooks(id) ON DELETE CASCADE,
+  "event" VARCHAR NOT NULL,
+  "payload" TEXT NOT NULL,
+  "status" VARCHAR NOT NULL DEFAULT 'pending' CHECK ("status" IN ('pending', 'delivered', 'failed')),
+  "attempts" INTEGER NOT NULL DEFAULT 0,
+  "next_attempt_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  "response_status" INTEGER,
+  "last_error" VARCHAR,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  "delivered_at" TIMESTAMPTZ
+);
+
+CREATE INDEX "webhook_deliveries_pending" ON "webhook_deliveries"("next_attempt_at") WHERE "status" = 'pending';
@@ -0,0 +1,15 @@
+CREATE TABLE "jobs"(
+  "id" SERIAL PRIMARY KEY,
+  "kind" VARCHAR NOT NULL,
+  "payload" JSONB NOT NULL,
+  "status" VARCHAR NOT NULL DEFAULT 'pending' CHECK ("status" IN ('pending', 'running', 'done', 'dead')),
+  "attempts" INTEGER NOT NULL DEFAULT 0,
+  "max_attempts" INTEGER NOT NULL,
+  "run_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  "locked_at" TIMESTAMPTZ,
+  "last_error" VARCHAR,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+  "finished_at" TIMESTAMPTZ
+);
+
+CREATE INDEX "jobs_pending" ON "jobs"("run_at") WHERE "status" = 'pending';
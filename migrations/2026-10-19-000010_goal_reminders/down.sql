@@ -0,0 +1,3 @@
+DROP TABLE "goal_reminders";
+ALTER TABLE "goals" DROP COLUMN "target_date";
+ALTER TABLE "users" DROP COLUMN "time_zone";
@@ -0,0 +1,20 @@
+ALTER TABLE "users" ADD COLUMN "time_zone" VARCHAR NOT NULL DEFAULT 'UTC';
+ALTER TABLE "goals" ADD COLUMN "target_date" DATE;
+
+CREATE TABLE "goal_reminders"(
+  "id" SERIAL PRIMARY KEY,
+  "goal_id" INTEGER NOT NULL REFERENCES goals(id) ON DELETE CASCADE,
+  "kind" VARCHAR NOT NULL CHECK ("kind" IN ('weekly', 'before_target')),
+  -- weekly only, bit 0 is monday
+  "weekdays" INTEGER NOT NULL DEFAULT 0,
+  -- before_target only
+  "days_before" INTEGER NOT NULL DEFAULT 0,
+  -- in the user's time zone
+  "time_of_day" TIME NOT NULL,
+  -- null once a one-off reminder has been sent
+  "next_run_at" TIMESTAMPTZ,
+  "last_sent_at" TIMESTAMPTZ,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX "goal_reminders_due" ON "goal_reminders"("next_run_at") WHERE "next_run_at" IS NOT NULL;
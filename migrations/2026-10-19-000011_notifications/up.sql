@@ -0,0 +1,14 @@
+CREATE TABLE "notifications"(
+  "id" SERIAL PRIMARY KEY,
+  "user_id" INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  "kind" VARCHAR NOT NULL,
+  "title" VARCHAR NOT NULL,
+  "body" TEXT NOT NULL DEFAULT '',
+  -- where clicking the notification goes, a path in the app
+  "link" VARCHAR,
+  "read_at" TIMESTAMPTZ,
+  "created_at" TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX "notifications_user_id" ON "notifications"("user_id", "created_at");
+CREATE INDEX "notifications_unread" ON "notifications"("user_id") WHERE "read_at" IS NULL;
@@ -0,0 +1,2 @@
+-- the newest totp time step a login accepted, so a code can't be used twice
+ALTER TABLE "users" ADD COLUMN "totp_last_step" BIGINT;
@@ -0,0 +1,2 @@
+-- set when the goal is marked done, cleared again if it is reopened
+ALTER TABLE "goals" ADD COLUMN "completed_at" TIMESTAMPTZ;
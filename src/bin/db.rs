@@ -1,20 +1,34 @@
-use std::io::{StdinLock, StdoutLock, Write, stdin, stdout};
-
-use axum_boilerplate::db::{
-    establish_connection,
-    models::{
-        EmailAddress, Goal, NewGoal, NewUser, User,
-        goal::create_new_goal,
-        user::{create_new_user, hash_password},
+use std::{
+    io::{StdinLock, StdoutLock, Write, stdin, stdout},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum_boilerplate::{
+    config::Config,
+    db::{
+        establish_connection, get_connection_pool,
+        models::{
+            EmailAddress, Goal, Job, NewGoal, NewUser, Role, User,
+            goal::create_new_goal,
+            job::{JobStatus, get_jobs, retry_job},
+            totp::disable_totp,
+            user::{create_new_user, hash_password, set_user_role, validate_password},
+        },
+        schema::{jobs, users},
     },
-    schema::{goals, users},
+    jobs::{JobContext, registry, reminders::spawn_reminder_scheduler, spawn_job_workers},
+    mailer::mailer_from_config,
+    shutdown,
 };
 use diesel::{debug_query, pg::Pg, prelude::*};
 
 use termion::input::TermRead;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
 use clap::{Parser, Subcommand};
-use tracing::{debug, info};
+use tracing::debug;
 use validator::Validate;
 
 #[derive(Debug, Parser)]
@@ -30,14 +44,31 @@ enum Commands {
 
     #[command(subcommand)]
     Goal(GoalCommands),
+
+    #[command(subcommand)]
+    Job(JobCommands),
 }
 
 #[derive(Debug, Subcommand)]
 enum UserCommands {
     New,
     Show,
-    Edit { id: i32 },
-    Delete { id: i32 },
+    Edit {
+        id: i32,
+    },
+    Delete {
+        id: i32,
+    },
+    /// Turn off two-factor authentication for a locked out user
+    #[command(name = "reset-2fa")]
+    Reset2fa {
+        id: i32,
+    },
+    /// Set a user's role, e.g. to make the first admin
+    Role {
+        id: i32,
+        role: Role,
+    },
 }
 
 #[derive(Debug, Subcommand)]
@@ -46,6 +77,28 @@ enum GoalCommands {
     Show { user_id: Option<i32> },
 }
 
+#[derive(Debug, Subcommand)]
+enum JobCommands {
+    /// List the newest jobs, e.g. `--status dead` for the dead letters
+    List {
+        #[arg(long)]
+        status: Option<JobStatus>,
+        #[arg(long, default_value_t = 20)]
+        limit: i64,
+    },
+    /// Show a job with its payload and last error
+    Show { id: i32 },
+    /// Put a dead job back in the queue with fresh attempts
+    Retry { id: i32 },
+    /// Queue a job by kind, e.g. `prune_webhook_deliveries '{"older_than_days": 30}'`
+    Enqueue { kind: String, payload: String },
+    /// Run job workers in the foreground, for when the webapp runs with JOB_WORKERS=0
+    Work {
+        #[arg(long, default_value_t = 2)]
+        workers: usize,
+    },
+}
+
 fn main() {
     tracing_subscriber::fmt::init();
 
@@ -65,6 +118,12 @@ fn main() {
             UserCommands::Delete { id } => {
                 delete_user_by_id(*id);
             }
+            UserCommands::Reset2fa { id } => {
+                reset_user_2fa(*id);
+            }
+            UserCommands::Role { id, role } => {
+                set_role(*id, *role);
+            }
         },
         Commands::Goal(goal_command) => match goal_command {
             GoalCommands::New => {
@@ -74,6 +133,23 @@ fn main() {
                 show_goals(*user_id);
             }
         },
+        Commands::Job(job_command) => match job_command {
+            JobCommands::List { status, limit } => {
+                list_jobs(*status, *limit);
+            }
+            JobCommands::Show { id } => {
+                show_job(*id);
+            }
+            JobCommands::Retry { id } => {
+                retry_dead_job(*id);
+            }
+            JobCommands::Enqueue { kind, payload } => {
+                enqueue_job(kind, payload);
+            }
+            JobCommands::Work { workers } => {
+                run_job_workers(*workers);
+            }
+        },
     };
 }
 
@@ -106,12 +182,13 @@ fn create_new_user_from_prompt() {
         .unwrap()
         .expect("Username cannot be blank");
 
-    let hashed_password = prompt_and_hash_password(&mut stdin, &mut stdout);
+    let password = prompt_password(&mut stdin, &mut stdout);
     let email = prompt_email(&mut stdin, &mut stdout);
 
-    let new_user = NewUser {
+    let mut new_user = NewUser {
         username: username.trim().to_string(),
-        hashed_password,
+        password,
+        hashed_password: None,
         email,
     };
 
@@ -119,12 +196,17 @@ fn create_new_user_from_prompt() {
         panic!("Validation errors: {:#?}", validation_errors);
     }
 
+    new_user.hashed_password = new_user
+        .password
+        .take()
+        .map(|password| hash_password(password).unwrap());
+
     let user = create_new_user(&new_user, &mut conn).expect("error saving user");
 
     debug!("created: {user:#?}");
 }
 
-fn prompt_and_hash_password(stdin: &mut StdinLock, stdout: &mut StdoutLock) -> Option<String> {
+fn prompt_password(stdin: &mut StdinLock, stdout: &mut StdoutLock) -> Option<String> {
     stdout.write_all(b"password: ").unwrap();
     stdout.flush().unwrap();
     let password = stdin.read_passwd(stdout).unwrap();
@@ -134,11 +216,10 @@ fn prompt_and_hash_password(stdin: &mut StdinLock, stdout: &mut StdoutLock) -> O
 
     match password {
         Some(password) => {
-            if password.len() == 0 {
+            if password.is_empty() {
                 return None;
             }
-            let hashed_password = hash_password(password).unwrap();
-            Some(hashed_password)
+            Some(password)
         }
         None => None,
     }
@@ -155,7 +236,7 @@ fn prompt_email(stdin: &mut StdinLock, stdout: &mut StdoutLock) -> Option<EmailA
     match raw_email {
         Some(raw_email) => {
             let email = raw_email.trim();
-            if email.len() == 0 {
+            if email.is_empty() {
                 return None;
             }
             Some(EmailAddress::new(email).unwrap())
@@ -172,19 +253,24 @@ fn edit_user(id: i32) {
     let stdin = stdin();
     let mut stdin = stdin.lock();
 
-    let hashed_password = prompt_and_hash_password(&mut stdin, &mut stdout);
+    let password = prompt_password(&mut stdin, &mut stdout);
     let email = prompt_email(&mut stdin, &mut stdout);
 
+    if let Some(Err(validation_error)) = password.as_deref().map(validate_password) {
+        panic!("Validation error: {:#?}", validation_error);
+    }
+    let hashed_password = password.map(|password| hash_password(password).unwrap());
+
     let mut user: User = users::table
         .find(id)
         .first(connection)
         .expect("No user with that id");
 
-    if let Some(_) = hashed_password {
+    if hashed_password.is_some() {
         user.hashed_password = hashed_password;
     }
 
-    if let Some(_) = email {
+    if email.is_some() {
         user.email = email;
     }
 
@@ -207,6 +293,32 @@ fn delete_user_by_id(id_to_delete: i32) {
     println!("deleted {num_deleted} users");
 }
 
+fn reset_user_2fa(id: i32) {
+    let connection = &mut establish_connection(None);
+
+    let user: User = users::table
+        .find(id)
+        .first(connection)
+        .expect("No user with that id");
+
+    disable_totp(&user, connection).expect("Error while resetting 2fa");
+
+    println!("reset 2fa for {}", user.username);
+}
+
+fn set_role(id: i32, role: Role) {
+    let connection = &mut establish_connection(None);
+
+    let user: User = users::table
+        .find(id)
+        .first(connection)
+        .expect("No user with that id");
+
+    set_user_role(&user, role, connection).expect("Error while setting role");
+
+    println!("{} is now {}", user.username, role);
+}
+
 fn create_goal_from_prompt() {
     let mut conn = establish_connection(None);
 
@@ -242,6 +354,7 @@ fn create_goal_from_prompt() {
         description,
         notes,
         user_id,
+        target_date: None,
     };
 
     let goal = create_new_goal(&new_goal, &mut conn).unwrap();
@@ -269,3 +382,87 @@ fn show_goals(user_id: Option<i32>) {
 
     println!("Goals: {:#?}", goals);
 }
+
+fn list_jobs(status: Option<JobStatus>, limit: i64) {
+    let connection = &mut establish_connection(None);
+
+    let jobs = get_jobs(status, limit, connection).expect("Error loading jobs");
+
+    println!("Displaying {} jobs", jobs.len());
+    for job in jobs {
+        println!(
+            "{:>6}  {:<30} {:<8} {}/{}  run at {}  {}",
+            job.id,
+            job.kind,
+            job.status,
+            job.attempts,
+            job.max_attempts,
+            job.run_at.format("%Y-%m-%d %H:%M:%S"),
+            job.last_error.unwrap_or_default()
+        );
+    }
+}
+
+fn show_job(id: i32) {
+    let connection = &mut establish_connection(None);
+
+    let job: Job = jobs::table
+        .find(id)
+        .first(connection)
+        .expect("No job with that id");
+
+    println!("{job:#?}");
+}
+
+fn retry_dead_job(id: i32) {
+    let connection = &mut establish_connection(None);
+
+    match retry_job(id, connection).expect("Error while retrying job") {
+        0 => println!("no dead job with id {id}"),
+        _ => println!("job {id} queued again"),
+    }
+}
+
+fn enqueue_job(kind: &str, payload: &str) {
+    let connection = &mut establish_connection(None);
+
+    let payload = serde_json::from_str(payload).expect("payload must be JSON");
+    let registry = registry();
+    let job = registry
+        .enqueue_json(kind, payload, connection)
+        .unwrap_or_else(|e| panic!("{e}, kinds are {:?}", registry.kinds()));
+
+    println!("queued job {}", job.id);
+}
+
+fn run_job_workers(workers: usize) {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+    let pool = get_connection_pool(&config.database);
+    let mailer = mailer_from_config(&config.mail).expect("mail config is validated");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let shutdown = CancellationToken::new();
+        shutdown::cancel_on_signal(shutdown.clone());
+
+        let mut tasks = Vec::new();
+        if config.features.reminders {
+            tasks.push(spawn_reminder_scheduler(pool.clone(), shutdown.clone()));
+        }
+        let context = JobContext { pool, mailer };
+        tasks.extend(spawn_job_workers(
+            Arc::new(registry()),
+            context,
+            workers,
+            shutdown.clone(),
+        ));
+
+        // running jobs get to finish
+        shutdown.cancelled().await;
+        let deadline = Instant::now() + Duration::from_secs(config.server.drain_timeout);
+        shutdown::join_tasks(tasks, deadline).await;
+    });
+}
@@ -1,6 +1,6 @@
 use axum_boilerplate::webapp;
 
 #[tokio::main]
-async fn main() {
-    webapp::run_server().await;
+async fn main() -> std::io::Result<()> {
+    webapp::run_server().await
 }
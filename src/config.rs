@@ -0,0 +1,788 @@
+use serde::Deserialize;
+use std::{env, fmt, fs, io, net::SocketAddr, path::PathBuf, str::FromStr};
+use thiserror::Error;
+use url::Url;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("could not read {path}: {source}")]
+    ReadError { path: PathBuf, source: io::Error },
+    #[error("{path} is not valid: {source}")]
+    ParseError {
+        path: PathBuf,
+        source: Box<toml::de::Error>,
+    },
+    #[error("{var}={value:?} is not valid: {message}")]
+    EnvError {
+        var: &'static str,
+        value: String,
+        message: String,
+    },
+    #[error("invalid configuration:\n  - {}", .0.join("\n  - "))]
+    Invalid(Vec<String>),
+}
+
+// everything the webapp and the workers are configured with. read from config.toml, or the
+// file in CONFIG_FILE, then environment variables override single settings
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub cookies: CookieConfig,
+    pub sso: SsoConfig,
+    pub features: FeatureConfig,
+    pub password: PasswordConfig,
+    pub mail: MailConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    #[default]
+    Development,
+    // refuses shortcuts that are only fine on a laptop, like a random cookie key
+    Production,
+}
+
+impl FromStr for Environment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "development" => Ok(Environment::Development),
+            "production" => Ok(Environment::Production),
+            _ => Err("expected development or production".to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub environment: Environment,
+    pub bind: SocketAddr,
+    pub templates: String,
+    // served under /static, including the vendored swagger ui
+    pub static_dir: String,
+    // reverse proxies in front that append to X-Forwarded-For, the client's address is taken
+    // from there instead of the connection when set
+    pub trusted_proxies: usize,
+    // seconds open requests and background jobs get to finish after SIGTERM
+    pub drain_timeout: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            environment: Environment::default(),
+            bind: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            templates: "src/webapp/templates/**/*.html".to_string(),
+            static_dir: "static".to_string(),
+            trusted_proxies: 0,
+            drain_timeout: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub pool_size: u32,
+    // seconds a request waits for a free connection before it fails with a 503
+    pub connection_timeout: u64,
+    // seconds before postgres cancels a query, 0 for no limit
+    pub statement_timeout: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            pool_size: 10,
+            connection_timeout: 30,
+            statement_timeout: 0,
+        }
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieConfig {
+    // signs and encrypts the private cookies, a random one logs everyone out on restart
+    pub secret: Option<String>,
+    // instead of secret, the first line is the secret and any further lines previous ones
+    pub secret_file: Option<PathBuf>,
+    // still decrypt cookies while a new secret rolls out, nothing is encrypted with them
+    pub previous_secrets: Vec<String>,
+    // only sent over https
+    pub secure: bool,
+}
+
+impl CookieConfig {
+    // moves the secrets from secret_file into secret and previous_secrets
+    pub fn read_secret_file(&mut self) -> Result<(), ConfigError> {
+        let Some(path) = &self.secret_file else {
+            return Ok(());
+        };
+        if self.secret.is_some() {
+            return Err(ConfigError::Invalid(vec![
+                "cookies.secret (SECRET) and cookies.secret_file (SECRET_FILE) are both set"
+                    .to_string(),
+            ]));
+        }
+
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::ReadError {
+            path: path.clone(),
+            source,
+        })?;
+        let mut secrets = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_string);
+        self.secret = secrets.next();
+        let mut previous_secrets: Vec<_> = secrets.collect();
+        previous_secrets.append(&mut self.previous_secrets);
+        self.previous_secrets = previous_secrets;
+
+        Ok(())
+    }
+}
+
+// keeps the secrets out of logs
+impl fmt::Debug for CookieConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CookieConfig")
+            .field("secret", &self.secret.as_ref().map(|_| "..."))
+            .field("secret_file", &self.secret_file)
+            .field("previous_secrets", &self.previous_secrets.len())
+            .field("secure", &self.secure)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SsoConfig {
+    // create users on first sso login from verified claims
+    pub jit_provisioning: bool,
+    pub google: Option<GoogleConfig>,
+    pub microsoft: Option<MicrosoftConfig>,
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GoogleConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_url: String,
+}
+
+impl fmt::Debug for GoogleConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GoogleConfig")
+            .field("client_id", &self.client_id)
+            .field("redirect_url", &self.redirect_url)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MicrosoftConfig {
+    pub client_id: String,
+    pub client_secret: String,
+    pub tenant_id: String,
+    pub redirect_url: String,
+}
+
+impl fmt::Debug for MicrosoftConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MicrosoftConfig")
+            .field("client_id", &self.client_id)
+            .field("tenant_id", &self.tenant_id)
+            .field("redirect_url", &self.redirect_url)
+            .finish_non_exhaustive()
+    }
+}
+
+// what this process runs besides serving requests
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    // 0 leaves the queue to `db job work` in another process
+    pub job_workers: usize,
+    pub webhook_delivery: bool,
+    // lets webhooks reach loopback and private addresses, only for local receivers in development
+    pub webhook_private_addresses: bool,
+    pub reminders: bool,
+    // the openapi spec and swagger ui page under /api
+    pub api_docs: bool,
+    // prometheus scrapes /metrics, unauthenticated on the same listener, so it is off unless
+    // the deployment keeps that path off the public internet
+    pub metrics: bool,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            job_workers: 2,
+            webhook_delivery: true,
+            webhook_private_addresses: false,
+            reminders: true,
+            api_docs: true,
+            metrics: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordConfig {
+    pub min_length: usize,
+    pub max_length: usize,
+    // one password per line
+    pub breached_passwords_file: Option<PathBuf>,
+}
+
+impl Default for PasswordConfig {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            breached_passwords_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    // one object per line for log collectors, with the request span's fields
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected text or json".to_string()),
+        }
+    }
+}
+
+// levels still come from RUST_LOG
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub format: LogFormat,
+    // the collector's base url, e.g. http://localhost:4318. spans are only exported by a build
+    // with the otel feature
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    // without one, mail is only logged
+    pub smtp_url: Option<String>,
+    pub from: String,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            smtp_url: None,
+            from: "axum-boilerplate <noreply@localhost>".to_string(),
+        }
+    }
+}
+
+// the smtp url can carry a password
+impl fmt::Debug for MailConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MailConfig")
+            .field("smtp_url", &self.smtp_url.as_ref().map(|_| "..."))
+            .field("from", &self.from)
+            .finish()
+    }
+}
+
+impl Config {
+    // .env, then the config file, then env overrides, then validate
+    pub fn load() -> Result<Self, ConfigError> {
+        dotenvy::dotenv().ok();
+
+        let (path, required) = match env::var("CONFIG_FILE") {
+            Ok(path) if !path.is_empty() => (PathBuf::from(path), true),
+            _ => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+        let mut config = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                Self::from_toml(&contents).map_err(|source| ConfigError::ParseError {
+                    path,
+                    source: Box::new(source),
+                })?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => Self::default(),
+            Err(source) => return Err(ConfigError::ReadError { path, source }),
+        };
+
+        config.apply_env(|var| env::var(var).ok())?;
+        config.cookies.read_secret_file()?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    // the env var names predate the config file, so they are kept as they were. an empty value
+    // counts as unset, like the blank lines in env_example
+    pub fn apply_env(
+        &mut self,
+        lookup: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), ConfigError> {
+        let var = |name: &str| lookup(name).filter(|value| !value.is_empty());
+
+        if let Some(environment) = parse_var(&var, "APP_ENV")? {
+            self.server.environment = environment;
+        }
+        if let Some(bind) = parse_var(&var, "BIND_ADDRESS")? {
+            self.server.bind = bind;
+        }
+        if let Some(templates) = var("TEMPLATE_GLOB") {
+            self.server.templates = templates;
+        }
+        if let Some(static_dir) = var("STATIC_DIR") {
+            self.server.static_dir = static_dir;
+        }
+        if let Some(trusted_proxies) = parse_var(&var, "TRUSTED_PROXIES")? {
+            self.server.trusted_proxies = trusted_proxies;
+        }
+        if let Some(drain_timeout) = parse_var(&var, "DRAIN_TIMEOUT")? {
+            self.server.drain_timeout = drain_timeout;
+        }
+
+        if let Some(url) = var("DATABASE_URL") {
+            self.database.url = url;
+        }
+        if let Some(pool_size) = parse_var(&var, "DATABASE_POOL_SIZE")? {
+            self.database.pool_size = pool_size;
+        }
+        if let Some(timeout) = parse_var(&var, "DATABASE_CONNECTION_TIMEOUT")? {
+            self.database.connection_timeout = timeout;
+        }
+        if let Some(timeout) = parse_var(&var, "DATABASE_STATEMENT_TIMEOUT")? {
+            self.database.statement_timeout = timeout;
+        }
+
+        if let Some(secret) = var("SECRET") {
+            self.cookies.secret = Some(secret);
+        }
+        if let Some(path) = var("SECRET_FILE") {
+            self.cookies.secret_file = Some(path.into());
+        }
+        // comma separated, the generated secrets are alphanumeric
+        if let Some(secrets) = var("PREVIOUS_SECRETS") {
+            self.cookies.previous_secrets = secrets
+                .split(',')
+                .map(str::trim)
+                .filter(|secret| !secret.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(secure) = parse_var(&var, "COOKIE_SECURE")? {
+            self.cookies.secure = secure;
+        }
+
+        if let Some(jit_provisioning) = parse_var(&var, "SSO_JIT_PROVISIONING")? {
+            self.sso.jit_provisioning = jit_provisioning;
+        }
+        // a client id turns a provider on, the redirect urls in env_example alone don't
+        let google = [
+            "GOOGLE_CLIENT_ID",
+            "GOOGLE_CLIENT_SECRET",
+            "GOOGLE_REDIRECT_URL",
+        ]
+        .map(&var);
+        if google[0].is_some() || self.sso.google.is_some() {
+            let config = self.sso.google.get_or_insert_default();
+            let [client_id, client_secret, redirect_url] = google;
+            override_string(&mut config.client_id, client_id);
+            override_string(&mut config.client_secret, client_secret);
+            override_string(&mut config.redirect_url, redirect_url);
+        }
+        let microsoft = [
+            "MICROSOFT_CLIENT_ID",
+            "MICROSOFT_CLIENT_SECRET",
+            "MICROSOFT_TENANT_ID",
+            "MICROSOFT_REDIRECT_URL",
+        ]
+        .map(&var);
+        if microsoft[0].is_some() || self.sso.microsoft.is_some() {
+            let config = self.sso.microsoft.get_or_insert_default();
+            let [client_id, client_secret, tenant_id, redirect_url] = microsoft;
+            override_string(&mut config.client_id, client_id);
+            override_string(&mut config.client_secret, client_secret);
+            override_string(&mut config.tenant_id, tenant_id);
+            override_string(&mut config.redirect_url, redirect_url);
+        }
+
+        if let Some(job_workers) = parse_var(&var, "JOB_WORKERS")? {
+            self.features.job_workers = job_workers;
+        }
+        if let Some(webhook_delivery) = parse_var(&var, "FEATURE_WEBHOOK_DELIVERY")? {
+            self.features.webhook_delivery = webhook_delivery;
+        }
+        if let Some(private_addresses) = parse_var(&var, "FEATURE_WEBHOOK_PRIVATE_ADDRESSES")? {
+            self.features.webhook_private_addresses = private_addresses;
+        }
+        if let Some(reminders) = parse_var(&var, "FEATURE_REMINDERS")? {
+            self.features.reminders = reminders;
+        }
+        if let Some(api_docs) = parse_var(&var, "FEATURE_API_DOCS")? {
+            self.features.api_docs = api_docs;
+        }
+        if let Some(metrics) = parse_var(&var, "FEATURE_METRICS")? {
+            self.features.metrics = metrics;
+        }
+
+        if let Some(min_length) = parse_var(&var, "PASSWORD_MIN_LENGTH")? {
+            self.password.min_length = min_length;
+        }
+        if let Some(max_length) = parse_var(&var, "PASSWORD_MAX_LENGTH")? {
+            self.password.max_length = max_length;
+        }
+        if let Some(path) = var("BREACHED_PASSWORDS_FILE") {
+            self.password.breached_passwords_file = Some(path.into());
+        }
+
+        if let Some(format) = parse_var(&var, "LOG_FORMAT")? {
+            self.logging.format = format;
+        }
+        if let Some(endpoint) = var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            self.logging.otlp_endpoint = Some(endpoint);
+        }
+
+        if let Some(smtp_url) = var("SMTP_URL") {
+            self.mail.smtp_url = Some(smtp_url);
+        }
+        if let Some(from) = var("MAIL_FROM") {
+            self.mail.from = from;
+        }
+
+        Ok(())
+    }
+
+    // every problem at once, named by its config file key
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        if self.database.url.is_empty() {
+            errors.push("database.url (DATABASE_URL) is required".to_string());
+        }
+        // the live update listener keeps one for itself
+        if self.database.pool_size < 2 {
+            errors.push("database.pool_size must be at least 2".to_string());
+        }
+        if self.database.connection_timeout == 0 {
+            errors.push("database.connection_timeout must be at least 1 second".to_string());
+        }
+
+        // cookie::Key needs 64 bytes
+        if let Some(secret) = &self.cookies.secret
+            && secret.len() < 64
+        {
+            errors.push(format!(
+                "cookies.secret (SECRET) must be at least 64 bytes, it is {}",
+                secret.len()
+            ));
+        }
+        for (i, secret) in self.cookies.previous_secrets.iter().enumerate() {
+            if secret.len() < 64 {
+                errors.push(format!(
+                    "cookies.previous_secrets[{i}] must be at least 64 bytes, it is {}",
+                    secret.len()
+                ));
+            }
+        }
+        // every instance needs the same key, and a restart mustn't log everyone out
+        if self.server.environment == Environment::Production && self.cookies.secret.is_none() {
+            errors.push(
+                "cookies.secret (SECRET) or cookies.secret_file (SECRET_FILE) is required in \
+                 production"
+                    .to_string(),
+            );
+        }
+
+        if let Some(google) = &self.sso.google {
+            check_provider(
+                "google",
+                &[
+                    ("client_id", &google.client_id),
+                    ("client_secret", &google.client_secret),
+                    ("redirect_url", &google.redirect_url),
+                ],
+                &mut errors,
+            );
+        }
+        if let Some(microsoft) = &self.sso.microsoft {
+            check_provider(
+                "microsoft",
+                &[
+                    ("client_id", &microsoft.client_id),
+                    ("client_secret", &microsoft.client_secret),
+                    ("tenant_id", &microsoft.tenant_id),
+                    ("redirect_url", &microsoft.redirect_url),
+                ],
+                &mut errors,
+            );
+        }
+
+        if self.password.min_length == 0 || self.password.min_length > self.password.max_length {
+            errors.push(format!(
+                "password.min_length must be between 1 and password.max_length ({}), it is {}",
+                self.password.max_length, self.password.min_length
+            ));
+        }
+        if let Some(path) = &self.password.breached_passwords_file
+            && !path.is_file()
+        {
+            errors.push(format!(
+                "password.breached_passwords_file {} does not exist",
+                path.display()
+            ));
+        }
+
+        if self.mail.from.parse::<lettre::message::Mailbox>().is_err() {
+            errors.push(format!(
+                "mail.from {:?} is not an email address",
+                self.mail.from
+            ));
+        }
+        if let Some(smtp_url) = &self.mail.smtp_url
+            && lettre::SmtpTransport::from_url(smtp_url).is_err()
+        {
+            errors.push("mail.smtp_url is not a valid smtp:// or smtps:// url".to_string());
+        }
+
+        if let Some(endpoint) = &self.logging.otlp_endpoint
+            && !Url::parse(endpoint).is_ok_and(|url| matches!(url.scheme(), "http" | "https"))
+        {
+            errors.push(format!(
+                "logging.otlp_endpoint {endpoint:?} is not an http:// or https:// url"
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+}
+
+fn parse_var<T>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &'static str,
+) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    var(name)
+        .map(|value| {
+            value.parse().map_err(|e: T::Err| ConfigError::EnvError {
+                var: name,
+                message: e.to_string(),
+                value,
+            })
+        })
+        .transpose()
+}
+
+fn override_string(field: &mut String, value: Option<String>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+// a provider is either fully configured or not at all
+fn check_provider(name: &str, fields: &[(&str, &String)], errors: &mut Vec<String>) {
+    for (field, value) in fields {
+        if value.is_empty() {
+            errors.push(format!("sso.{name}.{field} is required"));
+        }
+    }
+    if let Some((_, redirect_url)) = fields.iter().find(|(field, _)| *field == "redirect_url")
+        && !redirect_url.is_empty()
+        && Url::parse(redirect_url).is_err()
+    {
+        errors.push(format!("sso.{name}.redirect_url is not a url"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn valid() -> Config {
+        let mut config = Config::default();
+        config.database.url = "postgres://localhost/test".to_string();
+        config
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let config = Config::from_toml(
+            r#"
+            [server]
+            bind = "0.0.0.0:8080"
+
+            [database]
+            url = "postgres://localhost/app"
+
+            [sso.google]
+            client_id = "id"
+            client_secret = "secret"
+            redirect_url = "https://example.com/google/callback"
+
+            [features]
+            job_workers = 0
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.server.bind, "0.0.0.0:8080".parse().unwrap());
+        assert_eq!(config.server.templates, ServerConfig::default().templates);
+        assert_eq!(config.database.pool_size, 10);
+        assert_eq!(config.sso.google.as_ref().unwrap().client_id, "id");
+        assert_eq!(config.sso.microsoft, None);
+        assert_eq!(config.features.job_workers, 0);
+        assert!(config.features.webhook_delivery);
+        // /metrics has no auth, so it has to be asked for, same for private webhook addresses
+        assert!(!config.features.metrics);
+        assert!(!config.features.webhook_private_addresses);
+        assert!(config.validate().is_ok());
+
+        let typo = Config::from_toml("[server]\nbnd = \"0.0.0.0:8080\"").unwrap_err();
+        assert!(typo.to_string().contains("unknown field `bnd`"));
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let vars = HashMap::from([
+            ("DATABASE_URL", "postgres://localhost/env"),
+            ("DATABASE_STATEMENT_TIMEOUT", "5"),
+            ("JOB_WORKERS", "4"),
+            ("FEATURE_METRICS", "true"),
+            ("FEATURE_WEBHOOK_PRIVATE_ADDRESSES", "true"),
+            ("COOKIE_SECURE", "true"),
+            ("LOG_FORMAT", "json"),
+            ("MICROSOFT_CLIENT_ID", "id"),
+            (
+                "GOOGLE_REDIRECT_URL",
+                "http://localhost:3000/google/callback",
+            ),
+            // blank lines in .env are unset
+            ("SMTP_URL", ""),
+        ]);
+        let mut config = valid();
+        config
+            .apply_env(|name| vars.get(name).map(|value| value.to_string()))
+            .unwrap();
+
+        assert_eq!(config.database.url, "postgres://localhost/env");
+        assert_eq!(config.database.statement_timeout, 5);
+        assert_eq!(config.database.connection_timeout, 30);
+        assert_eq!(config.features.job_workers, 4);
+        assert!(config.features.metrics);
+        assert!(config.features.webhook_private_addresses);
+        assert!(config.cookies.secure);
+        assert_eq!(config.logging.format, LogFormat::Json);
+        assert_eq!(config.mail.smtp_url, None);
+        assert_eq!(config.sso.google, None);
+        // the rest of the provider is missing
+        let ConfigError::Invalid(errors) = config.validate().unwrap_err() else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(
+            errors,
+            [
+                "sso.microsoft.client_secret is required",
+                "sso.microsoft.tenant_id is required",
+                "sso.microsoft.redirect_url is required",
+            ]
+        );
+
+        let error = valid()
+            .apply_env(|name| (name == "JOB_WORKERS").then(|| "lots".to_string()))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "JOB_WORKERS=\"lots\" is not valid: invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = Config::default();
+        config.database.connection_timeout = 0;
+        config.cookies.secret = Some("short".to_string());
+        config.password.min_length = 200;
+        config.mail.from = "nobody".to_string();
+        config.logging.otlp_endpoint = Some("localhost:4318".to_string());
+
+        let ConfigError::Invalid(errors) = config.validate().unwrap_err() else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(errors.len(), 6);
+        assert!(errors[0].starts_with("database.url"));
+        assert!(errors[1].starts_with("database.connection_timeout"));
+        assert!(errors[2].starts_with("cookies.secret"));
+        assert!(errors[3].starts_with("password.min_length"));
+        assert!(errors[4].starts_with("mail.from"));
+        assert!(errors[5].starts_with("logging.otlp_endpoint"));
+    }
+
+    #[test]
+    fn test_cookie_secrets() {
+        let mut config = valid();
+        config.server.environment = Environment::Production;
+        let ConfigError::Invalid(errors) = config.validate().unwrap_err() else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].contains("required in production"));
+
+        let secret = "a".repeat(64);
+        let previous = "b".repeat(64);
+        let path = env::temp_dir().join(format!("cookie-secrets-{}", std::process::id()));
+        fs::write(&path, format!("{secret}\n\n{previous}\n")).unwrap();
+        config.cookies.secret_file = Some(path.clone());
+        config.cookies.previous_secrets = vec!["c".repeat(64)];
+        config.cookies.read_secret_file().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.cookies.secret, Some(secret));
+        assert_eq!(config.cookies.previous_secrets, [previous, "c".repeat(64)]);
+        assert!(config.validate().is_ok());
+
+        // one or the other
+        assert!(config.cookies.read_secret_file().is_err());
+    }
+}
@@ -1,11 +1,17 @@
+use crate::{config::DatabaseConfig, metrics::PoolMetrics};
 use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection, Pool, PoolError};
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness, embed_migrations};
 use dotenvy::dotenv;
-use std::env;
+use std::{env, time::Duration};
+use tokio::task::JoinError;
+use tracing::Span;
 
 pub mod models;
 pub mod schema;
 
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
 pub fn establish_connection(db_url: Option<String>) -> PgConnection {
     dotenv().ok();
 
@@ -16,13 +22,57 @@ pub fn establish_connection(db_url: Option<String>) -> PgConnection {
         .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
 }
 
-pub fn get_connection_pool() -> Pool<ConnectionManager<PgConnection>> {
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL");
-    let manager = ConnectionManager::<PgConnection>::new(database_url);
-    let pool = Pool::builder()
+pub fn get_connection_pool(config: &DatabaseConfig) -> Pool<ConnectionManager<PgConnection>> {
+    let manager = ConnectionManager::<PgConnection>::new(&config.url);
+    let builder = Pool::builder()
+        .max_size(config.pool_size)
+        .connection_timeout(Duration::from_secs(config.connection_timeout))
         .test_on_check_out(true)
+        .event_handler(Box::new(PoolMetrics));
+    let builder = match config.statement_timeout {
+        0 => builder,
+        seconds => builder.connection_customizer(Box::new(StatementTimeout(seconds))),
+    };
+    builder
         .build(manager)
-        .expect("Could not build connection pool!");
+        .expect("Could not build connection pool!")
+}
+
+#[derive(Debug)]
+struct StatementTimeout(u64);
+
+impl CustomizeConnection<PgConnection, r2d2::Error> for StatementTimeout {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        diesel::sql_query(format!("SET statement_timeout = '{}s'", self.0))
+            .execute(conn)
+            .map(|_| ())
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
+// diesel blocks, so async code runs its queries here, on tokio's blocking threads, rather than
+// holding up a runtime worker for as long as the query takes. the connection is only checked
+// out once there is a thread for it
+pub async fn interact<T, E, F>(pool: &Pool<ConnectionManager<PgConnection>>, f: F) -> Result<T, E>
+where
+    F: FnOnce(&mut PgConnection) -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: From<PoolError> + From<JoinError> + Send + 'static,
+{
+    let pool = pool.clone();
+    // so the queries are logged and traced as part of the request or job
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let mut conn = pool.get()?;
+        f(&mut conn)
+    })
+    .await?
+}
 
-    pool
+// migrations in this build that the database hasn't run yet
+pub fn has_pending_migrations(
+    conn: &mut PgConnection,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    conn.has_pending_migration(MIGRATIONS)
 }
@@ -0,0 +1,212 @@
+use crate::db::{
+    models::user::User,
+    schema::{api_tokens, users},
+};
+use chrono::{DateTime, Duration, Utc};
+use diesel::{
+    deserialize::{FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    prelude::*,
+    serialize::{IsNull, ToSql},
+};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, io::Write, str::FromStr};
+use thiserror::Error;
+
+// makes leaked tokens easy to spot in logs and secret scanners
+const TOKEN_PREFIX: &str = "abp_";
+
+// same as sessions, don't write last_used_at on every request
+const TOUCH_INTERVAL_SECONDS: i64 = 60;
+
+// write includes read
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+)]
+#[diesel(sql_type = diesel::sql_types::Text)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    Read,
+    Write,
+}
+
+impl TokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Write => "write",
+        }
+    }
+}
+
+impl fmt::Display for TokenScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{0} is not a valid token scope")]
+pub struct TokenScopeError(String);
+
+impl FromStr for TokenScope {
+    type Err = TokenScopeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(TokenScope::Read),
+            "write" => Ok(TokenScope::Write),
+            _ => Err(TokenScopeError(s.to_string())),
+        }
+    }
+}
+
+impl FromSql<diesel::sql_types::Text, Pg> for TokenScope {
+    fn from_sql(bytes: PgValue) -> diesel::deserialize::Result<Self> {
+        let string = String::from_utf8(bytes.as_bytes().to_vec())?;
+        Ok(string.parse()?)
+    }
+}
+
+impl ToSql<diesel::sql_types::Text, Pg> for TokenScope {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+// a personal access token for scripts, only the hash is stored
+#[derive(Debug, Clone, PartialEq, Serialize, Queryable, Identifiable, Associations, Selectable)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = crate::db::schema::api_tokens)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub scope: TokenScope,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ApiToken {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::api_tokens)]
+pub struct NewApiToken {
+    pub user_id: i32,
+    pub name: String,
+    pub token_hash: String,
+    pub scope: TokenScope,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// returns the token and its plaintext, which is only shown this once
+pub fn create_api_token(
+    user: &User,
+    name: &str,
+    scope: TokenScope,
+    expires_at: Option<DateTime<Utc>>,
+    conn: &mut PgConnection,
+) -> Result<(ApiToken, String), diesel::result::Error> {
+    let token = format!(
+        "{TOKEN_PREFIX}{}",
+        Alphanumeric.sample_string(&mut rand::rng(), 40)
+    );
+    let new_token = NewApiToken {
+        user_id: user.id,
+        name: name.to_string(),
+        token_hash: hash_api_token(&token),
+        scope,
+        expires_at,
+    };
+
+    let api_token = diesel::insert_into(api_tokens::table)
+        .values(&new_token)
+        .returning(ApiToken::as_returning())
+        .get_result(conn)?;
+
+    Ok((api_token, token))
+}
+
+// expired tokens are treated as missing
+pub fn get_user_by_api_token(
+    token: &str,
+    conn: &mut PgConnection,
+) -> Result<Option<(ApiToken, User)>, diesel::result::Error> {
+    let found = api_tokens::table
+        .inner_join(users::table)
+        .filter(api_tokens::token_hash.eq(hash_api_token(token)))
+        .select((ApiToken::as_select(), User::as_select()))
+        .first::<(ApiToken, User)>(conn)
+        .optional()?;
+
+    Ok(found.filter(|(api_token, _)| !api_token.is_expired()))
+}
+
+pub fn touch_api_token(
+    api_token: &ApiToken,
+    conn: &mut PgConnection,
+) -> Result<(), diesel::result::Error> {
+    let now = Utc::now();
+    if api_token
+        .last_used_at
+        .is_some_and(|last_used_at| now - last_used_at < Duration::seconds(TOUCH_INTERVAL_SECONDS))
+    {
+        return Ok(());
+    }
+
+    diesel::update(api_tokens::table.find(api_token.id))
+        .set(api_tokens::last_used_at.eq(now))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn get_api_tokens_for_user(
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<Vec<ApiToken>, diesel::result::Error> {
+    ApiToken::belonging_to(user)
+        .order(api_tokens::created_at.desc())
+        .load(conn)
+}
+
+// scoped to the user so one user can't revoke another's token
+pub fn delete_api_token(
+    id: i32,
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(
+        api_tokens::table.filter(api_tokens::id.eq(id).and(api_tokens::user_id.eq(user.id))),
+    )
+    .execute(conn)
+}
+
+fn hash_api_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
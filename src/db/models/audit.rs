@@ -0,0 +1,78 @@
+use crate::db::{models::user::User, schema::audit_log};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    ImpersonationStart,
+    ImpersonationStop,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::ImpersonationStart => "impersonation_start",
+            AuditAction::ImpersonationStop => "impersonation_stop",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Queryable, Identifiable, Selectable)]
+#[diesel(table_name = crate::db::schema::audit_log)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct AuditEntry {
+    pub id: i32,
+    pub actor_id: Option<i32>,
+    pub actor_username: String,
+    pub target_id: Option<i32>,
+    pub target_username: Option<String>,
+    pub action: String,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::audit_log)]
+pub struct NewAuditEntry<'a> {
+    pub actor_id: i32,
+    pub actor_username: &'a str,
+    pub target_id: Option<i32>,
+    pub target_username: Option<&'a str>,
+    pub action: &'a str,
+    pub ip: Option<&'a str>,
+}
+
+pub fn record_audit(
+    actor: &User,
+    action: AuditAction,
+    target: Option<&User>,
+    ip: Option<&str>,
+    conn: &mut PgConnection,
+) -> Result<AuditEntry, diesel::result::Error> {
+    let new_entry = NewAuditEntry {
+        actor_id: actor.id,
+        actor_username: &actor.username,
+        target_id: target.map(|target| target.id),
+        target_username: target.map(|target| target.username.as_str()),
+        action: action.as_str(),
+        ip,
+    };
+
+    diesel::insert_into(audit_log::table)
+        .values(&new_entry)
+        .returning(AuditEntry::as_returning())
+        .get_result(conn)
+}
+
+// newest first
+pub fn get_audit_log(
+    limit: i64,
+    conn: &mut PgConnection,
+) -> Result<Vec<AuditEntry>, diesel::result::Error> {
+    audit_log::table
+        .order(audit_log::created_at.desc())
+        .then_order_by(audit_log::id.desc())
+        .limit(limit)
+        .load(conn)
+}
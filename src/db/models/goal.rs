@@ -1,13 +1,16 @@
 use std::borrow::Cow;
 
 use crate::db::{models::user::User, schema::goals};
+use chrono::{DateTime, NaiveDate, Utc};
 use diesel::PgConnection;
 use diesel::prelude::*;
-use serde::{Deserialize, Serialize};
-use validator::{Validate, ValidateArgs, ValidationError};
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::ToSchema;
+use validator::{Validate, ValidationError};
 
 #[derive(
     Debug,
+    Clone,
     PartialEq,
     Serialize,
     Deserialize,
@@ -16,6 +19,7 @@ use validator::{Validate, ValidateArgs, ValidationError};
     Associations,
     Selectable,
     AsChangeset,
+    ToSchema,
 )]
 #[diesel(belongs_to(User))]
 #[diesel(table_name = crate::db::schema::goals)]
@@ -27,6 +31,8 @@ pub struct Goal {
     pub description: String,
     pub notes: Option<String>,
     pub user_id: i32,
+    pub target_date: Option<NaiveDate>,
+    pub completed_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Insertable)]
@@ -36,16 +42,33 @@ pub struct NewGoal {
     pub description: String,
     pub notes: Option<String>,
     pub user_id: i32,
+    pub target_date: Option<NaiveDate>,
 }
 
-#[derive(Debug, Deserialize, Validate, AsChangeset)]
+#[derive(Debug, Deserialize, Validate, AsChangeset, ToSchema)]
 #[diesel(table_name = crate::db::schema::goals)]
+#[diesel(treat_none_as_null = true)]
 #[validate(context = "GoalContext<'v_a>", mutable)]
 pub struct GoalForm {
     #[validate(custom(function = "validate_goal_title", use_context))]
     pub title: String,
     pub description: String,
     pub notes: Option<String>,
+    #[serde(default, deserialize_with = "empty_as_none")]
+    pub target_date: Option<NaiveDate>,
+}
+
+// an empty html date input is sent as ""
+fn empty_as_none<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<NaiveDate>, D::Error> {
+    match Option::<String>::deserialize(deserializer)?
+        .as_deref()
+        .map(str::trim)
+    {
+        None | Some("") => Ok(None),
+        Some(date) => date.parse().map(Some).map_err(serde::de::Error::custom),
+    }
 }
 
 pub struct GoalContext<'a> {
@@ -70,11 +93,9 @@ fn validate_goal_title(title: &str, context: &mut GoalContext) -> Result<(), Val
             return Err(ValidationError::new("duplicate_title")
                 .with_message(Cow::from("A goal with this title already exists.")));
         }
-        return Ok(());
+        Ok(())
     } else {
-        return Err(
-            ValidationError::new("db_error").with_message(Cow::from("An error has occurred"))
-        );
+        Err(ValidationError::new("db_error").with_message(Cow::from("An error has occurred")))
     }
 }
 
@@ -87,3 +108,21 @@ pub fn create_new_goal(
         .returning(Goal::as_returning())
         .get_result(conn)
 }
+
+// only changes a goal that isn't already in that state, so `None` means there was nothing to do
+// and the goal.completed webhook goes out once per completion
+pub fn set_goal_completed(
+    goal: &Goal,
+    completed: bool,
+    conn: &mut PgConnection,
+) -> Result<Option<Goal>, diesel::result::Error> {
+    diesel::update(
+        goals::table
+            .find(goal.id)
+            .filter(goals::completed_at.is_null().eq(completed)),
+    )
+    .set(goals::completed_at.eq(completed.then(Utc::now)))
+    .returning(Goal::as_returning())
+    .get_result(conn)
+    .optional()
+}
@@ -0,0 +1,84 @@
+use crate::db::{
+    models::user::User,
+    schema::{user_identities, users},
+};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+
+// an external (sso) identity linked to a user, keyed by provider and the id token's sub claim
+#[derive(
+    Debug, PartialEq, Serialize, Queryable, Identifiable, Associations, Selectable, AsChangeset,
+)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = crate::db::schema::user_identities)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct UserIdentity {
+    pub id: i32,
+    pub user_id: i32,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::user_identities)]
+pub struct NewUserIdentity {
+    pub user_id: i32,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+}
+
+pub fn create_new_identity(
+    new_identity: &NewUserIdentity,
+    conn: &mut PgConnection,
+) -> Result<UserIdentity, diesel::result::Error> {
+    diesel::insert_into(user_identities::table)
+        .values(new_identity)
+        .returning(UserIdentity::as_returning())
+        .get_result(conn)
+}
+
+pub fn get_user_by_identity(
+    provider: &str,
+    subject: &str,
+    conn: &mut PgConnection,
+) -> Result<Option<User>, diesel::result::Error> {
+    user_identities::table
+        .inner_join(users::table)
+        .filter(
+            user_identities::provider
+                .eq(provider)
+                .and(user_identities::subject.eq(subject)),
+        )
+        .select(User::as_select())
+        .first(conn)
+        .optional()
+}
+
+pub fn get_identities_for_user(
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<Vec<UserIdentity>, diesel::result::Error> {
+    UserIdentity::belonging_to(user)
+        .order(user_identities::provider)
+        .load(conn)
+}
+
+// scoped to the user so one user can't unlink another's identity
+pub fn delete_identity(
+    id: i32,
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(
+        user_identities::table.filter(
+            user_identities::id
+                .eq(id)
+                .and(user_identities::user_id.eq(user.id)),
+        ),
+    )
+    .execute(conn)
+}
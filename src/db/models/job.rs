@@ -0,0 +1,237 @@
+use crate::db::schema::jobs;
+use chrono::{DateTime, Duration, Utc};
+use diesel::{
+    deserialize::{FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    prelude::*,
+    serialize::{IsNull, ToSql},
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, io::Write, str::FromStr};
+use thiserror::Error;
+
+// retries back off from 10 seconds up to an hour
+const BASE_RETRY_SECONDS: i64 = 10;
+const MAX_RETRY_SECONDS: i64 = 60 * 60;
+
+// dead jobs used up their attempts and wait for `db job retry`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = diesel::sql_types::Text)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Dead,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Pending => "pending",
+            JobStatus::Running => "running",
+            JobStatus::Done => "done",
+            JobStatus::Dead => "dead",
+        }
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{0} is not a valid job status")]
+pub struct JobStatusError(String);
+
+impl FromStr for JobStatus {
+    type Err = JobStatusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(JobStatus::Pending),
+            "running" => Ok(JobStatus::Running),
+            "done" => Ok(JobStatus::Done),
+            "dead" => Ok(JobStatus::Dead),
+            _ => Err(JobStatusError(s.to_string())),
+        }
+    }
+}
+
+impl FromSql<diesel::sql_types::Text, Pg> for JobStatus {
+    fn from_sql(bytes: PgValue) -> diesel::deserialize::Result<Self> {
+        let string = String::from_utf8(bytes.as_bytes().to_vec())?;
+        Ok(string.parse()?)
+    }
+}
+
+impl ToSql<diesel::sql_types::Text, Pg> for JobStatus {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+// `kind` picks the handler, `payload` is that job type serialized
+#[derive(Debug, Clone, PartialEq, Serialize, Queryable, Identifiable, Selectable)]
+#[diesel(table_name = crate::db::schema::jobs)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Job {
+    pub id: i32,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub status: JobStatus,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+    pub locked_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::jobs)]
+pub struct NewJob<'a> {
+    pub kind: &'a str,
+    pub payload: serde_json::Value,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+}
+
+pub fn insert_job(new_job: &NewJob, conn: &mut PgConnection) -> Result<Job, diesel::result::Error> {
+    diesel::insert_into(jobs::table)
+        .values(new_job)
+        .returning(Job::as_returning())
+        .get_result(conn)
+}
+
+// takes the oldest due job, SKIP LOCKED lets any number of workers poll at once. a running job
+// whose lease ran out belonged to a worker that died, so it is up for grabs again
+pub fn claim_next_job(
+    lease: Duration,
+    conn: &mut PgConnection,
+) -> Result<Option<Job>, diesel::result::Error> {
+    conn.transaction(|conn| {
+        let now = Utc::now();
+        let id = jobs::table
+            .filter(
+                jobs::status
+                    .eq(JobStatus::Pending)
+                    .and(jobs::run_at.le(now))
+                    .or(jobs::status
+                        .eq(JobStatus::Running)
+                        .and(jobs::locked_at.lt(now - lease))),
+            )
+            .order(jobs::run_at)
+            .select(jobs::id)
+            .for_update()
+            .skip_locked()
+            .first::<i32>(conn)
+            .optional()?;
+        let Some(id) = id else {
+            return Ok(None);
+        };
+
+        diesel::update(jobs::table.find(id))
+            .set((
+                jobs::status.eq(JobStatus::Running),
+                jobs::attempts.eq(jobs::attempts + 1),
+                jobs::locked_at.eq(Some(now)),
+            ))
+            .returning(Job::as_returning())
+            .get_result(conn)
+            .map(Some)
+    })
+}
+
+pub fn complete_job(job: &Job, conn: &mut PgConnection) -> Result<Job, diesel::result::Error> {
+    diesel::update(job)
+        .set((
+            jobs::status.eq(JobStatus::Done),
+            jobs::locked_at.eq(None::<DateTime<Utc>>),
+            jobs::last_error.eq(None::<String>),
+            jobs::finished_at.eq(Some(Utc::now())),
+        ))
+        .returning(Job::as_returning())
+        .get_result(conn)
+}
+
+// schedules a retry, or marks the job dead once it is out of attempts
+pub fn fail_job(
+    job: &Job,
+    error: &str,
+    conn: &mut PgConnection,
+) -> Result<Job, diesel::result::Error> {
+    let now = Utc::now();
+    let (status, finished_at) = if job.attempts >= job.max_attempts {
+        (JobStatus::Dead, Some(now))
+    } else {
+        (JobStatus::Pending, None)
+    };
+
+    diesel::update(job)
+        .set((
+            jobs::status.eq(status),
+            jobs::run_at.eq(now + job_retry_delay(job.attempts)),
+            jobs::locked_at.eq(None::<DateTime<Utc>>),
+            jobs::last_error.eq(Some(error)),
+            jobs::finished_at.eq(finished_at),
+        ))
+        .returning(Job::as_returning())
+        .get_result(conn)
+}
+
+// puts a dead job back in the queue with a fresh set of attempts
+pub fn retry_job(id: i32, conn: &mut PgConnection) -> Result<usize, diesel::result::Error> {
+    diesel::update(
+        jobs::table
+            .find(id)
+            .filter(jobs::status.eq(JobStatus::Dead)),
+    )
+    .set((
+        jobs::status.eq(JobStatus::Pending),
+        jobs::attempts.eq(0),
+        jobs::run_at.eq(Utc::now()),
+        jobs::finished_at.eq(None::<DateTime<Utc>>),
+    ))
+    .execute(conn)
+}
+
+pub fn get_jobs(
+    status: Option<JobStatus>,
+    limit: i64,
+    conn: &mut PgConnection,
+) -> Result<Vec<Job>, diesel::result::Error> {
+    let mut query = jobs::table.into_boxed();
+    if let Some(status) = status {
+        query = query.filter(jobs::status.eq(status));
+    }
+
+    query.order(jobs::id.desc()).limit(limit).load(conn)
+}
+
+// doubles after every failed attempt
+pub fn job_retry_delay(attempts: i32) -> Duration {
+    let exponent = (attempts - 1).clamp(0, 16) as u32;
+    Duration::seconds((BASE_RETRY_SECONDS * 2i64.pow(exponent)).min(MAX_RETRY_SECONDS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_retry_delay() {
+        assert_eq!(job_retry_delay(0), Duration::seconds(10));
+        assert_eq!(job_retry_delay(1), Duration::seconds(10));
+        assert_eq!(job_retry_delay(3), Duration::seconds(40));
+        assert_eq!(job_retry_delay(20), Duration::hours(1));
+    }
+}
@@ -1,22 +1,42 @@
-use std::io::Write;
-
-use diesel::{
-    deserialize::{FromSql, FromSqlRow},
-    expression::AsExpression,
-    pg::{Pg, PgValue},
-    prelude::*,
-    serialize::{IsNull, ToSql},
-};
-
-use thiserror::Error;
-
-use crate::webapp::WebappError;
-
 pub mod user;
 pub use crate::db::models::user::EmailAddress;
 pub use crate::db::models::user::NewUser;
 pub use crate::db::models::user::User;
 
+pub mod password;
+
+pub mod role;
+pub use crate::db::models::role::Role;
+
+pub mod identity;
+pub use crate::db::models::identity::NewUserIdentity;
+pub use crate::db::models::identity::UserIdentity;
+
+pub mod session;
+pub use crate::db::models::session::UserSession;
+
+pub mod totp;
+pub use crate::db::models::totp::RecoveryCode;
+
+pub mod api_token;
+pub use crate::db::models::api_token::ApiToken;
+
+pub mod audit;
+pub use crate::db::models::audit::AuditEntry;
+
+pub mod webhook;
+pub use crate::db::models::webhook::Webhook;
+pub use crate::db::models::webhook::WebhookDelivery;
+
+pub mod job;
+pub use crate::db::models::job::Job;
+
+pub mod notification;
+pub use crate::db::models::notification::Notification;
+
+pub mod reminder;
+pub use crate::db::models::reminder::GoalReminder;
+
 pub mod goal;
 pub use crate::db::models::goal::Goal;
 pub use crate::db::models::goal::NewGoal;
@@ -0,0 +1,174 @@
+use crate::{
+    db::{models::User, schema::notifications},
+    live::{Topic, publish},
+};
+use chrono::{DateTime, Utc};
+use diesel::{
+    deserialize::{FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    prelude::*,
+    serialize::{IsNull, ToSql},
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, io::Write, str::FromStr};
+use thiserror::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = diesel::sql_types::Text)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationKind {
+    Reminder,
+    Deadline,
+    Share,
+    Mention,
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Reminder => "reminder",
+            NotificationKind::Deadline => "deadline",
+            NotificationKind::Share => "share",
+            NotificationKind::Mention => "mention",
+        }
+    }
+}
+
+impl fmt::Display for NotificationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{0} is not a valid notification kind")]
+pub struct NotificationKindError(String);
+
+impl FromStr for NotificationKind {
+    type Err = NotificationKindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reminder" => Ok(NotificationKind::Reminder),
+            "deadline" => Ok(NotificationKind::Deadline),
+            "share" => Ok(NotificationKind::Share),
+            "mention" => Ok(NotificationKind::Mention),
+            _ => Err(NotificationKindError(s.to_string())),
+        }
+    }
+}
+
+impl FromSql<diesel::sql_types::Text, Pg> for NotificationKind {
+    fn from_sql(bytes: PgValue) -> diesel::deserialize::Result<Self> {
+        let string = String::from_utf8(bytes.as_bytes().to_vec())?;
+        Ok(string.parse()?)
+    }
+}
+
+impl ToSql<diesel::sql_types::Text, Pg> for NotificationKind {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = crate::db::schema::notifications)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Notification {
+    pub id: i32,
+    pub user_id: i32,
+    pub kind: NotificationKind,
+    pub title: String,
+    pub body: String,
+    pub link: Option<String>,
+    pub read_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::notifications)]
+pub struct NewNotification<'a> {
+    pub user_id: i32,
+    pub kind: NotificationKind,
+    pub title: &'a str,
+    pub body: &'a str,
+    // a path in the app, e.g. /goals
+    pub link: Option<&'a str>,
+}
+
+// what other parts of the app call to put something in a user's notification center,
+// pass the caller's transaction so it only shows up if the rest of the change does
+pub fn notify(
+    new_notification: &NewNotification,
+    conn: &mut PgConnection,
+) -> Result<Notification, diesel::result::Error> {
+    let notification = diesel::insert_into(notifications::table)
+        .values(new_notification)
+        .returning(Notification::as_returning())
+        .get_result(conn)?;
+    publish(notification.user_id, Topic::Notifications, conn)?;
+
+    Ok(notification)
+}
+
+pub fn get_notifications_for_user(
+    user: &User,
+    limit: i64,
+    conn: &mut PgConnection,
+) -> Result<Vec<Notification>, diesel::result::Error> {
+    Notification::belonging_to(user)
+        .order(notifications::created_at.desc())
+        .then_order_by(notifications::id.desc())
+        .limit(limit)
+        .load(conn)
+}
+
+pub fn count_unread(user: &User, conn: &mut PgConnection) -> Result<i64, diesel::result::Error> {
+    Notification::belonging_to(user)
+        .filter(notifications::read_at.is_null())
+        .count()
+        .get_result(conn)
+}
+
+// none when it isn't the user's, marking twice keeps the first read_at
+pub fn mark_read(
+    id: i32,
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<Option<Notification>, diesel::result::Error> {
+    let notification = Notification::belonging_to(user)
+        .filter(notifications::id.eq(id))
+        .first::<Notification>(conn)
+        .optional()?;
+
+    match notification {
+        Some(notification) if notification.read_at.is_none() => {
+            let notification = diesel::update(&notification)
+                .set(notifications::read_at.eq(Some(Utc::now())))
+                .returning(Notification::as_returning())
+                .get_result(conn)?;
+            // the bell in the user's other tabs
+            publish(user.id, Topic::Notifications, conn)?;
+            Ok(Some(notification))
+        }
+        notification => Ok(notification),
+    }
+}
+
+pub fn mark_all_read(user: &User, conn: &mut PgConnection) -> Result<usize, diesel::result::Error> {
+    let marked =
+        diesel::update(Notification::belonging_to(user).filter(notifications::read_at.is_null()))
+            .set(notifications::read_at.eq(Some(Utc::now())))
+            .execute(conn)?;
+    if marked > 0 {
+        publish(user.id, Topic::Notifications, conn)?;
+    }
+
+    Ok(marked)
+}
@@ -0,0 +1,196 @@
+use crate::{
+    config::{Config, PasswordConfig},
+    db::{models::user::User, schema::users},
+    webapp::WebappError,
+};
+use argon2::{
+    Algorithm, Argon2, Params, Version,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
+use diesel::prelude::*;
+use std::{borrow::Cow, collections::HashSet, fs, sync::OnceLock};
+use tracing::{info, warn};
+use validator::ValidationError;
+
+static PASSWORD_POLICY: OnceLock<PasswordPolicy> = OnceLock::new();
+
+// new hashes are argon2id phc strings, existing bcrypt hashes still verify
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
+
+pub fn hash_password(password: String) -> Result<String, WebappError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hashed_password = argon2().hash_password(password.trim().as_bytes(), &salt)?;
+    Ok(hashed_password.to_string())
+}
+
+pub fn verify_password(password: &str, hashed_password: &str) -> Result<bool, WebappError> {
+    if is_bcrypt(hashed_password) {
+        return bcrypt::verify(password, hashed_password).map_err(WebappError::BcryptError);
+    }
+
+    let parsed_hash = PasswordHash::new(hashed_password)?;
+    match argon2().verify_password(password.as_bytes(), &parsed_hash) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn is_bcrypt(hashed_password: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| hashed_password.starts_with(prefix))
+}
+
+// bcrypt, another algorithm, or argon2id with outdated params
+pub fn needs_rehash(hashed_password: &str) -> bool {
+    let Ok(parsed_hash) = PasswordHash::new(hashed_password) else {
+        return true;
+    };
+    if parsed_hash.algorithm != Algorithm::Argon2id.ident() {
+        return true;
+    }
+    let current = Params::default();
+    Params::try_from(&parsed_hash).map_or(true, |params| {
+        params.m_cost() != current.m_cost()
+            || params.t_cost() != current.t_cost()
+            || params.p_cost() != current.p_cost()
+    })
+}
+
+// called after a successful login, while we still have the plaintext
+pub fn rehash_password_if_needed(
+    user: &User,
+    password: &str,
+    conn: &mut PgConnection,
+) -> Result<(), WebappError> {
+    let Some(hashed_password) = &user.hashed_password else {
+        return Ok(());
+    };
+    if !needs_rehash(hashed_password) {
+        return Ok(());
+    }
+
+    let rehashed_password = hash_password(password.to_string())?;
+    diesel::update(users::table.find(user.id))
+        .set(users::hashed_password.eq(rehashed_password))
+        .execute(conn)?;
+    info!("rehashed password for {}", user.username);
+
+    Ok(())
+}
+
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    // lowercased, one password per line in the source file
+    pub breached_passwords: HashSet<String>,
+}
+
+impl PasswordPolicy {
+    pub fn from_config(config: &PasswordConfig) -> Self {
+        let breached_passwords = match &config.breached_passwords_file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => contents
+                    .lines()
+                    .map(|line| line.trim().to_lowercase())
+                    .filter(|line| !line.is_empty())
+                    .collect(),
+                Err(e) => {
+                    warn!(
+                        "could not read breached passwords from {}: {}",
+                        path.display(),
+                        e
+                    );
+                    HashSet::new()
+                }
+            },
+            None => HashSet::new(),
+        };
+
+        Self {
+            min_length: config.min_length,
+            max_length: config.max_length,
+            breached_passwords,
+        }
+    }
+
+    pub fn check(&self, password: &str) -> Result<(), ValidationError> {
+        let length = password.chars().count();
+        if length < self.min_length || length > self.max_length {
+            return Err(
+                ValidationError::new("password_length").with_message(Cow::from(format!(
+                    "Password must be between {} and {} characters.",
+                    self.min_length, self.max_length
+                ))),
+            );
+        }
+        if self
+            .breached_passwords
+            .contains(&password.trim().to_lowercase())
+        {
+            return Err(
+                ValidationError::new("password_breached").with_message(Cow::from(
+                    "This password has appeared in a data breach, please choose another.",
+                )),
+            );
+        }
+        Ok(())
+    }
+}
+
+// called once at startup, before anything validates a password
+pub fn init_password_policy(config: &PasswordConfig) {
+    if PASSWORD_POLICY
+        .set(PasswordPolicy::from_config(config))
+        .is_err()
+    {
+        warn!("password policy was already initialized");
+    }
+}
+
+// for #[validate(custom)], the cli and tests don't init the policy so it is loaded on first use
+pub fn validate_password(password: &str) -> Result<(), ValidationError> {
+    PASSWORD_POLICY
+        .get_or_init(|| {
+            PasswordPolicy::from_config(&Config::load().map(|c| c.password).unwrap_or_default())
+        })
+        .check(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hashed_password = hash_password("correct horse".to_string()).unwrap();
+        assert!(hashed_password.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hashed_password).unwrap());
+        assert!(!verify_password("wrong horse", &hashed_password).unwrap());
+        assert!(!needs_rehash(&hashed_password));
+    }
+
+    #[test]
+    fn test_legacy_bcrypt() {
+        let hashed_password = bcrypt::hash("correct horse", 4).unwrap();
+        assert!(verify_password("correct horse", &hashed_password).unwrap());
+        assert!(!verify_password("wrong horse", &hashed_password).unwrap());
+        assert!(needs_rehash(&hashed_password));
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = PasswordPolicy {
+            min_length: 8,
+            max_length: 16,
+            breached_passwords: HashSet::from(["password123".to_string()]),
+        };
+        assert!(policy.check("long enough").is_ok());
+        assert!(policy.check("short").is_err());
+        assert!(policy.check("much too long for this policy").is_err());
+        assert!(policy.check("Password123").is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use crate::db::{
+    models::{Goal, User},
+    schema::{goal_reminders, goals, users},
+};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use diesel::{
+    deserialize::{FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    prelude::*,
+    serialize::{IsNull, ToSql},
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt, io::Write, str::FromStr};
+use thiserror::Error;
+
+// bit 0 is monday, matching chrono's num_days_from_monday
+pub const EVERY_DAY: i32 = 0b111_1111;
+pub const WEEKDAYS: i32 = 0b001_1111;
+pub const WEEKENDS: i32 = 0b110_0000;
+
+const DAY_NAMES: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = diesel::sql_types::Text)]
+#[serde(rename_all = "snake_case")]
+pub enum ReminderKind {
+    // on the chosen days of the week
+    Weekly,
+    // once, some days before the goal's target date
+    BeforeTarget,
+}
+
+impl ReminderKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReminderKind::Weekly => "weekly",
+            ReminderKind::BeforeTarget => "before_target",
+        }
+    }
+}
+
+impl fmt::Display for ReminderKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{0} is not a valid reminder kind")]
+pub struct ReminderKindError(String);
+
+impl FromStr for ReminderKind {
+    type Err = ReminderKindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "weekly" => Ok(ReminderKind::Weekly),
+            "before_target" => Ok(ReminderKind::BeforeTarget),
+            _ => Err(ReminderKindError(s.to_string())),
+        }
+    }
+}
+
+impl FromSql<diesel::sql_types::Text, Pg> for ReminderKind {
+    fn from_sql(bytes: PgValue) -> diesel::deserialize::Result<Self> {
+        let string = String::from_utf8(bytes.as_bytes().to_vec())?;
+        Ok(string.parse()?)
+    }
+}
+
+impl ToSql<diesel::sql_types::Text, Pg> for ReminderKind {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+// when a reminder fires, times are in the owner's time zone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReminderSchedule {
+    pub kind: ReminderKind,
+    pub weekdays: i32,
+    pub days_before: i32,
+    pub time_of_day: NaiveTime,
+}
+
+impl ReminderSchedule {
+    // the first time strictly after `after`, none when the reminder won't fire again
+    pub fn next_run_after(
+        &self,
+        target_date: Option<NaiveDate>,
+        tz: Tz,
+        after: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        match self.kind {
+            ReminderKind::Weekly => {
+                let today = after.with_timezone(&tz).date_naive();
+                // 8 days so today's time having passed still finds today next week
+                (0..8)
+                    .map(|offset| today + Duration::days(offset))
+                    .filter(|day| self.weekdays & (1 << day.weekday().num_days_from_monday()) != 0)
+                    .filter_map(|day| local_to_utc(tz, day.and_time(self.time_of_day)))
+                    .find(|run_at| *run_at > after)
+            }
+            ReminderKind::BeforeTarget => {
+                let day = target_date? - Duration::days(self.days_before.into());
+                local_to_utc(tz, day.and_time(self.time_of_day)).filter(|run_at| *run_at > after)
+            }
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let time = self.time_of_day.format("%H:%M");
+        match self.kind {
+            ReminderKind::Weekly => {
+                let days = match self.weekdays & EVERY_DAY {
+                    EVERY_DAY => "Every day".to_string(),
+                    WEEKDAYS => "Weekdays".to_string(),
+                    WEEKENDS => "Weekends".to_string(),
+                    weekdays => DAY_NAMES
+                        .iter()
+                        .enumerate()
+                        .filter(|(bit, _)| weekdays & (1 << bit) != 0)
+                        .map(|(_, name)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                };
+                format!("{days} at {time}")
+            }
+            ReminderKind::BeforeTarget => match self.days_before {
+                0 => format!("On the target date at {time}"),
+                1 => format!("1 day before the target date at {time}"),
+                days => format!("{days} days before the target date at {time}"),
+            },
+        }
+    }
+}
+
+// a time skipped by a dst change fires an hour later, a repeated one fires the first time
+fn local_to_utc(tz: Tz, local: NaiveDateTime) -> Option<DateTime<Utc>> {
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| {
+            tz.from_local_datetime(&(local + Duration::hours(1)))
+                .earliest()
+        })
+        .map(|run_at| run_at.with_timezone(&Utc))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Queryable, Identifiable, Selectable, Associations)]
+#[diesel(belongs_to(Goal))]
+#[diesel(table_name = crate::db::schema::goal_reminders)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct GoalReminder {
+    pub id: i32,
+    pub goal_id: i32,
+    pub kind: ReminderKind,
+    pub weekdays: i32,
+    pub days_before: i32,
+    pub time_of_day: NaiveTime,
+    pub next_run_at: Option<DateTime<Utc>>,
+    pub last_sent_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl GoalReminder {
+    pub fn schedule(&self) -> ReminderSchedule {
+        ReminderSchedule {
+            kind: self.kind,
+            weekdays: self.weekdays,
+            days_before: self.days_before,
+            time_of_day: self.time_of_day,
+        }
+    }
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::goal_reminders)]
+struct NewGoalReminder {
+    goal_id: i32,
+    kind: ReminderKind,
+    weekdays: i32,
+    days_before: i32,
+    time_of_day: NaiveTime,
+    next_run_at: Option<DateTime<Utc>>,
+}
+
+pub fn create_reminder(
+    goal: &Goal,
+    user: &User,
+    schedule: ReminderSchedule,
+    conn: &mut PgConnection,
+) -> Result<GoalReminder, diesel::result::Error> {
+    let new_reminder = NewGoalReminder {
+        goal_id: goal.id,
+        kind: schedule.kind,
+        weekdays: schedule.weekdays,
+        days_before: schedule.days_before,
+        time_of_day: schedule.time_of_day,
+        next_run_at: schedule.next_run_after(goal.target_date, user.tz(), Utc::now()),
+    };
+
+    diesel::insert_into(goal_reminders::table)
+        .values(&new_reminder)
+        .returning(GoalReminder::as_returning())
+        .get_result(conn)
+}
+
+pub fn get_reminders_for_goal(
+    goal: &Goal,
+    conn: &mut PgConnection,
+) -> Result<Vec<GoalReminder>, diesel::result::Error> {
+    GoalReminder::belonging_to(goal)
+        .order(goal_reminders::id)
+        .load(conn)
+}
+
+pub fn delete_reminder(
+    id: i32,
+    goal: &Goal,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(GoalReminder::belonging_to(goal).filter(goal_reminders::id.eq(id))).execute(conn)
+}
+
+// after a goal's target date or its owner's time zone changed
+pub fn reschedule_reminders(
+    goal: &Goal,
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<(), diesel::result::Error> {
+    let now = Utc::now();
+    for reminder in get_reminders_for_goal(goal, conn)? {
+        let next_run_at = reminder
+            .schedule()
+            .next_run_after(goal.target_date, user.tz(), now);
+        diesel::update(&reminder)
+            .set(goal_reminders::next_run_at.eq(next_run_at))
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+pub fn reschedule_reminders_for_user(
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<(), diesel::result::Error> {
+    for goal in Goal::belonging_to(user).load::<Goal>(conn)? {
+        reschedule_reminders(&goal, user, conn)?;
+    }
+
+    Ok(())
+}
+
+// locks the due reminders and moves each on to its next run, so the caller should hand them
+// off in the same transaction. a run missed while nothing was polling fires once, not per miss
+pub fn advance_due_reminders(
+    limit: i64,
+    conn: &mut PgConnection,
+) -> Result<Vec<(GoalReminder, Goal, User)>, diesel::result::Error> {
+    let now = Utc::now();
+    let ids = goal_reminders::table
+        .filter(goal_reminders::next_run_at.le(now))
+        .order(goal_reminders::next_run_at)
+        .limit(limit)
+        .select(goal_reminders::id)
+        .for_update()
+        .skip_locked()
+        .load::<i32>(conn)?;
+
+    let due = goal_reminders::table
+        .inner_join(goals::table.inner_join(users::table))
+        .filter(goal_reminders::id.eq_any(&ids))
+        .select((
+            GoalReminder::as_select(),
+            Goal::as_select(),
+            User::as_select(),
+        ))
+        .load::<(GoalReminder, Goal, User)>(conn)?;
+
+    for (reminder, goal, user) in &due {
+        let next_run_at = reminder
+            .schedule()
+            .next_run_after(goal.target_date, user.tz(), now);
+        diesel::update(reminder)
+            .set(goal_reminders::next_run_at.eq(next_run_at))
+            .execute(conn)?;
+    }
+
+    Ok(due)
+}
+
+pub fn mark_reminder_sent(
+    reminder: &GoalReminder,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::update(reminder)
+        .set(goal_reminders::last_sent_at.eq(Some(Utc::now())))
+        .execute(conn)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weekly(weekdays: i32, time: &str) -> ReminderSchedule {
+        ReminderSchedule {
+            kind: ReminderKind::Weekly,
+            weekdays,
+            days_before: 0,
+            time_of_day: time.parse().unwrap(),
+        }
+    }
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_weekly_next_run() {
+        let tz: Tz = "Europe/Berlin".parse().unwrap();
+        let schedule = weekly(WEEKDAYS, "09:00:00");
+
+        // friday 2026-10-16 07:00 utc is 09:00 in berlin, so an hour later the next is monday
+        assert_eq!(
+            schedule.next_run_after(None, tz, utc("2026-10-16T06:00:00Z")),
+            Some(utc("2026-10-16T07:00:00Z"))
+        );
+        assert_eq!(
+            schedule.next_run_after(None, tz, utc("2026-10-16T07:00:00Z")),
+            Some(utc("2026-10-19T07:00:00Z"))
+        );
+
+        // only wednesdays, asked on a wednesday after the time has passed
+        assert_eq!(
+            weekly(1 << 2, "09:00:00").next_run_after(None, tz, utc("2026-10-14T12:00:00Z")),
+            Some(utc("2026-10-21T07:00:00Z"))
+        );
+        assert_eq!(
+            weekly(0, "09:00:00").next_run_after(None, tz, Utc::now()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_weekly_next_run_across_dst() {
+        let tz: Tz = "America/New_York".parse().unwrap();
+
+        // clocks go back on 2026-11-01, 09:00 local moves from 13:00 to 14:00 utc
+        assert_eq!(
+            weekly(EVERY_DAY, "09:00:00").next_run_after(None, tz, utc("2026-10-31T14:00:00Z")),
+            Some(utc("2026-11-01T14:00:00Z"))
+        );
+
+        // 02:30 doesn't exist on 2026-03-08, it fires at 03:30 edt instead
+        assert_eq!(
+            weekly(EVERY_DAY, "02:30:00").next_run_after(None, tz, utc("2026-03-08T05:00:00Z")),
+            Some(utc("2026-03-08T07:30:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_before_target_next_run() {
+        let schedule = ReminderSchedule {
+            kind: ReminderKind::BeforeTarget,
+            weekdays: 0,
+            days_before: 3,
+            time_of_day: "08:30:00".parse().unwrap(),
+        };
+        let target = NaiveDate::from_ymd_opt(2026, 12, 24);
+
+        assert_eq!(
+            schedule.next_run_after(target, Tz::UTC, utc("2026-12-01T00:00:00Z")),
+            Some(utc("2026-12-21T08:30:00Z"))
+        );
+        assert_eq!(
+            schedule.next_run_after(target, Tz::UTC, utc("2026-12-21T08:30:00Z")),
+            None
+        );
+        assert_eq!(
+            schedule.next_run_after(None, Tz::UTC, utc("2026-12-01T00:00:00Z")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(weekly(WEEKDAYS, "09:00:00").summary(), "Weekdays at 09:00");
+        assert_eq!(
+            weekly(0b000_0101, "18:15:00").summary(),
+            "Mon, Wed at 18:15"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use crate::db::{
+    models::user::User,
+    schema::{user_sessions, users},
+};
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use rand::distr::{Alphanumeric, SampleString};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+// don't write last_seen_at on every request
+const TOUCH_INTERVAL_SECONDS: i64 = 60;
+
+// a logged in browser, the token itself only lives in the encrypted session cookie
+#[derive(Debug, Clone, PartialEq, Serialize, Queryable, Identifiable, Associations, Selectable)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = crate::db::schema::user_sessions)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct UserSession {
+    pub id: i32,
+    pub user_id: i32,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub impersonator_id: Option<i32>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::user_sessions)]
+pub struct NewUserSession {
+    pub user_id: i32,
+    pub token_hash: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub impersonator_id: Option<i32>,
+}
+
+// returns the session and the plaintext token for the cookie
+pub fn create_session(
+    user: &User,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    conn: &mut PgConnection,
+) -> Result<(UserSession, String), diesel::result::Error> {
+    insert_session(user, None, ip, user_agent, conn)
+}
+
+// a session as user, started by the admin in impersonator
+pub fn create_impersonation_session(
+    user: &User,
+    impersonator: &User,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    conn: &mut PgConnection,
+) -> Result<(UserSession, String), diesel::result::Error> {
+    insert_session(user, Some(impersonator.id), ip, user_agent, conn)
+}
+
+fn insert_session(
+    user: &User,
+    impersonator_id: Option<i32>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    conn: &mut PgConnection,
+) -> Result<(UserSession, String), diesel::result::Error> {
+    let token = Alphanumeric.sample_string(&mut rand::rng(), 32);
+    let new_session = NewUserSession {
+        user_id: user.id,
+        token_hash: hash_session_token(&token),
+        ip,
+        user_agent,
+        impersonator_id,
+    };
+
+    let session = diesel::insert_into(user_sessions::table)
+        .values(&new_session)
+        .returning(UserSession::as_returning())
+        .get_result(conn)?;
+
+    Ok((session, token))
+}
+
+pub fn get_session_by_token(
+    token: &str,
+    conn: &mut PgConnection,
+) -> Result<Option<(UserSession, User)>, diesel::result::Error> {
+    user_sessions::table
+        .inner_join(users::table)
+        .filter(user_sessions::token_hash.eq(hash_session_token(token)))
+        .select((UserSession::as_select(), User::as_select()))
+        .first(conn)
+        .optional()
+}
+
+pub fn delete_session(
+    session: &UserSession,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(user_sessions::table.find(session.id)).execute(conn)
+}
+
+pub fn touch_session(
+    session: &UserSession,
+    conn: &mut PgConnection,
+) -> Result<(), diesel::result::Error> {
+    let now = Utc::now();
+    if now - session.last_seen_at < Duration::seconds(TOUCH_INTERVAL_SECONDS) {
+        return Ok(());
+    }
+
+    diesel::update(user_sessions::table.find(session.id))
+        .set(user_sessions::last_seen_at.eq(now))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn get_sessions_for_user(
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<Vec<UserSession>, diesel::result::Error> {
+    UserSession::belonging_to(user)
+        .order(user_sessions::last_seen_at.desc())
+        .load(conn)
+}
+
+pub fn delete_session_by_token(
+    token: &str,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(
+        user_sessions::table.filter(user_sessions::token_hash.eq(hash_session_token(token))),
+    )
+    .execute(conn)
+}
+
+// signs the user out everywhere, except the session in keep if given
+pub fn delete_sessions_for_user(
+    user: &User,
+    keep: Option<i32>,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    let mut query = diesel::delete(user_sessions::table)
+        .filter(user_sessions::user_id.eq(user.id))
+        .into_boxed();
+    if let Some(keep) = keep {
+        query = query.filter(user_sessions::id.ne(keep));
+    }
+    query.execute(conn)
+}
+
+fn hash_session_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
@@ -0,0 +1,179 @@
+use crate::{
+    db::{
+        models::user::User,
+        schema::{recovery_codes, users},
+    },
+    webapp::WebappError,
+};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rand::distr::{Alphanumeric, SampleString};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const ISSUER: &str = "axum-boilerplate";
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Debug, PartialEq, Queryable, Identifiable, Associations, Selectable)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = crate::db::schema::recovery_codes)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct RecoveryCode {
+    pub id: i32,
+    pub user_id: i32,
+    pub code_hash: String,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::recovery_codes)]
+pub struct NewRecoveryCode {
+    pub user_id: i32,
+    pub code_hash: String,
+}
+
+// new random base32 secret, not saved until the user confirms a code
+pub fn generate_totp_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+// rfc 6238 defaults: sha1, 6 digits, 30 second steps, one step of clock skew
+pub fn totp_for(secret: &str, username: &str) -> Result<TOTP, WebappError> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes()?;
+    let totp = TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        secret,
+        Some(ISSUER.to_string()),
+        username.to_string(),
+    )?;
+    Ok(totp)
+}
+
+pub fn verify_totp_code(secret: &str, username: &str, code: &str) -> Result<bool, WebappError> {
+    Ok(totp_step(secret, username, code)?.is_some())
+}
+
+// the time step the code belongs to, if it matches one within the allowed skew
+pub fn totp_step(secret: &str, username: &str, code: &str) -> Result<Option<i64>, WebappError> {
+    let totp = totp_for(secret, username)?;
+    let code = code.trim();
+    let now = Utc::now().timestamp() / totp.step as i64;
+    let skew = totp.skew as i64;
+    Ok((now - skew..=now + skew)
+        .find(|step| totp.generate((step * totp.step as i64) as u64) == code))
+}
+
+// accepts a code only if its step is newer than the last one accepted, so an
+// observed code can't be replayed within its validity window
+pub fn use_totp_code(
+    user: &User,
+    code: &str,
+    conn: &mut PgConnection,
+) -> Result<bool, WebappError> {
+    let Some(secret) = &user.totp_secret else {
+        return Ok(false);
+    };
+    let Some(step) = totp_step(secret, &user.username, code)? else {
+        return Ok(false);
+    };
+    let used = diesel::update(
+        users::table.find(user.id).filter(
+            users::totp_last_step
+                .is_null()
+                .or(users::totp_last_step.lt(step)),
+        ),
+    )
+    .set(users::totp_last_step.eq(step))
+    .execute(conn)?;
+
+    Ok(used > 0)
+}
+
+// saves the secret and returns fresh recovery codes, which are only shown this once
+pub fn enable_totp(
+    user: &User,
+    secret: &str,
+    conn: &mut PgConnection,
+) -> Result<Vec<String>, diesel::result::Error> {
+    conn.transaction(|conn| {
+        diesel::update(users::table.find(user.id))
+            .set((
+                users::totp_secret.eq(secret),
+                users::totp_last_step.eq(None::<i64>),
+            ))
+            .execute(conn)?;
+        regenerate_recovery_codes(user, conn)
+    })
+}
+
+pub fn disable_totp(user: &User, conn: &mut PgConnection) -> Result<(), diesel::result::Error> {
+    conn.transaction(|conn| {
+        diesel::update(users::table.find(user.id))
+            .set((
+                users::totp_secret.eq(None::<String>),
+                users::totp_last_step.eq(None::<i64>),
+            ))
+            .execute(conn)?;
+        diesel::delete(RecoveryCode::belonging_to(user)).execute(conn)?;
+        Ok(())
+    })
+}
+
+pub fn regenerate_recovery_codes(
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<Vec<String>, diesel::result::Error> {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = Alphanumeric
+                .sample_string(&mut rand::rng(), 10)
+                .to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect();
+
+    let new_codes: Vec<NewRecoveryCode> = codes
+        .iter()
+        .map(|code| NewRecoveryCode {
+            user_id: user.id,
+            code_hash: hash_recovery_code(code),
+        })
+        .collect();
+
+    diesel::delete(RecoveryCode::belonging_to(user)).execute(conn)?;
+    diesel::insert_into(recovery_codes::table)
+        .values(&new_codes)
+        .execute(conn)?;
+
+    Ok(codes)
+}
+
+// marks the code used, so each one only works once
+pub fn use_recovery_code(
+    user: &User,
+    code: &str,
+    conn: &mut PgConnection,
+) -> Result<bool, diesel::result::Error> {
+    let used = diesel::update(
+        RecoveryCode::belonging_to(user)
+            .filter(recovery_codes::code_hash.eq(hash_recovery_code(code)))
+            .filter(recovery_codes::used_at.is_null()),
+    )
+    .set(recovery_codes::used_at.eq(Utc::now()))
+    .execute(conn)?;
+
+    Ok(used > 0)
+}
+
+// recovery codes are long and random, so a fast hash is enough
+fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
@@ -1,20 +1,28 @@
-use crate::{
-    db::schema::{self, users},
-    webapp::WebappError,
+use crate::db::{
+    models::{role::Role, session::delete_sessions_for_user},
+    schema::{self, goals, users},
 };
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
 use diesel::{
     deserialize::{FromSql, FromSqlRow},
+    dsl::case_when,
     expression::AsExpression,
     pg::{Pg, PgValue},
     prelude::*,
     serialize::{IsNull, ToSql},
+    sql_types::Integer,
 };
+use lazy_static::lazy_static;
 use serde::Deserialize;
 use std::io::Write;
 use thiserror::Error;
+use utoipa::ToSchema;
 use validator::{Validate, ValidateEmail};
 
-#[derive(Debug, Clone, PartialEq, Deserialize, Validate, AsExpression, FromSqlRow)]
+pub use super::password::{hash_password, validate_password, verify_password};
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Validate, AsExpression, FromSqlRow, ToSchema)]
 #[diesel(sql_type = diesel::sql_types::Text)]
 pub struct EmailAddress {
     #[validate(email)]
@@ -60,6 +68,9 @@ impl EmailAddress {
         }
     }
 
+    /// # Safety
+    ///
+    /// `raw_email` is stored as-is, so it must already be a valid, lowercased address.
     pub unsafe fn new_unchecked(raw_email: &str) -> Self {
         Self {
             address: raw_email.to_string(),
@@ -67,7 +78,7 @@ impl EmailAddress {
     }
 }
 
-#[derive(Debug, PartialEq, Queryable, Identifiable, Selectable, AsChangeset)]
+#[derive(Debug, Clone, PartialEq, Queryable, Identifiable, Selectable, AsChangeset)]
 #[diesel(table_name = crate::db::schema::users)]
 #[diesel(check_for_backend(diesel::pg::Pg))]
 #[diesel(treat_none_as_null = true)]
@@ -76,9 +87,34 @@ pub struct User {
     pub username: String,
     pub hashed_password: Option<String>,
     pub email: Option<EmailAddress>,
+    pub totp_secret: Option<String>,
+    pub failed_login_count: i32,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub role: Role,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub password_reset_required: bool,
+    pub time_zone: String,
+    // see use_totp_code
+    pub totp_last_step: Option<i64>,
+}
+
+impl User {
+    // an unknown name, e.g. one dropped from the tz database, falls back to utc
+    pub fn tz(&self) -> Tz {
+        self.time_zone.parse().unwrap_or(Tz::UTC)
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.disabled_at.is_some()
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked_until
+            .is_some_and(|locked_until| locked_until > Utc::now())
+    }
 }
 
-#[derive(Debug, Deserialize, Validate, Insertable)]
+#[derive(Debug, Deserialize, Validate, Insertable, ToSchema)]
 #[diesel(table_name = crate::db::schema::users)]
 pub struct NewUser {
     #[validate(length(
@@ -89,15 +125,79 @@ pub struct NewUser {
     pub username: String,
     #[validate(nested)]
     pub email: Option<EmailAddress>,
+    // plaintext, only here to be checked against the password policy before hashing
+    #[serde(skip)]
+    #[diesel(skip_insertion)]
+    #[validate(custom(function = "validate_password"))]
+    pub password: Option<String>,
+    #[schema(ignore)]
     pub hashed_password: Option<String>,
 }
 
-pub fn hash_password(password: String) -> Result<String, WebappError> {
-    bcrypt::hash(password.trim(), bcrypt::DEFAULT_COST).map_err(WebappError::BcryptError)
+// admin edits, password changes go through set_password
+#[derive(Debug, AsChangeset)]
+#[diesel(table_name = crate::db::schema::users)]
+#[diesel(treat_none_as_null = true)]
+pub struct UserChanges {
+    pub username: String,
+    pub email: Option<EmailAddress>,
+    pub role: Role,
+}
+
+lazy_static! {
+    static ref DUMMY_HASH: String =
+        hash_password("dummy password".to_string()).expect("dummy hash should build");
+}
+
+// same cost as a real verify, for when there is no user or password to check against
+pub fn dummy_verify_password(password: &str) {
+    let _ = verify_password(password, &DUMMY_HASH);
+}
+
+// consecutive failures before the account is locked, and for how long
+const LOCKOUT_THRESHOLD: i32 = 10;
+const LOCKOUT_MINUTES: i64 = 15;
+
+// one statement, so concurrent failures can't overwrite each other's count. a lock that has run
+// out starts the count over, otherwise the next mistake would lock the account again
+pub fn record_failed_login(
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<User, diesel::result::Error> {
+    let now = Utc::now();
+    let lock_expired = users::locked_until.lt(now);
+    let failed_login_count =
+        case_when::<_, _, Integer>(lock_expired, 1).otherwise(users::failed_login_count + 1);
+    let locked_until = case_when(lock_expired, None::<DateTime<Utc>>)
+        .when(
+            users::failed_login_count.ge(LOCKOUT_THRESHOLD - 1),
+            Some(now + Duration::minutes(LOCKOUT_MINUTES)),
+        )
+        .otherwise(None::<DateTime<Utc>>);
+
+    diesel::update(users::table.find(user.id))
+        .set((
+            users::failed_login_count.eq(failed_login_count),
+            users::locked_until.eq(locked_until),
+        ))
+        .returning(User::as_returning())
+        .get_result(conn)
 }
 
-pub fn verify_password(password: &str, hashed_password: &str) -> Result<bool, WebappError> {
-    bcrypt::verify(password, hashed_password).map_err(WebappError::BcryptError)
+pub fn reset_failed_logins(
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<(), diesel::result::Error> {
+    if user.failed_login_count == 0 && user.locked_until.is_none() {
+        return Ok(());
+    }
+    diesel::update(users::table.find(user.id))
+        .set((
+            users::failed_login_count.eq(0),
+            users::locked_until.eq(None::<DateTime<Utc>>),
+        ))
+        .execute(conn)?;
+    Ok(())
 }
 
 pub fn get_user_by_email(email: &str, conn: &mut PgConnection) -> Option<User> {
@@ -116,6 +216,146 @@ pub fn get_user_by_username(username: &str, conn: &mut PgConnection) -> Option<U
         .unwrap()
 }
 
+pub fn get_users_with_role(
+    role: Role,
+    conn: &mut PgConnection,
+) -> Result<Vec<User>, diesel::result::Error> {
+    users::table
+        .filter(users::role.eq(role))
+        .order(users::username)
+        .load(conn)
+}
+
+pub fn count_users(conn: &mut PgConnection) -> Result<i64, diesel::result::Error> {
+    users::table.count().get_result(conn)
+}
+
+// case insensitive match on username or email, pages start at 1
+pub fn search_users(
+    search: Option<&str>,
+    page: i64,
+    page_size: i64,
+    conn: &mut PgConnection,
+) -> Result<(Vec<User>, i64), diesel::result::Error> {
+    let filtered = || {
+        let mut query = users::table.into_boxed();
+        if let Some(search) = search.filter(|search| !search.is_empty()) {
+            let pattern = format!("%{}%", escape_like(search));
+            query = query.filter(
+                users::username
+                    .ilike(pattern.clone())
+                    .or(users::email.ilike(pattern)),
+            );
+        }
+        query
+    };
+
+    let total = filtered().count().get_result(conn)?;
+    let users = filtered()
+        .order(users::username)
+        .limit(page_size)
+        .offset((page.max(1) - 1) * page_size)
+        .load(conn)?;
+
+    Ok((users, total))
+}
+
+// so a search for % or _ matches them literally, for ilike filters
+pub(crate) fn escape_like(search: &str) -> String {
+    search
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+pub fn update_user(
+    user: &User,
+    changes: &UserChanges,
+    conn: &mut PgConnection,
+) -> Result<User, diesel::result::Error> {
+    diesel::update(users::table.find(user.id))
+        .set(changes)
+        .returning(User::as_returning())
+        .get_result(conn)
+}
+
+// also clears the reset flag, the user has just picked a new password
+pub fn set_password(
+    user: &User,
+    hashed_password: &str,
+    conn: &mut PgConnection,
+) -> Result<User, diesel::result::Error> {
+    diesel::update(users::table.find(user.id))
+        .set((
+            users::hashed_password.eq(hashed_password),
+            users::password_reset_required.eq(false),
+        ))
+        .returning(User::as_returning())
+        .get_result(conn)
+}
+
+pub fn set_time_zone(
+    user: &User,
+    tz: Tz,
+    conn: &mut PgConnection,
+) -> Result<User, diesel::result::Error> {
+    diesel::update(users::table.find(user.id))
+        .set(users::time_zone.eq(tz.name()))
+        .returning(User::as_returning())
+        .get_result(conn)
+}
+
+// disabling signs the user out everywhere
+pub fn set_user_disabled(
+    user: &User,
+    disabled: bool,
+    conn: &mut PgConnection,
+) -> Result<User, diesel::result::Error> {
+    conn.transaction(|conn| {
+        if disabled {
+            delete_sessions_for_user(user, None, conn)?;
+        }
+        diesel::update(users::table.find(user.id))
+            .set(users::disabled_at.eq(disabled.then(Utc::now)))
+            .returning(User::as_returning())
+            .get_result(conn)
+    })
+}
+
+// signs the user out, they have to pick a new password after the next login
+pub fn require_password_reset(
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<User, diesel::result::Error> {
+    conn.transaction(|conn| {
+        delete_sessions_for_user(user, None, conn)?;
+        diesel::update(users::table.find(user.id))
+            .set(users::password_reset_required.eq(true))
+            .returning(User::as_returning())
+            .get_result(conn)
+    })
+}
+
+// goals don't cascade, everything else does
+pub fn delete_user(user: &User, conn: &mut PgConnection) -> Result<(), diesel::result::Error> {
+    conn.transaction(|conn| {
+        diesel::delete(goals::table.filter(goals::user_id.eq(user.id))).execute(conn)?;
+        diesel::delete(users::table.find(user.id)).execute(conn)?;
+        Ok(())
+    })
+}
+
+pub fn set_user_role(
+    user: &User,
+    role: Role,
+    conn: &mut PgConnection,
+) -> Result<User, diesel::result::Error> {
+    diesel::update(users::table.find(user.id))
+        .set(users::role.eq(role))
+        .returning(User::as_returning())
+        .get_result(conn)
+}
+
 pub fn create_new_user(
     new_user: &NewUser,
     conn: &mut PgConnection,
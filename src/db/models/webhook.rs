@@ -0,0 +1,451 @@
+use crate::db::{
+    models::user::User,
+    schema::{webhook_deliveries, webhooks},
+};
+use chrono::{DateTime, Duration, Utc};
+use diesel::{
+    deserialize::{FromSql, FromSqlRow},
+    expression::AsExpression,
+    pg::{Pg, PgValue},
+    prelude::*,
+    serialize::{IsNull, ToSql},
+};
+use hmac::{Hmac, Mac};
+use rand::distr::{Alphanumeric, SampleString};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{fmt, io::Write, str::FromStr};
+use thiserror::Error;
+
+const SECRET_PREFIX: &str = "whsec_";
+
+// after this many failed attempts a delivery is given up on
+pub const MAX_ATTEMPTS: i32 = 8;
+
+// retries back off from 30 seconds up to 6 hours
+const BASE_RETRY_SECONDS: i64 = 30;
+const MAX_RETRY_SECONDS: i64 = 6 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = diesel::sql_types::Text)]
+pub enum WebhookEvent {
+    #[serde(rename = "goal.created")]
+    GoalCreated,
+    #[serde(rename = "goal.updated")]
+    GoalUpdated,
+    #[serde(rename = "goal.completed")]
+    GoalCompleted,
+    #[serde(rename = "goal.deleted")]
+    GoalDeleted,
+}
+
+impl WebhookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::GoalCreated => "goal.created",
+            WebhookEvent::GoalUpdated => "goal.updated",
+            WebhookEvent::GoalCompleted => "goal.completed",
+            WebhookEvent::GoalDeleted => "goal.deleted",
+        }
+    }
+}
+
+impl fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{0} is not a valid webhook event")]
+pub struct WebhookEventError(String);
+
+impl FromStr for WebhookEvent {
+    type Err = WebhookEventError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "goal.created" => Ok(WebhookEvent::GoalCreated),
+            "goal.updated" => Ok(WebhookEvent::GoalUpdated),
+            "goal.completed" => Ok(WebhookEvent::GoalCompleted),
+            "goal.deleted" => Ok(WebhookEvent::GoalDeleted),
+            _ => Err(WebhookEventError(s.to_string())),
+        }
+    }
+}
+
+impl FromSql<diesel::sql_types::Text, Pg> for WebhookEvent {
+    fn from_sql(bytes: PgValue) -> diesel::deserialize::Result<Self> {
+        let string = String::from_utf8(bytes.as_bytes().to_vec())?;
+        Ok(string.parse()?)
+    }
+}
+
+impl ToSql<diesel::sql_types::Text, Pg> for WebhookEvent {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = diesel::sql_types::Text)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{0} is not a valid delivery status")]
+pub struct DeliveryStatusError(String);
+
+impl FromStr for DeliveryStatus {
+    type Err = DeliveryStatusError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(DeliveryStatus::Pending),
+            "delivered" => Ok(DeliveryStatus::Delivered),
+            "failed" => Ok(DeliveryStatus::Failed),
+            _ => Err(DeliveryStatusError(s.to_string())),
+        }
+    }
+}
+
+impl FromSql<diesel::sql_types::Text, Pg> for DeliveryStatus {
+    fn from_sql(bytes: PgValue) -> diesel::deserialize::Result<Self> {
+        let string = String::from_utf8(bytes.as_bytes().to_vec())?;
+        Ok(string.parse()?)
+    }
+}
+
+impl ToSql<diesel::sql_types::Text, Pg> for DeliveryStatus {
+    fn to_sql<'b>(
+        &'b self,
+        out: &mut diesel::serialize::Output<'b, '_, Pg>,
+    ) -> diesel::serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+// a url that gets every goal event of its user, signed with the secret
+#[derive(Debug, Clone, PartialEq, Serialize, Queryable, Identifiable, Associations, Selectable)]
+#[diesel(belongs_to(User))]
+#[diesel(table_name = crate::db::schema::webhooks)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct Webhook {
+    pub id: i32,
+    pub user_id: i32,
+    pub url: String,
+    #[serde(skip)]
+    pub secret: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::webhooks)]
+pub struct NewWebhook {
+    pub user_id: i32,
+    pub url: String,
+    pub secret: String,
+}
+
+// one event for one webhook, pending rows are the outgoing queue
+#[derive(Debug, Clone, PartialEq, Serialize, Queryable, Identifiable, Associations, Selectable)]
+#[diesel(belongs_to(Webhook))]
+#[diesel(table_name = crate::db::schema::webhook_deliveries)]
+#[diesel(check_for_backend(diesel::pg::Pg))]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: WebhookEvent,
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime<Utc>,
+    pub response_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub delivered_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Insertable)]
+#[diesel(table_name = crate::db::schema::webhook_deliveries)]
+pub struct NewWebhookDelivery<'a> {
+    pub webhook_id: i32,
+    pub event: WebhookEvent,
+    pub payload: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a, T: Serialize> {
+    event: WebhookEvent,
+    created_at: DateTime<Utc>,
+    data: &'a T,
+}
+
+// the secret is shown once, it has to be stored as is to sign with it
+pub fn create_webhook(
+    user: &User,
+    url: &str,
+    conn: &mut PgConnection,
+) -> Result<(Webhook, String), diesel::result::Error> {
+    let secret = format!(
+        "{SECRET_PREFIX}{}",
+        Alphanumeric.sample_string(&mut rand::rng(), 32)
+    );
+    let new_webhook = NewWebhook {
+        user_id: user.id,
+        url: url.to_string(),
+        secret: secret.clone(),
+    };
+
+    let webhook = diesel::insert_into(webhooks::table)
+        .values(&new_webhook)
+        .returning(Webhook::as_returning())
+        .get_result(conn)?;
+
+    Ok((webhook, secret))
+}
+
+pub fn get_webhooks_for_user(
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<Vec<Webhook>, diesel::result::Error> {
+    Webhook::belonging_to(user)
+        .order(webhooks::created_at.desc())
+        .load(conn)
+}
+
+// scoped to the user, like api tokens
+pub fn get_webhook(
+    id: i32,
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<Webhook, diesel::result::Error> {
+    Webhook::belonging_to(user)
+        .filter(webhooks::id.eq(id))
+        .first(conn)
+}
+
+pub fn delete_webhook(
+    id: i32,
+    user: &User,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(webhooks::table.filter(webhooks::id.eq(id).and(webhooks::user_id.eq(user.id))))
+        .execute(conn)
+}
+
+// queues the event for each of the user's webhooks, call it in the same transaction as the
+// change so an event is never sent for a rolled back change or lost for a committed one
+pub fn enqueue_webhook_event<T: Serialize>(
+    user_id: i32,
+    event: WebhookEvent,
+    data: &T,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    let webhook_ids = webhooks::table
+        .filter(webhooks::user_id.eq(user_id))
+        .select(webhooks::id)
+        .load::<i32>(conn)?;
+    if webhook_ids.is_empty() {
+        return Ok(0);
+    }
+
+    let payload = serde_json::to_string(&WebhookPayload {
+        event,
+        created_at: Utc::now(),
+        data,
+    })
+    .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+    let deliveries: Vec<NewWebhookDelivery> = webhook_ids
+        .into_iter()
+        .map(|webhook_id| NewWebhookDelivery {
+            webhook_id,
+            event,
+            payload: &payload,
+        })
+        .collect();
+
+    diesel::insert_into(webhook_deliveries::table)
+        .values(&deliveries)
+        .execute(conn)
+}
+
+// locks due deliveries with SKIP LOCKED so several workers can share the queue, and pushes
+// next_attempt_at out by `lease` so a worker that dies mid send doesn't lose them
+pub fn claim_due_deliveries(
+    limit: i64,
+    lease: Duration,
+    conn: &mut PgConnection,
+) -> Result<Vec<(WebhookDelivery, Webhook)>, diesel::result::Error> {
+    conn.transaction(|conn| {
+        let now = Utc::now();
+        let ids = webhook_deliveries::table
+            .filter(webhook_deliveries::status.eq(DeliveryStatus::Pending))
+            .filter(webhook_deliveries::next_attempt_at.le(now))
+            .order(webhook_deliveries::next_attempt_at)
+            .limit(limit)
+            .select(webhook_deliveries::id)
+            .for_update()
+            .skip_locked()
+            .load::<i32>(conn)?;
+
+        diesel::update(webhook_deliveries::table.filter(webhook_deliveries::id.eq_any(&ids)))
+            .set((
+                webhook_deliveries::attempts.eq(webhook_deliveries::attempts + 1),
+                webhook_deliveries::next_attempt_at.eq(now + lease),
+            ))
+            .execute(conn)?;
+
+        webhook_deliveries::table
+            .inner_join(webhooks::table)
+            .filter(webhook_deliveries::id.eq_any(&ids))
+            .order(webhook_deliveries::id)
+            .select((WebhookDelivery::as_select(), Webhook::as_select()))
+            .load(conn)
+    })
+}
+
+// `outcome` is the response status, or the error if there was no response at all
+pub fn record_delivery_attempt(
+    delivery: &WebhookDelivery,
+    outcome: Result<u16, String>,
+    conn: &mut PgConnection,
+) -> Result<WebhookDelivery, diesel::result::Error> {
+    let now = Utc::now();
+    let (response_status, last_error) = match outcome {
+        Ok(status) if (200..300).contains(&status) => {
+            return diesel::update(delivery)
+                .set((
+                    webhook_deliveries::status.eq(DeliveryStatus::Delivered),
+                    webhook_deliveries::response_status.eq(Some(status as i32)),
+                    webhook_deliveries::last_error.eq(None::<String>),
+                    webhook_deliveries::delivered_at.eq(Some(now)),
+                ))
+                .returning(WebhookDelivery::as_returning())
+                .get_result(conn);
+        }
+        Ok(status) => (Some(status as i32), format!("HTTP {status}")),
+        Err(e) => (None, e),
+    };
+
+    let status = if delivery.attempts >= MAX_ATTEMPTS {
+        DeliveryStatus::Failed
+    } else {
+        DeliveryStatus::Pending
+    };
+    diesel::update(delivery)
+        .set((
+            webhook_deliveries::status.eq(status),
+            webhook_deliveries::response_status.eq(response_status),
+            webhook_deliveries::last_error.eq(Some(last_error)),
+            webhook_deliveries::next_attempt_at.eq(now + retry_delay(delivery.attempts)),
+        ))
+        .returning(WebhookDelivery::as_returning())
+        .get_result(conn)
+}
+
+// sends a failed delivery again, from the first attempt
+pub fn retry_delivery(
+    id: i32,
+    webhook: &Webhook,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::update(
+        WebhookDelivery::belonging_to(webhook)
+            .filter(webhook_deliveries::id.eq(id))
+            .filter(webhook_deliveries::status.eq(DeliveryStatus::Failed)),
+    )
+    .set((
+        webhook_deliveries::status.eq(DeliveryStatus::Pending),
+        webhook_deliveries::attempts.eq(0),
+        webhook_deliveries::next_attempt_at.eq(Utc::now()),
+    ))
+    .execute(conn)
+}
+
+pub fn get_deliveries_for_webhook(
+    webhook: &Webhook,
+    limit: i64,
+    conn: &mut PgConnection,
+) -> Result<Vec<WebhookDelivery>, diesel::result::Error> {
+    WebhookDelivery::belonging_to(webhook)
+        .order(webhook_deliveries::id.desc())
+        .limit(limit)
+        .load(conn)
+}
+
+// delivered and failed deliveries, pending ones are still in the queue
+pub fn delete_finished_deliveries(
+    before: DateTime<Utc>,
+    conn: &mut PgConnection,
+) -> Result<usize, diesel::result::Error> {
+    diesel::delete(
+        webhook_deliveries::table
+            .filter(webhook_deliveries::status.ne(DeliveryStatus::Pending))
+            .filter(webhook_deliveries::created_at.lt(before)),
+    )
+    .execute(conn)
+}
+
+// doubles after every failed attempt
+pub fn retry_delay(attempts: i32) -> Duration {
+    let exponent = (attempts - 1).clamp(0, 16) as u32;
+    Duration::seconds((BASE_RETRY_SECONDS * 2i64.pow(exponent)).min(MAX_RETRY_SECONDS))
+}
+
+// hex hmac-sha256 of "{timestamp}.{payload}", the timestamp lets receivers reject replays
+pub fn webhook_signature(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes keys of any size");
+    mac.update(format!("{timestamp}.{payload}").as_bytes());
+    format!("sha256={:x}", mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::seconds(30));
+        assert_eq!(retry_delay(2), Duration::seconds(60));
+        assert_eq!(retry_delay(5), Duration::seconds(480));
+        assert_eq!(retry_delay(MAX_ATTEMPTS + 10), Duration::hours(6));
+    }
+
+    #[test]
+    fn test_webhook_signature() {
+        let signature = webhook_signature("secret", 1700000000, "{}");
+
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature.len(), "sha256=".len() + 64);
+        assert_eq!(signature, webhook_signature("secret", 1700000000, "{}"));
+        assert_ne!(signature, webhook_signature("secret", 1700000001, "{}"));
+        assert_ne!(signature, webhook_signature("other", 1700000000, "{}"));
+    }
+}
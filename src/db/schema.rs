@@ -1,5 +1,45 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Varchar,
+        token_hash -> Varchar,
+        scope -> Varchar,
+        expires_at -> Nullable<Timestamptz>,
+        last_used_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    audit_log (id) {
+        id -> Int4,
+        actor_id -> Nullable<Int4>,
+        actor_username -> Varchar,
+        target_id -> Nullable<Int4>,
+        target_username -> Nullable<Varchar>,
+        action -> Varchar,
+        ip -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    goal_reminders (id) {
+        id -> Int4,
+        goal_id -> Int4,
+        kind -> Varchar,
+        weekdays -> Int4,
+        days_before -> Int4,
+        time_of_day -> Time,
+        next_run_at -> Nullable<Timestamptz>,
+        last_sent_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     goals (id) {
         id -> Int4,
@@ -7,6 +47,70 @@ diesel::table! {
         description -> Varchar,
         notes -> Nullable<Varchar>,
         user_id -> Int4,
+        target_date -> Nullable<Date>,
+        completed_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    jobs (id) {
+        id -> Int4,
+        kind -> Varchar,
+        payload -> Jsonb,
+        status -> Varchar,
+        attempts -> Int4,
+        max_attempts -> Int4,
+        run_at -> Timestamptz,
+        locked_at -> Nullable<Timestamptz>,
+        last_error -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        finished_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    notifications (id) {
+        id -> Int4,
+        user_id -> Int4,
+        kind -> Varchar,
+        title -> Varchar,
+        body -> Text,
+        link -> Nullable<Varchar>,
+        read_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        id -> Int4,
+        user_id -> Int4,
+        code_hash -> Varchar,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    user_identities (id) {
+        id -> Int4,
+        user_id -> Int4,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    user_sessions (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        ip -> Nullable<Varchar>,
+        user_agent -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        last_seen_at -> Timestamptz,
+        impersonator_id -> Nullable<Int4>,
     }
 }
 
@@ -16,9 +120,64 @@ diesel::table! {
         username -> Varchar,
         hashed_password -> Nullable<Varchar>,
         email -> Nullable<Varchar>,
+        totp_secret -> Nullable<Varchar>,
+        failed_login_count -> Int4,
+        locked_until -> Nullable<Timestamptz>,
+        role -> Varchar,
+        disabled_at -> Nullable<Timestamptz>,
+        password_reset_required -> Bool,
+        time_zone -> Varchar,
+        totp_last_step -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        webhook_id -> Int4,
+        event -> Varchar,
+        payload -> Text,
+        status -> Varchar,
+        attempts -> Int4,
+        next_attempt_at -> Timestamptz,
+        response_status -> Nullable<Int4>,
+        last_error -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        delivered_at -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int4,
+        user_id -> Int4,
+        url -> Varchar,
+        secret -> Varchar,
+        created_at -> Timestamptz,
     }
 }
 
+diesel::joinable!(api_tokens -> users (user_id));
+diesel::joinable!(goal_reminders -> goals (goal_id));
 diesel::joinable!(goals -> users (user_id));
+diesel::joinable!(notifications -> users (user_id));
+diesel::joinable!(recovery_codes -> users (user_id));
+diesel::joinable!(user_identities -> users (user_id));
+diesel::joinable!(user_sessions -> users (user_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+diesel::joinable!(webhooks -> users (user_id));
 
-diesel::allow_tables_to_appear_in_same_query!(goals, users,);
+diesel::allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    audit_log,
+    goal_reminders,
+    goals,
+    jobs,
+    notifications,
+    recovery_codes,
+    user_identities,
+    user_sessions,
+    users,
+    webhook_deliveries,
+    webhooks,
+);
@@ -0,0 +1,29 @@
+use super::{JobContext, JobDefinition, JobError};
+use crate::db::{self, models::webhook::delete_finished_deliveries};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+// keeps the webhook delivery log from growing forever
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PruneWebhookDeliveries {
+    pub older_than_days: i64,
+}
+
+impl JobDefinition for PruneWebhookDeliveries {
+    const KIND: &'static str = "prune_webhook_deliveries";
+
+    async fn run(self, context: JobContext) -> Result<(), JobError> {
+        let before = Utc::now() - Duration::days(self.older_than_days);
+        let deleted = db::interact(&context.pool, move |conn| {
+            Ok::<_, JobError>(delete_finished_deliveries(before, conn)?)
+        })
+        .await?;
+        info!(
+            "pruned {} webhook deliveries from before {}",
+            deleted, before
+        );
+
+        Ok(())
+    }
+}
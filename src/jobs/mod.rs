@@ -0,0 +1,233 @@
+use crate::{
+    db::{
+        self,
+        models::job::{Job, NewJob, claim_next_job, complete_job, fail_job, insert_job},
+    },
+    mailer::{MailError, Mailer},
+};
+use chrono::{DateTime, Utc};
+use diesel::{
+    PgConnection,
+    r2d2::{ConnectionManager, Pool, PoolError},
+};
+use serde::{Serialize, de::DeserializeOwned};
+use std::{collections::HashMap, future::Future, pin::Pin, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+
+pub mod maintenance;
+pub mod reminders;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// a job running longer than this is stopped and counts as a failed attempt
+const JOB_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// longer than JOB_TIMEOUT, only a worker that died leaves a job running past it
+const LEASE_SECONDS: i64 = 10 * 60;
+
+#[derive(Error, Debug)]
+pub enum JobError {
+    #[error(transparent)]
+    DieselResultError(#[from] diesel::result::Error),
+    #[error(transparent)]
+    R2d2Error(#[from] PoolError),
+    #[error(transparent)]
+    JoinError(#[from] tokio::task::JoinError),
+    #[error("bad payload: {0}")]
+    PayloadError(#[from] serde_json::Error),
+    #[error(transparent)]
+    MailError(#[from] MailError),
+    #[error("no job kind named {0}")]
+    UnknownKindError(String),
+    #[error("{0}")]
+    Failed(String),
+}
+
+// what a running job gets to use
+#[derive(Clone)]
+pub struct JobContext {
+    pub pool: Pool<ConnectionManager<PgConnection>>,
+    pub mailer: Arc<dyn Mailer>,
+}
+
+// a typed job, stored as its KIND and serialized self, then deserialized again to run
+pub trait JobDefinition: Serialize + DeserializeOwned + Send + 'static {
+    const KIND: &'static str;
+    const MAX_ATTEMPTS: i32 = 5;
+
+    fn run(self, context: JobContext) -> impl Future<Output = Result<(), JobError>> + Send;
+}
+
+pub fn enqueue<J: JobDefinition>(job: &J, conn: &mut PgConnection) -> Result<Job, JobError> {
+    enqueue_at(job, Utc::now(), conn)
+}
+
+pub fn enqueue_at<J: JobDefinition>(
+    job: &J,
+    run_at: DateTime<Utc>,
+    conn: &mut PgConnection,
+) -> Result<Job, JobError> {
+    let new_job = NewJob {
+        kind: J::KIND,
+        payload: serde_json::to_value(job)?,
+        max_attempts: J::MAX_ATTEMPTS,
+        run_at,
+    };
+
+    Ok(insert_job(&new_job, conn)?)
+}
+
+type JobFuture = Pin<Box<dyn Future<Output = Result<(), JobError>> + Send>>;
+
+struct Registered {
+    max_attempts: i32,
+    check: fn(&serde_json::Value) -> Result<(), serde_json::Error>,
+    run: Box<dyn Fn(serde_json::Value, JobContext) -> JobFuture + Send + Sync>,
+}
+
+// the job kinds a worker knows how to run
+#[derive(Default)]
+pub struct JobRegistry {
+    jobs: HashMap<&'static str, Registered>,
+}
+
+impl JobRegistry {
+    pub fn register<J: JobDefinition>(mut self) -> Self {
+        self.jobs.insert(
+            J::KIND,
+            Registered {
+                max_attempts: J::MAX_ATTEMPTS,
+                check: |payload| serde_json::from_value::<J>(payload.clone()).map(|_| ()),
+                run: Box::new(|payload, context| {
+                    Box::pin(async move {
+                        let job: J = serde_json::from_value(payload)?;
+                        job.run(context).await
+                    })
+                }),
+            },
+        );
+        self
+    }
+
+    pub fn kinds(&self) -> Vec<&'static str> {
+        let mut kinds: Vec<_> = self.jobs.keys().copied().collect();
+        kinds.sort();
+        kinds
+    }
+
+    // for the cli, where the job type is only known by name
+    pub fn enqueue_json(
+        &self,
+        kind: &str,
+        payload: serde_json::Value,
+        conn: &mut PgConnection,
+    ) -> Result<Job, JobError> {
+        let Some((kind, registered)) = self.jobs.get_key_value(kind) else {
+            return Err(JobError::UnknownKindError(kind.to_string()));
+        };
+        (registered.check)(&payload)?;
+
+        let new_job = NewJob {
+            kind,
+            payload,
+            max_attempts: registered.max_attempts,
+            run_at: Utc::now(),
+        };
+        Ok(insert_job(&new_job, conn)?)
+    }
+
+    // claims and runs one job, false when nothing was due
+    pub async fn run_next_job(&self, context: &JobContext) -> Result<bool, JobError> {
+        let lease = chrono::Duration::seconds(LEASE_SECONDS);
+        // the connection goes back to the pool while the job runs
+        let claimed = db::interact(&context.pool, move |conn| {
+            Ok::<_, JobError>(claim_next_job(lease, conn)?)
+        })
+        .await?;
+        let Some(job) = claimed else {
+            return Ok(false);
+        };
+
+        let outcome = match self.jobs.get(job.kind.as_str()) {
+            Some(registered) => {
+                // its own task so a panic only fails the job, not the worker
+                let mut task = tokio::spawn((registered.run)(job.payload.clone(), context.clone()));
+                match tokio::time::timeout(JOB_TIMEOUT, &mut task).await {
+                    Ok(Ok(result)) => result.map_err(|e| e.to_string()),
+                    Ok(Err(e)) => Err(format!("job panicked: {e}")),
+                    Err(_) => {
+                        task.abort();
+                        Err(format!("timed out after {}s", JOB_TIMEOUT.as_secs()))
+                    }
+                }
+            }
+            None => Err(JobError::UnknownKindError(job.kind.clone()).to_string()),
+        };
+
+        match outcome {
+            Ok(()) => {
+                let done = job.clone();
+                db::interact(&context.pool, move |conn| {
+                    Ok::<_, JobError>(complete_job(&done, conn)?)
+                })
+                .await?;
+                info!("job {} ({}) done", job.id, job.kind);
+            }
+            Err(e) => {
+                let error = e.clone();
+                let job = db::interact(&context.pool, move |conn| {
+                    Ok::<_, JobError>(fail_job(&job, &error, conn)?)
+                })
+                .await?;
+                warn!(
+                    "job {} ({}) failed attempt {} of {}, now {}: {}",
+                    job.id, job.kind, job.attempts, job.max_attempts, job.status, e
+                );
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+// every job kind the app runs
+pub fn registry() -> JobRegistry {
+    JobRegistry::default()
+        .register::<maintenance::PruneWebhookDeliveries>()
+        .register::<reminders::SendGoalReminder>()
+}
+
+// each worker runs jobs back to back while there are any, then polls
+pub fn spawn_job_workers(
+    registry: Arc<JobRegistry>,
+    context: JobContext,
+    count: usize,
+    shutdown: CancellationToken,
+) -> Vec<JoinHandle<()>> {
+    info!("starting {} job workers for {:?}", count, registry.kinds());
+
+    (0..count)
+        .map(|_| {
+            let registry = registry.clone();
+            let context = context.clone();
+            let shutdown = shutdown.clone();
+            // a job that is running when shutdown starts gets to finish
+            tokio::spawn(async move {
+                while !shutdown.is_cancelled() {
+                    match registry.run_next_job(&context).await {
+                        Ok(true) => continue,
+                        Ok(false) => {}
+                        Err(e) => error!("job worker: {:#?}", e),
+                    }
+                    tokio::select! {
+                        _ = shutdown.cancelled() => {}
+                        _ = tokio::time::sleep(POLL_INTERVAL) => {}
+                    }
+                }
+            })
+        })
+        .collect()
+}
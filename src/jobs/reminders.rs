@@ -0,0 +1,155 @@
+use super::{JobContext, JobDefinition, JobError, enqueue};
+use crate::{
+    db::{
+        self,
+        models::{
+            Goal, GoalReminder, User,
+            notification::{NewNotification, NotificationKind, notify},
+            reminder::{advance_due_reminders, mark_reminder_sent},
+        },
+        schema::{goal_reminders, goals, users},
+    },
+    mailer::Email,
+};
+use diesel::{
+    PgConnection,
+    prelude::*,
+    r2d2::{ConnectionManager, Pool},
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+const BATCH_SIZE: i64 = 100;
+
+// one reminder that came due, sent by a job so a mail server outage gets retried
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SendGoalReminder {
+    pub reminder_id: i32,
+}
+
+impl JobDefinition for SendGoalReminder {
+    const KIND: &'static str = "send_goal_reminder";
+
+    async fn run(self, context: JobContext) -> Result<(), JobError> {
+        let reminder_id = self.reminder_id;
+        let found = db::interact(&context.pool, move |conn| {
+            Ok::<_, JobError>(
+                goal_reminders::table
+                    .inner_join(goals::table.inner_join(users::table))
+                    .filter(goal_reminders::id.eq(reminder_id))
+                    .select((
+                        GoalReminder::as_select(),
+                        Goal::as_select(),
+                        User::as_select(),
+                    ))
+                    .first::<(GoalReminder, Goal, User)>(conn)
+                    .optional()?,
+            )
+        })
+        .await?;
+        // deleted along with its goal since it was queued
+        let Some((reminder, goal, user)) = found else {
+            return Ok(());
+        };
+
+        if user.is_disabled() {
+            return Ok(());
+        }
+        let Some(address) = &user.email else {
+            info!(
+                "reminder {}: {} has no email address",
+                reminder.id, user.username
+            );
+            return Ok(());
+        };
+
+        let email = reminder_email(address.as_ref(), &reminder, &goal);
+        let mailer = context.mailer.clone();
+        tokio::task::spawn_blocking(move || mailer.send(&email))
+            .await
+            .map_err(|e| JobError::Failed(e.to_string()))??;
+
+        db::interact(&context.pool, move |conn| {
+            Ok::<_, JobError>(mark_reminder_sent(&reminder, conn)?)
+        })
+        .await?;
+
+        Ok(())
+    }
+}
+
+fn reminder_email(to: &str, reminder: &GoalReminder, goal: &Goal) -> Email {
+    let mut body = format!("{}\n\n{}\n", goal.title, goal.description);
+    if let Some(target_date) = goal.target_date {
+        body.push_str(&format!(
+            "\nTarget date: {}\n",
+            target_date.format("%Y-%m-%d")
+        ));
+    }
+    body.push_str(&format!(
+        "\nYou get this reminder {}.\n",
+        reminder.schedule().summary().to_lowercase()
+    ));
+
+    Email {
+        to: to.to_string(),
+        subject: format!("Reminder: {}", goal.title),
+        body,
+    }
+}
+
+// notifies and queues an email job for every due reminder, then moves it on to its next run.
+// the notification is made here rather than in the job so a retried email doesn't repeat it
+pub fn schedule_due_reminders(conn: &mut PgConnection) -> Result<usize, JobError> {
+    conn.transaction(|conn| {
+        let due = advance_due_reminders(BATCH_SIZE, conn)?;
+        for (reminder, goal, user) in &due {
+            if user.is_disabled() {
+                continue;
+            }
+            notify(
+                &NewNotification {
+                    user_id: user.id,
+                    kind: NotificationKind::Reminder,
+                    title: &format!("Reminder: {}", goal.title),
+                    body: &reminder.schedule().summary(),
+                    link: Some("/goals"),
+                },
+                conn,
+            )?;
+            enqueue(
+                &SendGoalReminder {
+                    reminder_id: reminder.id,
+                },
+                conn,
+            )?;
+        }
+        Ok(due.len())
+    })
+}
+
+// safe to run in several processes, each due reminder is only claimed once
+pub fn spawn_reminder_scheduler(
+    pool: Pool<ConnectionManager<PgConnection>>,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while !shutdown.is_cancelled() {
+            match db::interact(&pool, schedule_due_reminders).await {
+                // there may be more waiting
+                Ok(scheduled) if scheduled as i64 == BATCH_SIZE => continue,
+                Ok(0) => {}
+                Ok(scheduled) => info!("queued {} goal reminders", scheduled),
+                Err(e) => error!("reminder scheduler: {:#?}", e),
+            }
+            tokio::select! {
+                _ = shutdown.cancelled() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    })
+}
@@ -1,11 +1,11 @@
-use dotenvy::dotenv;
-
+pub mod config;
 pub mod db;
+pub mod jobs;
+pub mod live;
+pub mod logging;
+pub mod mailer;
+pub mod metrics;
+pub mod shutdown;
+#[cfg(feature = "otel")]
+pub mod telemetry;
 pub mod webapp;
-
-pub fn get_config() {
-    match dotenv() {
-        Ok(path) => println!("Path: {:#?}", path),
-        Err(_) => println!("No .env found, continuing..."),
-    }
-}
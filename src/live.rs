@@ -0,0 +1,116 @@
+use diesel::{PgConnection, prelude::*, sql_types::Text};
+use serde::{Deserialize, Serialize};
+use std::{thread::JoinHandle, time::Duration};
+use tokio::sync::broadcast;
+use tracing::{error, warn};
+
+// postgres NOTIFY channel, so every instance and the job workers share one stream of events
+const CHANNEL: &str = "live_events";
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// a slow browser falls behind this many events before it starts missing them
+const BUFFER_SIZE: usize = 256;
+
+// what changed, browsers refetch whatever shows it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Topic {
+    Goals,
+    Notifications,
+}
+
+impl Topic {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Topic::Goals => "goals",
+            Topic::Notifications => "notifications",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LiveEvent {
+    pub user_id: i32,
+    pub topic: Topic,
+}
+
+// sent when the surrounding transaction commits, and not at all if it rolls back
+pub fn publish(
+    user_id: i32,
+    topic: Topic,
+    conn: &mut PgConnection,
+) -> Result<(), diesel::result::Error> {
+    let payload =
+        serde_json::to_string(&LiveEvent { user_id, topic }).expect("LiveEvent always serializes");
+    diesel::sql_query("SELECT pg_notify($1, $2)")
+        .bind::<Text, _>(CHANNEL)
+        .bind::<Text, _>(payload)
+        .execute(conn)?;
+
+    Ok(())
+}
+
+// fans the events out to the open event streams in this process
+#[derive(Clone)]
+pub struct LiveHub {
+    sender: broadcast::Sender<LiveEvent>,
+}
+
+impl Default for LiveHub {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(BUFFER_SIZE).0,
+        }
+    }
+}
+
+impl LiveHub {
+    pub fn subscribe(&self) -> broadcast::Receiver<LiveEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn send(&self, event: LiveEvent) {
+        // an error only means nobody is listening
+        let _ = self.sender.send(event);
+    }
+}
+
+// keeps its own connection for LISTEN, outside the pool so it never takes a slot from requests,
+// and feeds what arrives into the hub, until the hub is dropped
+pub fn spawn_listener(database_url: String, hub: &LiveHub) -> JoinHandle<()> {
+    let sender = hub.sender.downgrade();
+    std::thread::spawn(move || {
+        loop {
+            match listen(&database_url, &sender) {
+                Ok(()) => return,
+                Err(e) => error!("live event listener: {:#?}", e),
+            }
+            std::thread::sleep(RETRY_INTERVAL);
+        }
+    })
+}
+
+fn listen(
+    database_url: &str,
+    sender: &broadcast::WeakSender<LiveEvent>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut conn = PgConnection::establish(database_url)?;
+    diesel::sql_query(format!("LISTEN {CHANNEL}")).execute(&mut conn)?;
+
+    loop {
+        let Some(sender) = sender.upgrade() else {
+            return Ok(());
+        };
+        for notification in conn.notifications_iter() {
+            let notification = notification?;
+            match serde_json::from_str(&notification.payload) {
+                // an error only means nobody is listening
+                Ok(event) => drop(sender.send(event)),
+                Err(e) => warn!("bad live event {:?}: {}", notification.payload, e),
+            }
+        }
+        drop(sender);
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,72 @@
+use crate::config::{LogFormat, LoggingConfig};
+use tracing_subscriber::{EnvFilter, Layer, layer::SubscriberExt, util::SubscriberInitExt};
+
+// flushes the spans still waiting to be exported when dropped, so it's kept until the server
+// has shut down
+#[must_use]
+pub struct LoggingGuard {
+    #[cfg(feature = "otel")]
+    tracer_provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+#[cfg(feature = "otel")]
+impl Drop for LoggingGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            eprintln!("could not export the last spans: {}", e);
+        }
+    }
+}
+
+// RUST_LOG picks the levels that are logged, in either format. it doesn't limit the spans that
+// are exported
+pub fn init(config: &LoggingConfig) -> LoggingGuard {
+    let fmt = tracing_subscriber::fmt::layer();
+    let fmt = match config.format {
+        LogFormat::Text => fmt.boxed(),
+        // every span the line was logged in, the request's request_id and user_id among them
+        LogFormat::Json => fmt
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .boxed(),
+    };
+    let registry =
+        tracing_subscriber::registry().with(fmt.with_filter(EnvFilter::from_default_env()));
+
+    #[cfg(feature = "otel")]
+    {
+        use crate::telemetry;
+
+        let tracer_provider = config
+            .otlp_endpoint
+            .as_deref()
+            .map(telemetry::tracer_provider)
+            .transpose();
+        let otel = match &tracer_provider {
+            Ok(Some(provider)) => Some(telemetry::layer(provider)),
+            _ => None,
+        };
+        registry.with(otel).init();
+
+        let tracer_provider = tracer_provider.unwrap_or_else(|e| {
+            tracing::error!("not exporting spans: {}", e);
+            None
+        });
+        if tracer_provider.is_some() {
+            telemetry::instrument_queries();
+        }
+        LoggingGuard { tracer_provider }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        registry.init();
+        if config.otlp_endpoint.is_some() {
+            tracing::warn!("logging.otlp_endpoint is set, but this build has no otel feature");
+        }
+        LoggingGuard {}
+    }
+}
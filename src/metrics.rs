@@ -0,0 +1,281 @@
+use diesel::r2d2::{
+    self, HandleEvent,
+    event::{CheckoutEvent, TimeoutEvent},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+// prometheus' default buckets, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+// one registry per process, the pool's event handler is set up before there is an AppState
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoginMethod {
+    Password,
+    Totp,
+    Sso,
+}
+
+impl LoginMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginMethod::Password => "password",
+            LoginMethod::Totp => "totp",
+            LoginMethod::Sso => "sso",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    // not cumulative, that's done when rendering
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bucket| value <= *bucket) {
+            self.buckets[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bucket, count) in BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{separator}le=\"{bucket}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{separator}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    // method, route template, status
+    http_requests: Mutex<BTreeMap<(String, String, u16), Histogram>>,
+    // method, success
+    logins: Mutex<BTreeMap<(LoginMethod, bool), u64>>,
+    // provider, error
+    sso_errors: Mutex<BTreeMap<(String, &'static str), u64>>,
+    pool_wait: Mutex<Histogram>,
+    pool_timeouts: AtomicU64,
+}
+
+impl Metrics {
+    // route is the template, e.g. /goals/{id}, so the number of series stays bounded
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.http_requests
+            .lock()
+            .unwrap()
+            .entry((method.to_string(), route.to_string(), status))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_login(&self, method: LoginMethod, success: bool) {
+        *self
+            .logins
+            .lock()
+            .unwrap()
+            .entry((method, success))
+            .or_default() += 1;
+    }
+
+    pub fn record_sso_error(&self, provider: &str, error: &'static str) {
+        *self
+            .sso_errors
+            .lock()
+            .unwrap()
+            .entry((provider.to_string(), error))
+            .or_default() += 1;
+    }
+
+    // prometheus text format, the pool's current state is passed in at scrape time
+    pub fn render(&self, pool: r2d2::State, pool_max_size: u32) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP http_requests_total HTTP requests by route template and status.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        let http_requests = self.http_requests.lock().unwrap().clone();
+        for ((method, route, status), histogram) in &http_requests {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{{}}} {}",
+                request_labels(method, route, *status),
+                histogram.count
+            );
+        }
+        out.push_str("# HELP http_request_duration_seconds Time to the response headers.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        for ((method, route, status), histogram) in &http_requests {
+            histogram.render(
+                &mut out,
+                "http_request_duration_seconds",
+                &request_labels(method, route, *status),
+            );
+        }
+
+        out.push_str("# HELP db_pool_connections Connections in the pool by state.\n");
+        out.push_str("# TYPE db_pool_connections gauge\n");
+        let _ = writeln!(
+            out,
+            "db_pool_connections{{state=\"idle\"}} {}",
+            pool.idle_connections
+        );
+        let _ = writeln!(
+            out,
+            "db_pool_connections{{state=\"in_use\"}} {}",
+            pool.connections - pool.idle_connections
+        );
+        out.push_str("# HELP db_pool_max_connections The pool's size limit.\n");
+        out.push_str("# TYPE db_pool_max_connections gauge\n");
+        let _ = writeln!(out, "db_pool_max_connections {pool_max_size}");
+        out.push_str("# HELP db_pool_wait_seconds Time spent waiting to check out a connection.\n");
+        out.push_str("# TYPE db_pool_wait_seconds histogram\n");
+        self.pool_wait
+            .lock()
+            .unwrap()
+            .render(&mut out, "db_pool_wait_seconds", "");
+        out.push_str("# HELP db_pool_timeouts_total Checkouts that gave up waiting.\n");
+        out.push_str("# TYPE db_pool_timeouts_total counter\n");
+        let _ = writeln!(
+            out,
+            "db_pool_timeouts_total {}",
+            self.pool_timeouts.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP logins_total Login attempts by method and result.\n");
+        out.push_str("# TYPE logins_total counter\n");
+        for ((method, success), count) in self.logins.lock().unwrap().iter() {
+            let result = if *success { "success" } else { "failure" };
+            let _ = writeln!(
+                out,
+                "logins_total{{method=\"{}\",result=\"{result}\"}} {count}",
+                method.as_str()
+            );
+        }
+
+        out.push_str("# HELP sso_errors_total Failed SSO logins by provider and error.\n");
+        out.push_str("# TYPE sso_errors_total counter\n");
+        for ((provider, error), count) in self.sso_errors.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "sso_errors_total{{provider=\"{}\",error=\"{error}\"}} {count}",
+                escape(provider)
+            );
+        }
+
+        out
+    }
+}
+
+fn request_labels(method: &str, route: &str, status: u16) -> String {
+    format!(
+        "method=\"{}\",route=\"{}\",status=\"{status}\"",
+        escape(method),
+        escape(route)
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// set on the pool with Pool::builder().event_handler
+#[derive(Debug)]
+pub struct PoolMetrics;
+
+impl HandleEvent for PoolMetrics {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        metrics()
+            .pool_wait
+            .lock()
+            .unwrap()
+            .observe(event.duration().as_secs_f64());
+    }
+
+    fn handle_timeout(&self, _event: TimeoutEvent) {
+        metrics().pool_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::{
+        PgConnection,
+        r2d2::{ConnectionManager, Pool},
+    };
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.observe_request("GET", "/goals/{id}", 200, Duration::from_millis(20));
+        metrics.observe_request("GET", "/goals/{id}", 200, Duration::from_secs(20));
+        metrics.record_login(LoginMethod::Password, false);
+        metrics.record_sso_error("google", "token");
+
+        // never connects, the pool's state is all that's needed
+        let pool = Pool::builder()
+            .max_size(4)
+            .min_idle(Some(0))
+            .build_unchecked(ConnectionManager::<PgConnection>::new("postgres://"));
+        let rendered = metrics.render(pool.state(), pool.max_size());
+
+        for line in [
+            "http_requests_total{method=\"GET\",route=\"/goals/{id}\",status=\"200\"} 2",
+            "http_request_duration_seconds_bucket{method=\"GET\",route=\"/goals/{id}\",status=\"200\",le=\"0.01\"} 0",
+            "http_request_duration_seconds_bucket{method=\"GET\",route=\"/goals/{id}\",status=\"200\",le=\"0.025\"} 1",
+            "http_request_duration_seconds_bucket{method=\"GET\",route=\"/goals/{id}\",status=\"200\",le=\"10\"} 1",
+            "http_request_duration_seconds_bucket{method=\"GET\",route=\"/goals/{id}\",status=\"200\",le=\"+Inf\"} 2",
+            "http_request_duration_seconds_count{method=\"GET\",route=\"/goals/{id}\",status=\"200\"} 2",
+            "db_pool_connections{state=\"in_use\"} 0",
+            "db_pool_max_connections 4",
+            "db_pool_wait_seconds_count 0",
+            "logins_total{method=\"password\",result=\"failure\"} 1",
+            "sso_errors_total{provider=\"google\",error=\"token\"} 1",
+        ] {
+            assert!(
+                rendered.lines().any(|l| l == line),
+                "{line} missing from\n{rendered}"
+            );
+        }
+    }
+}
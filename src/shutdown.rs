@@ -0,0 +1,46 @@
+use futures_util::future::join_all;
+use tokio::{task::JoinHandle, time::Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+// resolves on ctrl-c, or SIGTERM from the container runtime
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("ctrl-c received, shutting down"),
+        _ = terminate => info!("SIGTERM received, shutting down"),
+    }
+}
+
+// cancels the token on the first signal, whatever holds it stops taking on new work
+pub fn cancel_on_signal(shutdown: CancellationToken) {
+    tokio::spawn(async move {
+        signal().await;
+        shutdown.cancel();
+    });
+}
+
+// waits for the background tasks to finish what they are doing, until `deadline`
+pub async fn join_tasks(tasks: Vec<JoinHandle<()>>, deadline: Instant) {
+    if tokio::time::timeout_at(deadline, join_all(tasks))
+        .await
+        .is_err()
+    {
+        warn!("background tasks still running at the drain timeout, stopping anyway");
+    }
+}
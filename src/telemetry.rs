@@ -0,0 +1,151 @@
+use diesel::{
+    connection::{DebugQuery, Instrumentation, InstrumentationEvent},
+    result::Error,
+};
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use std::env;
+use tracing::{Level, Span, Subscriber, field::Empty, info_span};
+use tracing_subscriber::{Layer, filter::Targets, registry::LookupSpan};
+
+// spans are batched and sent from the sdk's own thread, see LoggingGuard for the flush
+pub fn tracer_provider(endpoint: &str) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')))
+        .build()?;
+
+    let resource = Resource::builder();
+    // OTEL_SERVICE_NAME wins when it's set
+    let resource = if env::var_os("OTEL_SERVICE_NAME").is_some() {
+        resource
+    } else {
+        resource.with_service_name(env!("CARGO_PKG_NAME"))
+    };
+
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource.build())
+        .build())
+}
+
+// only the app's own spans: requests, handlers and queries. the exporter's http client is
+// traced too, and exporting its spans would never stop
+pub fn layer<S>(provider: &SdkTracerProvider) -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(env!("CARGO_PKG_NAME")))
+        .with_filter(Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::INFO))
+}
+
+// every connection made after this, pooled or not, puts its queries in spans
+pub fn instrument_queries() {
+    if let Err(e) =
+        diesel::connection::set_default_instrumentation(|| Some(Box::new(QuerySpans::default())))
+    {
+        tracing::warn!("could not instrument diesel queries: {}", e);
+    }
+}
+
+// a span from the start of a query to its end, under whatever span ran it
+#[derive(Debug, Default)]
+struct QuerySpans {
+    query: Option<Span>,
+}
+
+impl QuerySpans {
+    fn start(&mut self, query: &dyn DebugQuery) {
+        self.query = Some(info_span!(
+            "db.query",
+            otel.kind = "client",
+            otel.status_code = Empty,
+            otel.status_description = Empty,
+            db.system = "postgresql",
+            db.statement = statement(query),
+        ));
+    }
+
+    fn finish(&mut self, error: Option<&Error>) {
+        if let Some(span) = self.query.take()
+            && let Some(error) = error
+        {
+            span.record("otel.status_code", "error");
+            span.record("otel.status_description", error.to_string());
+        }
+    }
+}
+
+impl Instrumentation for QuerySpans {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        match event {
+            InstrumentationEvent::StartQuery { query, .. } => self.start(query),
+            InstrumentationEvent::FinishQuery { error, .. } => self.finish(error),
+            _ => {}
+        }
+    }
+}
+
+// the sql without diesel's "-- binds: [...]", those are passwords and tokens as often as not
+fn statement(query: &dyn DebugQuery) -> String {
+    let query = query.to_string();
+    match query.rsplit_once(" -- binds: ") {
+        Some((sql, _)) => sql.to_string(),
+        None => query,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::schema::users::dsl::*;
+    use diesel::{debug_query, pg::Pg, prelude::*};
+    use opentelemetry::trace::{SpanKind, Status};
+    use opentelemetry_sdk::trace::InMemorySpanExporter;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_query_spans() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(layer(&provider));
+
+        let query = users.filter(username.eq("alice")).select(id);
+        tracing::subscriber::with_default(subscriber, || {
+            info_span!("request").in_scope(|| {
+                let mut spans = QuerySpans::default();
+                spans.start(&debug_query::<Pg, _>(&query));
+                spans.finish(None);
+                spans.start(&debug_query::<Pg, _>(&query));
+                spans.finish(Some(&Error::NotFound));
+            });
+            // another crate's span isn't exported
+            tracing::info_span!(target: "hyper", "connect").in_scope(|| {});
+        });
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let names: Vec<_> = spans.iter().map(|span| span.name.as_ref()).collect();
+        assert_eq!(names, ["db.query", "db.query", "request"]);
+
+        let request = &spans[2];
+        let (ok, failed) = (&spans[0], &spans[1]);
+        assert_eq!(ok.parent_span_id, request.span_context.span_id());
+        assert_eq!(ok.span_kind, SpanKind::Client);
+        assert_eq!(ok.status, Status::Unset);
+        assert!(matches!(failed.status, Status::Error { .. }));
+
+        let statement = ok
+            .attributes
+            .iter()
+            .find(|attribute| attribute.key.as_str() == "db.statement")
+            .unwrap()
+            .value
+            .to_string();
+        assert!(statement.starts_with("SELECT \"users\".\"id\" FROM \"users\""));
+        assert!(!statement.contains("alice"), "binds are left out");
+    }
+}
@@ -0,0 +1,309 @@
+use super::{super::state::AppState, ApiError, ErrorBody};
+use crate::{
+    db::{
+        models::{
+            Goal, NewGoal,
+            goal::{GoalContext, GoalForm, create_new_goal, set_goal_completed},
+            reminder::reschedule_reminders,
+            user::escape_like,
+            webhook::{WebhookEvent, enqueue_webhook_event},
+        },
+        schema::goals,
+    },
+    live::{Topic, publish},
+    webapp::handlers::middleware::CurrentUser,
+};
+use axum::{
+    Json,
+    extract::{Path, Query, State, rejection::JsonRejection},
+    http::{StatusCode, header::LOCATION},
+    response::{IntoResponse, Response},
+};
+use chrono::NaiveDate;
+use diesel::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use validator::ValidateArgs;
+
+const DEFAULT_LIMIT: i64 = 50;
+const MAX_LIMIT: i64 = 100;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GoalFilters {
+    // case insensitive match on title, description or notes
+    q: Option<String>,
+    has_notes: Option<bool>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GoalList {
+    items: Vec<Goal>,
+    total: i64,
+    limit: i64,
+    offset: i64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/goals",
+    tag = "goals",
+    params(GoalFilters),
+    responses(
+        (status = 200, body = GoalList),
+        (status = 401, body = ErrorBody),
+    )
+)]
+#[tracing::instrument(skip_all)]
+pub async fn list_goals(
+    CurrentUser(user): CurrentUser,
+    State(state): State<AppState>,
+    Query(filters): Query<GoalFilters>,
+) -> Result<Json<GoalList>, ApiError> {
+    let limit = filters.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let offset = filters.offset.unwrap_or(0).max(0);
+
+    let (total, items) = state
+        .db(move |conn| {
+            let filtered = || {
+                let mut query = Goal::belonging_to(&user).into_boxed();
+                if let Some(q) = filters.q.as_deref().filter(|q| !q.is_empty()) {
+                    let pattern = format!("%{}%", escape_like(q));
+                    query = query.filter(
+                        goals::title
+                            .ilike(pattern.clone())
+                            .or(goals::description.ilike(pattern.clone()))
+                            .or(goals::notes.ilike(pattern)),
+                    );
+                }
+                match filters.has_notes {
+                    Some(true) => query = query.filter(goals::notes.is_not_null()),
+                    Some(false) => query = query.filter(goals::notes.is_null()),
+                    None => {}
+                }
+                query
+            };
+
+            let total = filtered().count().get_result(conn)?;
+            let items = filtered()
+                .order(goals::id)
+                .limit(limit)
+                .offset(offset)
+                .load::<Goal>(conn)?;
+            Ok((total, items))
+        })
+        .await?;
+
+    Ok(Json(GoalList {
+        items,
+        total,
+        limit,
+        offset,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/goals/{id}",
+    tag = "goals",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 200, body = Goal),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+#[tracing::instrument(skip_all)]
+pub async fn get_goal(
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<Json<Goal>, ApiError> {
+    let goal = state
+        .db(move |conn| {
+            Ok(Goal::belonging_to(&user)
+                .filter(goals::id.eq(id))
+                .first::<Goal>(conn)?)
+        })
+        .await?;
+
+    Ok(Json(goal))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/goals",
+    tag = "goals",
+    request_body = GoalForm,
+    responses(
+        (status = 201, body = Goal, headers(("location" = String))),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 422, body = ErrorBody),
+    )
+)]
+#[tracing::instrument(skip_all)]
+pub async fn create_goal(
+    CurrentUser(user): CurrentUser,
+    State(state): State<AppState>,
+    payload: Result<Json<GoalForm>, JsonRejection>,
+) -> Result<Response, ApiError> {
+    let Json(goal_form) = payload?;
+    let goal = state
+        .db(move |conn| {
+            // same validation as the htmx form
+            let mut context = GoalContext {
+                conn: &mut *conn,
+                current_title: None,
+            };
+            goal_form.validate_with_args(&mut context)?;
+
+            let new_goal = NewGoal {
+                title: goal_form.title,
+                description: goal_form.description,
+                notes: goal_form.notes,
+                user_id: user.id,
+                target_date: goal_form.target_date,
+            };
+            Ok(conn.transaction(|conn| {
+                let goal = create_new_goal(&new_goal, conn)?;
+                enqueue_webhook_event(user.id, WebhookEvent::GoalCreated, &goal, conn)?;
+                publish(user.id, Topic::Goals, conn)?;
+                Ok::<_, diesel::result::Error>(goal)
+            })?)
+        })
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        [(LOCATION, format!("/api/v1/goals/{}", goal.id))],
+        Json(goal),
+    )
+        .into_response())
+}
+
+// only the fields present are changed, a null clears the notes or target date. `completed`
+// marks the goal done or reopens it, and sends goal.completed when it becomes done
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct GoalPatch {
+    title: Option<String>,
+    description: Option<String>,
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<String>)]
+    notes: Option<Option<String>>,
+    #[serde(default, deserialize_with = "present")]
+    #[schema(value_type = Option<NaiveDate>)]
+    target_date: Option<Option<NaiveDate>>,
+    completed: Option<bool>,
+}
+
+// tells a null apart from a missing field
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[utoipa::path(
+    patch,
+    path = "/api/v1/goals/{id}",
+    tag = "goals",
+    params(("id" = i32, Path)),
+    request_body = GoalPatch,
+    responses(
+        (status = 200, body = Goal),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+        (status = 422, body = ErrorBody),
+    )
+)]
+#[tracing::instrument(skip_all)]
+pub async fn update_goal(
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    payload: Result<Json<GoalPatch>, JsonRejection>,
+) -> Result<Json<Goal>, ApiError> {
+    let Json(patch) = payload?;
+    let goal = state
+        .db(move |conn| {
+            let goal = Goal::belonging_to(&user)
+                .filter(goals::id.eq(id))
+                .first::<Goal>(conn)?;
+
+            let goal_form = GoalForm {
+                title: patch.title.unwrap_or_else(|| goal.title.clone()),
+                description: patch
+                    .description
+                    .unwrap_or_else(|| goal.description.clone()),
+                notes: patch.notes.unwrap_or_else(|| goal.notes.clone()),
+                target_date: patch.target_date.unwrap_or(goal.target_date),
+            };
+            let mut context = GoalContext {
+                conn: &mut *conn,
+                current_title: Some(&goal.title),
+            };
+            goal_form.validate_with_args(&mut context)?;
+
+            Ok(conn.transaction(|conn| {
+                let mut goal = diesel::update(&goal)
+                    .set(&goal_form)
+                    .returning(Goal::as_returning())
+                    .get_result::<Goal>(conn)?;
+                let mut completed = false;
+                if let Some(done) = patch.completed
+                    && let Some(updated) = set_goal_completed(&goal, done, conn)?
+                {
+                    completed = done;
+                    goal = updated;
+                }
+                reschedule_reminders(&goal, &user, conn)?;
+                enqueue_webhook_event(user.id, WebhookEvent::GoalUpdated, &goal, conn)?;
+                if completed {
+                    enqueue_webhook_event(user.id, WebhookEvent::GoalCompleted, &goal, conn)?;
+                }
+                publish(user.id, Topic::Goals, conn)?;
+                Ok::<_, diesel::result::Error>(goal)
+            })?)
+        })
+        .await?;
+
+    Ok(Json(goal))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/goals/{id}",
+    tag = "goals",
+    params(("id" = i32, Path)),
+    responses(
+        (status = 204),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+#[tracing::instrument(skip_all)]
+pub async fn delete_goal(
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, ApiError> {
+    state
+        .db(move |conn| {
+            Ok(conn.transaction(|conn| {
+                let goal = diesel::delete(Goal::belonging_to(&user).filter(goals::id.eq(id)))
+                    .returning(Goal::as_returning())
+                    .get_result::<Goal>(conn)?;
+                enqueue_webhook_event(user.id, WebhookEvent::GoalDeleted, &goal, conn)?;
+                publish(user.id, Topic::Goals, conn)
+            })?)
+        })
+        .await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,242 @@
+use super::{WebappError, handlers, state::AppState};
+use axum::{
+    Json, Router,
+    extract::{State, rejection::JsonRejection},
+    http::StatusCode,
+    middleware,
+    response::{Html, IntoResponse, Response},
+    routing::get,
+};
+use serde::Serialize;
+use std::{borrow::Cow, collections::HashMap};
+use thiserror::Error;
+use utoipa::{
+    Modify, OpenApi, ToSchema,
+    openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+use utoipa_axum::{router::OpenApiRouter, routes};
+use validator::{ValidationError, ValidationErrors};
+
+pub mod goals;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "axum-boilerplate", description = "JSON API, versioned under /api/v1"),
+    components(schemas(crate::db::models::NewUser)),
+    modifiers(&SecuritySchemes),
+    security(("bearer" = []), ("session" = [])),
+    tags((name = "goals", description = "The current user's goals"))
+)]
+pub struct ApiDoc;
+
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some("A personal api token from /profile/tokens"))
+                    .build(),
+            ),
+        );
+        // the browser's login, the cookie itself is encrypted
+        components.add_security_scheme(
+            "session",
+            SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("session"))),
+        );
+    }
+}
+
+// the paths and the spec come from the same #[utoipa::path] attributes
+fn routes() -> OpenApiRouter<AppState> {
+    let mut openapi = ApiDoc::openapi();
+    // utoipa fills in an empty one from Cargo.toml
+    openapi.info.license = None;
+
+    OpenApiRouter::with_openapi(openapi)
+        .routes(routes!(goals::list_goals, goals::create_goal))
+        .routes(routes!(
+            goals::get_goal,
+            goals::update_goal,
+            goals::delete_goal
+        ))
+}
+
+// json in and out, authenticated by session cookie or api token, the docs are public unless
+// turned off in the config
+pub fn api_router(state: AppState, docs: bool) -> Router<AppState> {
+    let (router, openapi) = routes().split_for_parts();
+
+    let router = router.route_layer(middleware::from_fn_with_state(
+        state,
+        handlers::middleware::auth_middleware,
+    ));
+    if !docs {
+        return router;
+    }
+
+    router
+        .route("/api/openapi.json", get(move || async { Json(openapi) }))
+        .route("/api/docs", get(get_api_docs))
+}
+
+async fn get_api_docs(State(state): State<AppState>) -> Result<Html<String>, WebappError> {
+    let rendered = state.tera.render("api-docs.html", &tera::Context::new())?;
+
+    Ok(Html(rendered))
+}
+
+// the api's error responses, always json rather than the html error page
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("Not found")]
+    NotFound,
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("Validation failed")]
+    Validation(ValidationErrors),
+    #[error(transparent)]
+    Webapp(WebappError),
+}
+
+// `fields` maps each invalid field to its validator errors
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody<'a> {
+    pub error: &'a str,
+    #[schema(value_type = String)]
+    pub message: Cow<'a, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Object>)]
+    pub fields: Option<HashMap<Cow<'a, str>, &'a Vec<ValidationError>>>,
+}
+
+// handlers share AppState::db with the html side, so its errors come back as WebappError
+impl From<WebappError> for ApiError {
+    fn from(e: WebappError) -> Self {
+        match e {
+            WebappError::DieselResultError(e) => e.into(),
+            WebappError::ValidationError(errors) => ApiError::Validation(errors),
+            e => ApiError::Webapp(e),
+        }
+    }
+}
+
+impl From<diesel::result::Error> for ApiError {
+    fn from(e: diesel::result::Error) -> Self {
+        match e {
+            diesel::result::Error::NotFound => ApiError::NotFound,
+            e => ApiError::Webapp(WebappError::DieselResultError(e)),
+        }
+    }
+}
+
+impl From<diesel::r2d2::PoolError> for ApiError {
+    fn from(e: diesel::r2d2::PoolError) -> Self {
+        ApiError::Webapp(e.into())
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        ApiError::BadRequest(rejection.body_text())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let message = self.to_string();
+        let (status, error, fields) = match &self {
+            ApiError::NotFound => (StatusCode::NOT_FOUND, "not_found", None),
+            ApiError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request", None),
+            ApiError::Validation(errors)
+            | ApiError::Webapp(WebappError::ValidationError(errors)) => (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "validation_failed",
+                Some(errors.field_errors()),
+            ),
+            ApiError::Webapp(e) if e.status().is_client_error() => {
+                let error = match e.status() {
+                    StatusCode::UNAUTHORIZED => "unauthorized",
+                    StatusCode::FORBIDDEN => "forbidden",
+                    StatusCode::NOT_FOUND => "not_found",
+                    _ => "bad_request",
+                };
+                (e.status(), error, None)
+            }
+            // details stay in the log
+            ApiError::Webapp(e) => {
+                tracing::error!(error = ?e, "request failed");
+                return (
+                    e.status(),
+                    Json(ErrorBody {
+                        error: "internal_error",
+                        message: Cow::from("Internal server error"),
+                        fields: None,
+                    }),
+                )
+                    .into_response();
+            }
+        };
+
+        let body = ErrorBody {
+            error,
+            message: Cow::from(message),
+            fields,
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_status() {
+        let status = |e: ApiError| e.into_response().status();
+
+        assert_eq!(status(ApiError::NotFound), StatusCode::NOT_FOUND);
+        assert_eq!(
+            status(diesel::result::Error::NotFound.into()),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(ApiError::Validation(ValidationErrors::new())),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        assert_eq!(
+            status(WebappError::NotLoggedInError.into()),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(WebappError::MissingOauthClientError.into()),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            status(diesel::result::Error::RollbackTransaction.into()),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    // regenerate the snapshot with UPDATE_OPENAPI=1 cargo test, then review the diff
+    #[test]
+    fn test_openapi_snapshot() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/webapp/api/openapi.json");
+        let spec = routes().split_for_parts().1.to_pretty_json().unwrap() + "\n";
+
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            std::fs::write(path, &spec).unwrap();
+        }
+        let snapshot = std::fs::read_to_string(path).unwrap_or_default();
+
+        assert!(
+            spec == snapshot,
+            "the api routes no longer match src/webapp/api/openapi.json, \
+             rerun with UPDATE_OPENAPI=1 to update it"
+        );
+    }
+}
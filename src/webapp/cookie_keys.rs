@@ -0,0 +1,105 @@
+use super::state::AppState;
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, HeaderValue, header::COOKIE},
+    middleware::Next,
+    response::Response,
+};
+use cookie::{Cookie, CookieJar, Key};
+
+// cookies encrypted with a previous key are re-encrypted with the current one before any
+// PrivateCookieJar sees them, so rotating the key doesn't log everyone out. they stay valid until
+// the old key is dropped from the config, or the app sets them again
+pub async fn cookie_keys_middleware(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if !state.previous_keys.is_empty()
+        && let Some(cookies) =
+            reencrypt_cookies(request.headers(), &state.key, &state.previous_keys)
+    {
+        request.headers_mut().insert(COOKIE, cookies);
+    }
+
+    next.run(request).await
+}
+
+// the request's cookie header with the old cookies replaced, none if nothing changed
+fn reencrypt_cookies(headers: &HeaderMap, key: &Key, previous_keys: &[Key]) -> Option<HeaderValue> {
+    let mut reencrypted = false;
+    let mut cookies = Vec::new();
+
+    let raw_cookies = headers
+        .get_all(COOKIE)
+        .into_iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| Cookie::parse_encoded(cookie.trim().to_owned()).ok());
+    for cookie in raw_cookies {
+        let mut jar = CookieJar::new();
+        if jar.private(key).decrypt(cookie.clone()).is_none()
+            && let Some(plain) = previous_keys
+                .iter()
+                .find_map(|previous| jar.private(previous).decrypt(cookie.clone()))
+        {
+            jar.private_mut(key).add(plain);
+            if let Some(encrypted) = jar.get(cookie.name()) {
+                cookies.push(encrypted.encoded().stripped().to_string());
+                reencrypted = true;
+                continue;
+            }
+        }
+        cookies.push(cookie.encoded().stripped().to_string());
+    }
+
+    if !reencrypted {
+        return None;
+    }
+    HeaderValue::from_str(&cookies.join("; ")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum_extra::extract::PrivateCookieJar;
+
+    fn encrypted(key: &Key, name: &'static str, value: &'static str) -> String {
+        let mut jar = CookieJar::new();
+        jar.private_mut(key).add(Cookie::new(name, value));
+        jar.get(name).unwrap().encoded().stripped().to_string()
+    }
+
+    #[test]
+    fn test_reencrypt_cookies() {
+        let key = Key::generate();
+        let old_key = Key::generate();
+        let other_key = Key::generate();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_str(&format!(
+                "{}; {}; {}; plain=1",
+                encrypted(&key, "user", "alice"),
+                encrypted(&old_key, "session", "token"),
+                encrypted(&other_key, "next_url", "/goals"),
+            ))
+            .unwrap(),
+        );
+
+        // nothing to do without a previous key that fits
+        assert_eq!(reencrypt_cookies(&headers, &key, &[Key::generate()]), None);
+
+        let cookies = reencrypt_cookies(&headers, &key, &[old_key]).unwrap();
+        headers.insert(COOKIE, cookies);
+        let jar = PrivateCookieJar::from_headers(&headers, key);
+        assert_eq!(jar.get("user").unwrap().value(), "alice");
+        assert_eq!(jar.get("session").unwrap().value(), "token");
+        assert_eq!(jar.get("next_url"), None);
+        assert!(
+            headers[COOKIE].to_str().unwrap().ends_with("; plain=1"),
+            "other cookies are left alone"
+        );
+    }
+}
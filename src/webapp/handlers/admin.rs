@@ -0,0 +1,433 @@
+use super::{
+    super::{WebappError, state::AppState},
+    middleware::CurrentUser,
+    session::{CsrfForm, NoFields, insert_csrf_token},
+};
+use crate::db::{
+    models::{
+        EmailAddress, Goal, NewUser, Role, User,
+        audit::get_audit_log,
+        session::get_sessions_for_user,
+        user::{
+            UserChanges, count_users, create_new_user, delete_user, get_users_with_role,
+            hash_password, require_password_reset, search_users, set_password, set_user_disabled,
+            set_user_role, update_user,
+        },
+    },
+    schema::users,
+};
+use axum::{
+    extract::{Path, Query, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use diesel::{
+    prelude::*,
+    result::{DatabaseErrorKind, Error::DatabaseError},
+};
+use serde::{Deserialize, Serialize};
+use validator::{Validate, ValidationErrorsKind};
+
+const PAGE_SIZE: i64 = 20;
+const AUDIT_LOG_SIZE: i64 = 20;
+
+const TAKEN: &str = "That username or email is already taken.";
+const SELF_ACTION: &str = "You can't do that to your own account.";
+
+// require_permission has already checked the current user
+#[tracing::instrument(skip_all)]
+pub async fn get_admin(
+    CurrentUser(admin): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let (user_count, admins, audit_log) = state
+        .db(|conn| {
+            let user_count = count_users(conn)?;
+            let admins: Vec<String> = get_users_with_role(Role::Admin, conn)?
+                .into_iter()
+                .map(|admin| admin.username)
+                .collect();
+            let audit_log = get_audit_log(AUDIT_LOG_SIZE, conn)?;
+            Ok((user_count, admins, audit_log))
+        })
+        .await?;
+
+    let mut context = admin_context(&admin, &jar, "Admin");
+    context.insert("user_count", &user_count);
+    context.insert("audit_log", &audit_log);
+    context.insert("admins", &admins);
+    let rendered = state.tera.render("admin.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+// what the admin templates get to see of a user
+#[derive(Debug, Serialize)]
+struct UserSummary<'a> {
+    id: i32,
+    username: &'a str,
+    email: Option<&'a str>,
+    role: Role,
+    has_password: bool,
+    totp_enabled: bool,
+    locked: bool,
+    disabled: bool,
+    password_reset_required: bool,
+}
+
+impl<'a> From<&'a User> for UserSummary<'a> {
+    fn from(user: &'a User) -> Self {
+        Self {
+            id: user.id,
+            username: &user.username,
+            email: user.email.as_ref().map(|email| email.as_ref()),
+            role: user.role,
+            has_password: user.hashed_password.is_some(),
+            totp_enabled: user.totp_secret.is_some(),
+            locked: user.is_locked(),
+            disabled: user.is_disabled(),
+            password_reset_required: user.password_reset_required,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserSearch {
+    q: Option<String>,
+    page: Option<i64>,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_admin_users(
+    CurrentUser(admin): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+    Query(search): Query<UserSearch>,
+) -> Result<Response, WebappError> {
+    let page = search.page.unwrap_or(1).max(1);
+    let q = search.q.clone();
+    let (users, total) = state
+        .db(move |conn| Ok(search_users(q.as_deref(), page, PAGE_SIZE, conn)?))
+        .await?;
+    let summaries: Vec<UserSummary> = users.iter().map(UserSummary::from).collect();
+
+    let mut context = admin_context(&admin, &jar, "Users");
+    context.insert("users", &summaries);
+    context.insert("total", &total);
+    context.insert("q", &search.q.unwrap_or_default());
+    context.insert("page", &page);
+    context.insert("pages", &((total + PAGE_SIZE - 1) / PAGE_SIZE).max(1));
+    let rendered = state.tera.render("admin-users.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_admin_user(
+    CurrentUser(admin): CurrentUser,
+    jar: PrivateCookieJar,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let user = load_user(id, &state).await?;
+
+    Ok(
+        render_user(&state, &admin, &jar, &user, tera::Context::new())
+            .await?
+            .into_response(),
+    )
+}
+
+pub async fn load_user(id: i32, state: &AppState) -> Result<User, WebappError> {
+    state
+        .db(move |conn| Ok(users::table.find(id).first::<User>(conn)?))
+        .await
+}
+
+pub async fn render_user(
+    state: &AppState,
+    admin: &User,
+    jar: &PrivateCookieJar,
+    user: &User,
+    mut context: tera::Context,
+) -> Result<Html<String>, WebappError> {
+    let (sessions, goals) = {
+        let user = user.clone();
+        state
+            .db(move |conn| {
+                let sessions = get_sessions_for_user(&user, conn)?;
+                let goals = Goal::belonging_to(&user).load::<Goal>(conn)?;
+                Ok((sessions, goals))
+            })
+            .await?
+    };
+
+    context.extend(admin_context(admin, jar, &user.username));
+    context.insert("target", &UserSummary::from(user));
+    context.insert(
+        "can_impersonate",
+        &(user.id != admin.id && user.role < Role::Admin && !user.is_disabled()),
+    );
+    context.insert("sessions", &sessions);
+    context.insert("goals", &goals);
+    let rendered = state.tera.render("admin-user.html", &context)?;
+
+    Ok(Html(rendered))
+}
+
+// shared by new and edit, a blank password leaves it unset or unchanged
+#[derive(Debug, Deserialize)]
+pub struct AdminUserForm {
+    username: String,
+    email: String,
+    role: Role,
+    password: String,
+}
+
+impl AdminUserForm {
+    // runs the same validation as `db user new`, collecting messages for the alert
+    fn validate(&self) -> Result<NewUser, Vec<String>> {
+        let email = match self.email.trim() {
+            "" => None,
+            email => match EmailAddress::new(email) {
+                Ok(email) => Some(email),
+                Err(e) => return Err(vec![e.to_string()]),
+            },
+        };
+        let new_user = NewUser {
+            username: self.username.trim().to_string(),
+            email,
+            password: (!self.password.is_empty()).then(|| self.password.clone()),
+            hashed_password: None,
+        };
+
+        match new_user.validate() {
+            Ok(()) => Ok(new_user),
+            Err(validation_errors) => Err(validation_errors
+                .into_errors()
+                .into_values()
+                .filter_map(|kind| match kind {
+                    ValidationErrorsKind::Field(validation_errors) => Some(validation_errors),
+                    _ => None,
+                })
+                .flatten()
+                .filter_map(|validation_error| validation_error.message)
+                .map(|message| message.to_string())
+                .collect()),
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_admin_new_user(
+    CurrentUser(admin): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let mut context = admin_context(&admin, &jar, "New user");
+    context.insert("role", &Role::User);
+    let rendered = state.tera.render("admin-user-form.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_admin_new_user(
+    CurrentUser(admin): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<AdminUserForm>,
+) -> Result<Response, WebappError> {
+    let mut new_user = match form.validate() {
+        Ok(new_user) => new_user,
+        Err(messages) => return render_user_form(&state, &admin, &jar, None, &form, messages),
+    };
+
+    let role = form.role;
+    // argon2 would hold up the runtime as long as a query does
+    let created = state
+        .db(move |conn| {
+            new_user.hashed_password = new_user.password.take().map(hash_password).transpose()?;
+            let user = match create_new_user(&new_user, conn) {
+                Ok(user) => user,
+                Err(DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if role != user.role {
+                set_user_role(&user, role, conn)?;
+            }
+            Ok(Some(user))
+        })
+        .await?;
+    let Some(user) = created else {
+        return render_user_form(&state, &admin, &jar, None, &form, vec![TAKEN.to_string()]);
+    };
+
+    Ok(Redirect::to(&format!("/admin/users/{}", user.id)).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_admin_edit_user(
+    CurrentUser(admin): CurrentUser,
+    jar: PrivateCookieJar,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let user = load_user(id, &state).await?;
+
+    let mut context = admin_context(&admin, &jar, &user.username);
+    context.insert("edit", &true);
+    context.insert("id", &user.id);
+    context.insert("username", &user.username);
+    context.insert("email", &user.email.as_ref().map(|email| email.as_ref()));
+    context.insert("role", &user.role);
+    let rendered = state.tera.render("admin-user-form.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_admin_edit_user(
+    CurrentUser(admin): CurrentUser,
+    jar: PrivateCookieJar,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    CsrfForm(form): CsrfForm<AdminUserForm>,
+) -> Result<Response, WebappError> {
+    let user = load_user(id, &state).await?;
+
+    let new_user = match form.validate() {
+        Ok(new_user) => new_user,
+        Err(messages) => return render_user_form(&state, &admin, &jar, Some(id), &form, messages),
+    };
+    if user.id == admin.id && form.role < admin.role {
+        let messages = vec![SELF_ACTION.to_string()];
+        return render_user_form(&state, &admin, &jar, Some(id), &form, messages);
+    }
+
+    let changes = UserChanges {
+        username: new_user.username,
+        email: new_user.email,
+        role: form.role,
+    };
+    let updated = state
+        .db(move |conn| {
+            let updated = conn.transaction(|conn| {
+                let updated = update_user(&user, &changes, conn)?;
+                match new_user.password {
+                    Some(password) => {
+                        let hashed_password = hash_password(password)?;
+                        Ok(set_password(&updated, &hashed_password, conn)?)
+                    }
+                    None => Ok::<_, WebappError>(updated),
+                }
+            });
+            match updated {
+                Ok(updated) => Ok(Some(updated)),
+                Err(WebappError::DieselResultError(DatabaseError(
+                    DatabaseErrorKind::UniqueViolation,
+                    _,
+                ))) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+        .await?;
+    let Some(updated) = updated else {
+        return render_user_form(
+            &state,
+            &admin,
+            &jar,
+            Some(id),
+            &form,
+            vec![TAKEN.to_string()],
+        );
+    };
+
+    Ok(Redirect::to(&format!("/admin/users/{}", updated.id)).into_response())
+}
+
+fn render_user_form(
+    state: &AppState,
+    admin: &User,
+    jar: &PrivateCookieJar,
+    id: Option<i32>,
+    form: &AdminUserForm,
+    messages: Vec<String>,
+) -> Result<Response, WebappError> {
+    let mut context = admin_context(admin, jar, &form.username);
+    context.insert("edit", &id.is_some());
+    context.insert("id", &id);
+    context.insert("username", &form.username);
+    context.insert("email", &form.email);
+    context.insert("role", &form.role);
+    context.insert("alerts", &messages);
+    let rendered = state.tera.render("admin-user-form.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum UserAction {
+    Disable,
+    Enable,
+    ResetPassword,
+    Delete,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_admin_user_action(
+    CurrentUser(admin): CurrentUser,
+    jar: PrivateCookieJar,
+    Path((id, action)): Path<(i32, UserAction)>,
+    State(state): State<AppState>,
+    CsrfForm(NoFields {}): CsrfForm<NoFields>,
+) -> Result<Response, WebappError> {
+    let user = load_user(id, &state).await?;
+
+    // admins can't lock themselves out
+    if user.id == admin.id && !matches!(action, UserAction::Enable) {
+        let mut context = tera::Context::new();
+        context.insert("alert", SELF_ACTION);
+        return Ok(render_user(&state, &admin, &jar, &user, context)
+            .await?
+            .into_response());
+    }
+
+    let user_id = user.id;
+    state
+        .db(move |conn| {
+            match action {
+                UserAction::Disable => {
+                    set_user_disabled(&user, true, conn)?;
+                }
+                UserAction::Enable => {
+                    set_user_disabled(&user, false, conn)?;
+                }
+                UserAction::ResetPassword => {
+                    require_password_reset(&user, conn)?;
+                }
+                UserAction::Delete => {
+                    delete_user(&user, conn)?;
+                }
+            }
+            Ok(())
+        })
+        .await?;
+
+    if matches!(action, UserAction::Delete) {
+        return Ok(Redirect::to("/admin/users").into_response());
+    }
+    Ok(Redirect::to(&format!("/admin/users/{}", user_id)).into_response())
+}
+
+fn admin_context(admin: &User, jar: &PrivateCookieJar, title: &str) -> tera::Context {
+    let mut context = tera::Context::new();
+    insert_csrf_token(&mut context, jar);
+    context.insert("user", &admin.username);
+    context.insert("is_admin", &true);
+    context.insert("title", &format!("axum-boilerplate | {title}"));
+    context.insert("active", "admin");
+    context
+}
@@ -4,7 +4,12 @@ use chrono::{DateTime, Datelike, Days, NaiveDate, Utc};
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-use super::super::WebappError;
+use super::super::{WebappError, state::AppState};
+use crate::db::{
+    models::{Goal, user::get_user_by_username},
+    schema::goals,
+};
+use diesel::prelude::*;
 
 use axum::response::Response;
 
@@ -12,6 +17,7 @@ use axum::extract::State;
 
 use axum_extra::extract::PrivateCookieJar;
 
+#[tracing::instrument(skip_all)]
 pub async fn get_calendar(
     jar: PrivateCookieJar,
     State(tera): State<tera::Tera>,
@@ -29,8 +35,10 @@ pub async fn get_calendar(
     Ok(Html(rendered).into_response())
 }
 
+#[tracing::instrument(skip_all)]
 pub async fn hx_get_calendar_content(
     jar: PrivateCookieJar,
+    State(state): State<AppState>,
     State(tera): State<tera::Tera>,
     Query(user_datetime): Query<UserDateTime>,
     // Json(payload): Json<UserDate>,
@@ -41,6 +49,23 @@ pub async fn hx_get_calendar_content(
 
     let (start_date, end_date) = calendar_month_start_end_dates(&today)?;
 
+    // a logged in user sees their goals on their target dates
+    let mut goals = Vec::new();
+    if let Some(username) = jar.get("user") {
+        let username = username.value().to_string();
+        goals = state
+            .db(move |conn| {
+                let Some(user) = get_user_by_username(&username, conn) else {
+                    return Ok(Vec::new());
+                };
+                Ok(Goal::belonging_to(&user)
+                    .filter(goals::target_date.between(start_date, end_date))
+                    .order(goals::title)
+                    .load::<Goal>(conn)?)
+            })
+            .await?;
+    }
+
     let mut last_pushed = start_date;
 
     let mut date_iter = start_date.iter_days();
@@ -51,9 +76,10 @@ pub async fn hx_get_calendar_content(
     while last_pushed != end_date {
         let mut days_vec = Vec::new();
         for _ in 0..7 {
-            days_vec.push(CalendarDay::new(
-                date_iter.next().ok_or(DateError::UnreachableError)?,
-            ));
+            days_vec.push(
+                CalendarDay::new(date_iter.next().ok_or(DateError::UnreachableError)?)
+                    .with_goals(&goals),
+            );
         }
         last_pushed = days_vec.last().ok_or(DateError::UnreachableError)?.date;
         weeks_vec.push(days_vec);
@@ -107,6 +133,7 @@ pub enum DateError {
 struct CalendarDay {
     date: NaiveDate,
     display_str: String,
+    goals: Vec<String>,
 }
 
 impl CalendarDay {
@@ -117,8 +144,19 @@ impl CalendarDay {
                 1 => date.format("%b %-d").to_string(),
                 _ => date.format("%-d").to_string(),
             },
+            goals: Vec::new(),
         }
     }
+
+    // titles of the goals due that day
+    fn with_goals(mut self, goals: &[Goal]) -> Self {
+        self.goals = goals
+            .iter()
+            .filter(|goal| goal.target_date == Some(self.date))
+            .map(|goal| goal.title.clone())
+            .collect();
+        self
+    }
 }
 
 #[cfg(test)]
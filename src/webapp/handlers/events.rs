@@ -0,0 +1,46 @@
+use super::{super::state::AppState, middleware::CurrentUser};
+use crate::live::Topic;
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{Stream, StreamExt, stream};
+use std::convert::Infallible;
+use tokio::sync::broadcast::error::RecvError;
+
+// one stream per open page, named after the topic so htmx can use `sse:goals` as a trigger
+#[tracing::instrument(skip_all)]
+pub async fn get_events(
+    CurrentUser(user): CurrentUser,
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.live.subscribe();
+
+    let events = stream::unfold(
+        (receiver, Vec::new()),
+        move |(mut receiver, mut pending)| async move {
+            loop {
+                if let Some(topic) = pending.pop() {
+                    return Some((Ok(topic_event(topic)), (receiver, pending)));
+                }
+                match receiver.recv().await {
+                    Ok(event) if event.user_id == user.id => pending.push(event.topic),
+                    Ok(_) => {}
+                    // missed some, so refresh everything
+                    Err(RecvError::Lagged(_)) => pending = vec![Topic::Goals, Topic::Notifications],
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    );
+
+    // graceful shutdown waits for every connection, browsers reconnect to another instance
+    let events = events.take_until(state.shutdown.clone().cancelled_owned());
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+fn topic_event(topic: Topic) -> Event {
+    // EventSource drops events without data
+    Event::default().event(topic.as_str()).data(topic.as_str())
+}
@@ -1,70 +1,76 @@
-use super::super::{WebappError, state::AppState};
-use crate::db::{
-    models::{
-        Goal, NewGoal, User,
-        goal::{GoalContext, GoalForm, create_new_goal},
+use super::{
+    super::{WebappError, state::AppState},
+    middleware::CurrentUser,
+};
+use crate::{
+    db::{
+        models::{
+            Goal, GoalReminder, NewGoal, User,
+            goal::{GoalContext, GoalForm, create_new_goal, set_goal_completed},
+            reminder::{
+                EVERY_DAY, ReminderKind, ReminderSchedule, WEEKDAYS, WEEKENDS, create_reminder,
+                delete_reminder, get_reminders_for_goal, reschedule_reminders,
+            },
+            webhook::{WebhookEvent, enqueue_webhook_event},
+        },
+        schema::goals,
     },
-    schema::{goals, users},
+    live::{Topic, publish},
 };
 use axum::{
     extract::{Form, Path, State},
     response::{Html, IntoResponse, Response},
 };
-use axum_extra::extract::PrivateCookieJar;
-use axum_htmx::{HxEvent, HxRequest, HxResponseTrigger};
+use axum_htmx::{HxEvent, HxResponseTrigger};
+use chrono::NaiveTime;
 use diesel::prelude::*;
 use indoc::formatdoc;
-use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
 use validator::{ValidateArgs, ValidationErrorsKind};
 
+#[tracing::instrument(skip_all)]
 pub async fn get_goals(
-    jar: PrivateCookieJar,
+    CurrentUser(user): CurrentUser,
     State(state): State<AppState>,
     State(tera): State<tera::Tera>,
 ) -> Result<Response, WebappError> {
     let mut context = tera::Context::new();
-    let rendered = render_goals(jar, state, tera, &mut context)?;
-
-    Ok(Html(rendered).into_response())
-}
-
-fn render_goals(
-    jar: PrivateCookieJar,
-    state: AppState,
-    tera: tera::Tera,
-    context: &mut tera::Context,
-) -> Result<String, WebappError> {
-    let username = match jar.get("user") {
-        Some(user) => user.value().to_string(),
-        None => return Err(WebappError::NotLoggedInError),
-    };
-    let mut conn = state.pool.clone().get()?;
-    let user = users::table
-        .filter(users::username.eq(&username))
-        .first::<User>(&mut conn)?;
-    let goals = Goal::belonging_to(&user).load::<Goal>(&mut conn)?;
-    context.insert("user", &username);
+    let goals = load_goals(&user, &state).await?;
+    context.insert("user", &user.username);
     context.insert("title", "axum-boilerplate | Goals");
     context.insert("goals", &goals);
     context.insert("active", "goals");
     let rendered = tera.render("goals.html", &context)?;
-    Ok(rendered)
+
+    Ok(Html(rendered).into_response())
+}
+
+async fn load_goals(user: &User, state: &AppState) -> Result<Vec<Goal>, WebappError> {
+    let user = user.clone();
+    state
+        .db(move |conn| Ok(Goal::belonging_to(&user).load::<Goal>(conn)?))
+        .await
 }
 
+async fn load_goal(id: i32, user: &User, state: &AppState) -> Result<Goal, WebappError> {
+    let user_id = user.id;
+    state
+        .db(move |conn| {
+            Ok(goals::table
+                .filter(goals::user_id.eq(user_id).and(goals::id.eq(id)))
+                .first::<Goal>(conn)?)
+        })
+        .await
+}
+
+#[tracing::instrument(skip_all)]
 pub async fn hx_get_goals_table(
-    jar: PrivateCookieJar,
+    CurrentUser(user): CurrentUser,
     State(state): State<AppState>,
     State(tera): State<tera::Tera>,
 ) -> Result<Response, WebappError> {
-    let username = match jar.get("user") {
-        Some(user) => user.value().to_string(),
-        None => return Err(WebappError::NotLoggedInError),
-    };
-    let mut conn = state.pool.clone().get()?;
-    let user = users::table
-        .filter(users::username.eq(&username))
-        .first::<User>(&mut conn)?;
-    let goals = Goal::belonging_to(&user).load::<Goal>(&mut conn)?;
+    let goals = load_goals(&user, &state).await?;
 
     let mut context = tera::Context::new();
     context.insert("goals", &goals);
@@ -73,51 +79,53 @@ pub async fn hx_get_goals_table(
     Ok(Html(rendered).into_response())
 }
 
-pub async fn hx_get_new_goal(
-    jar: PrivateCookieJar,
-    State(state): State<AppState>,
-    State(tera): State<tera::Tera>,
-) -> Result<Response, WebappError> {
+#[tracing::instrument(skip_all)]
+pub async fn hx_get_new_goal(State(tera): State<tera::Tera>) -> Result<Response, WebappError> {
     let context = tera::Context::new();
     let rendered = tera.render("fragments/goal-form.html", &context)?;
 
-    return Ok(Html(rendered).into_response());
+    Ok(Html(rendered).into_response())
 }
 
+#[tracing::instrument(skip_all)]
 pub async fn hx_post_new_goal(
-    jar: PrivateCookieJar,
+    CurrentUser(user): CurrentUser,
     State(state): State<AppState>,
-    State(tera): State<tera::Tera>,
     Form(goal_form): Form<GoalForm>,
 ) -> Result<Response, WebappError> {
-    let username = match jar.get("user") {
-        Some(user) => user.value().to_string(),
-        None => return Err(WebappError::NotLoggedInError),
-    };
-    let mut conn = state.pool.clone().get()?;
-    let user = users::table
-        .filter(users::username.eq(&username))
-        .first::<User>(&mut conn)?;
-
-    let mut context = GoalContext {
-        conn: &mut conn,
-        current_title: None,
-    };
-    let alert = validate_goal_form_extract_alert(&goal_form, &mut context);
+    let alert = state
+        .db(move |conn| {
+            let mut context = GoalContext {
+                conn: &mut *conn,
+                current_title: None,
+            };
+            let alert = validate_goal_form_extract_alert(&goal_form, &mut context);
+
+            if alert.is_some() {
+                return Ok(alert);
+            }
+
+            let new_goal = NewGoal {
+                title: goal_form.title,
+                description: goal_form.description,
+                notes: goal_form.notes,
+                user_id: user.id,
+                target_date: goal_form.target_date,
+            };
+
+            conn.transaction(|conn| {
+                let goal = create_new_goal(&new_goal, conn)?;
+                enqueue_webhook_event(user.id, WebhookEvent::GoalCreated, &goal, conn)?;
+                publish(user.id, Topic::Goals, conn)
+            })?;
+            Ok(None)
+        })
+        .await?;
 
     if let Some(alert) = alert {
         return Ok(Html(alert).into_response());
     }
 
-    let new_goal = NewGoal {
-        title: goal_form.title,
-        description: goal_form.description,
-        notes: goal_form.notes,
-        user_id: user.id,
-    };
-
-    let _goal = create_new_goal(&new_goal, &mut conn)?;
-
     // don't need to push url, closing modal via trigger handles url history
     let trigger = HxResponseTrigger::normal([
         HxEvent::new("trigger_close"),
@@ -133,11 +141,11 @@ fn validate_goal_form_extract_alert<'a>(
 ) -> Option<String> {
     // validate form, see GoalForm impl
     let validation_result = goal_form.validate_with_args(context);
-    let validation_error_messages = validation_result.err().and_then(|errors| {
-        let es = errors
-            .0 // inner HashMap
-            .into_iter()
-            .filter_map(|(_k, v)| match v {
+    let validation_error_messages = validation_result.err().map(|errors| {
+        errors
+            .0
+            .into_values()
+            .filter_map(|v| match v {
                 // only want the Field types
                 ValidationErrorsKind::Field(validation_errors) => Some(validation_errors),
                 _ => None,
@@ -145,13 +153,12 @@ fn validate_goal_form_extract_alert<'a>(
             .flatten() // because fields can have multiple errors
             .filter_map(|validation_error| validation_error.message)
             .map(|message| message.to_string())
-            .collect::<Vec<_>>();
-        Some(es)
+            .collect::<Vec<_>>()
     });
 
     // if errors, pull out messages and return as bullet list fragment
-    let alert = validation_error_messages.and_then(|messages| {
-        let alert = formatdoc!(
+    validation_error_messages.map(|messages| {
+        formatdoc!(
             "
             <div id='alert'
                 hx-swap-oob='true'
@@ -167,64 +174,243 @@ fn validate_goal_form_extract_alert<'a>(
                 .map(|x| format!("<li>{x}</li>"))
                 .collect::<Vec<_>>()
                 .join("")
-        );
-        Some(alert)
-    });
-    alert
+        )
+    })
 }
 
+#[tracing::instrument(skip_all)]
 pub async fn hx_get_goal(
     Path(id): Path<i32>,
     State(state): State<AppState>,
     State(tera): State<tera::Tera>,
-    jar: PrivateCookieJar,
+    CurrentUser(user): CurrentUser,
 ) -> Result<Response, WebappError> {
     debug!("getting goal with id {}", id);
-    let username = match jar.get("user") {
-        Some(user) => user.value().to_string(),
-        None => return Err(WebappError::NotLoggedInError),
-    };
-    let mut conn = state.pool.clone().get()?;
-    let user = users::table
-        .filter(users::username.eq(&username))
-        .first::<User>(&mut conn)?;
-
-    let goal = goals::table
-        .filter(goals::user_id.eq(user.id).and(goals::id.eq(id)))
-        .first::<Goal>(&mut conn)?;
+    let goal = load_goal(id, &user, &state).await?;
     debug!("goal: {:#?}", goal);
 
+    let reminders = {
+        let goal = goal.clone();
+        state
+            .db(move |conn| Ok(get_reminders_for_goal(&goal, conn)?))
+            .await?
+    };
+    let rendered = render_goal_detail(&tera, &goal, &user, tera::Context::new(), &reminders)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+#[derive(Serialize)]
+struct ReminderRow {
+    id: i32,
+    summary: String,
+    next_run_at: Option<String>,
+}
+
+fn render_goal_detail(
+    tera: &tera::Tera,
+    goal: &Goal,
+    user: &User,
+    mut context: tera::Context,
+    reminders: &[GoalReminder],
+) -> Result<String, WebappError> {
+    let tz = user.tz();
+    let reminders = reminders
+        .iter()
+        .map(|reminder| ReminderRow {
+            id: reminder.id,
+            summary: reminder.schedule().summary(),
+            next_run_at: reminder.next_run_at.map(|next_run_at| {
+                next_run_at
+                    .with_timezone(&tz)
+                    .format("%a %Y-%m-%d %H:%M")
+                    .to_string()
+            }),
+        })
+        .collect::<Vec<_>>();
+
+    context.insert("goal", goal);
+    context.insert("reminders", &reminders);
+    context.insert("time_zone", &user.time_zone);
+    Ok(tera.render("fragments/goal-detail.html", &context)?)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReminderPayload {
+    kind: String,
+    // every_day, weekdays, weekends, or a single day where 0 is monday
+    days: Option<String>,
+    days_before: Option<String>,
+    time: String,
+}
+
+impl ReminderPayload {
+    fn schedule(&self, goal: &Goal) -> Result<ReminderSchedule, &'static str> {
+        let time_of_day =
+            NaiveTime::parse_from_str(&self.time, "%H:%M").map_err(|_| "Enter a time.")?;
+
+        match self.kind.parse().map_err(|_| "Pick a kind of reminder.")? {
+            ReminderKind::Weekly => {
+                let weekdays = match self.days.as_deref() {
+                    Some("every_day") => EVERY_DAY,
+                    Some("weekdays") => WEEKDAYS,
+                    Some("weekends") => WEEKENDS,
+                    Some(day) => match day.parse::<i32>() {
+                        Ok(day @ 0..=6) => 1 << day,
+                        _ => return Err("Pick the days to be reminded on."),
+                    },
+                    None => return Err("Pick the days to be reminded on."),
+                };
+                Ok(ReminderSchedule {
+                    kind: ReminderKind::Weekly,
+                    weekdays,
+                    days_before: 0,
+                    time_of_day,
+                })
+            }
+            ReminderKind::BeforeTarget => {
+                if goal.target_date.is_none() {
+                    return Err("Set a target date on the goal first.");
+                }
+                match self.days_before.as_deref().map(|days| days.trim().parse()) {
+                    Some(Ok(days_before @ 0..=365)) => Ok(ReminderSchedule {
+                        kind: ReminderKind::BeforeTarget,
+                        weekdays: 0,
+                        days_before,
+                        time_of_day,
+                    }),
+                    _ => Err("Days before must be between 0 and 365."),
+                }
+            }
+        }
+    }
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn hx_post_goal_reminder(
+    Path(id): Path<i32>,
+    CurrentUser(user): CurrentUser,
+    State(state): State<AppState>,
+    State(tera): State<tera::Tera>,
+    Form(payload): Form<ReminderPayload>,
+) -> Result<Response, WebappError> {
+    let goal = load_goal(id, &user, &state).await?;
+
     let mut context = tera::Context::new();
-    context.insert("goal", &goal);
-    let rendered = tera.render("fragments/goal-detail.html", &context)?;
+    let schedule = payload
+        .schedule(&goal)
+        .inspect_err(|alert| context.insert("reminder_alert", alert))
+        .ok();
+    let reminders = {
+        let (goal, user) = (goal.clone(), user.clone());
+        state
+            .db(move |conn| {
+                if let Some(schedule) = schedule {
+                    create_reminder(&goal, &user, schedule, conn)?;
+                }
+                Ok(get_reminders_for_goal(&goal, conn)?)
+            })
+            .await?
+    };
+
+    let rendered = render_goal_detail(&tera, &goal, &user, context, &reminders)?;
 
     Ok(Html(rendered).into_response())
 }
 
-pub async fn hx_delete_goal(
+#[tracing::instrument(skip_all)]
+pub async fn hx_delete_goal_reminder(
+    Path((id, reminder_id)): Path<(i32, i32)>,
+    CurrentUser(user): CurrentUser,
+    State(state): State<AppState>,
+    State(tera): State<tera::Tera>,
+) -> Result<Response, WebappError> {
+    let goal = load_goal(id, &user, &state).await?;
+
+    let reminders = {
+        let goal = goal.clone();
+        state
+            .db(move |conn| {
+                if delete_reminder(reminder_id, &goal, conn)? == 0 {
+                    return Err(WebappError::DieselResultError(
+                        diesel::result::Error::NotFound,
+                    ));
+                }
+                Ok(get_reminders_for_goal(&goal, conn)?)
+            })
+            .await?
+    };
+
+    let rendered = render_goal_detail(&tera, &goal, &user, tera::Context::new(), &reminders)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CompletedPayload {
+    completed: bool,
+}
+
+// marks the goal done or reopens it, then shows the detail again
+#[tracing::instrument(skip_all)]
+pub async fn hx_post_goal_completed(
     Path(id): Path<i32>,
+    CurrentUser(user): CurrentUser,
     State(state): State<AppState>,
     State(tera): State<tera::Tera>,
-    jar: PrivateCookieJar,
+    Form(payload): Form<CompletedPayload>,
 ) -> Result<Response, WebappError> {
-    debug!("getting goal with id {}", id);
-    let username = match jar.get("user") {
-        Some(user) => user.value().to_string(),
-        None => return Err(WebappError::NotLoggedInError),
+    let goal = load_goal(id, &user, &state).await?;
+
+    let (goal, reminders) = {
+        let user_id = user.id;
+        state
+            .db(move |conn| {
+                let goal = conn.transaction(|conn| {
+                    let Some(updated) = set_goal_completed(&goal, payload.completed, conn)? else {
+                        return Ok::<_, diesel::result::Error>(goal);
+                    };
+                    let event = if payload.completed {
+                        WebhookEvent::GoalCompleted
+                    } else {
+                        WebhookEvent::GoalUpdated
+                    };
+                    enqueue_webhook_event(user_id, event, &updated, conn)?;
+                    publish(user_id, Topic::Goals, conn)?;
+                    Ok(updated)
+                })?;
+                let reminders = get_reminders_for_goal(&goal, conn)?;
+                Ok((goal, reminders))
+            })
+            .await?
     };
-    let mut conn = state.pool.clone().get()?;
-    let user = users::table
-        .filter(users::username.eq(&username))
-        .first::<User>(&mut conn)?;
-
-    let res = diesel::delete(goals::table.filter(goals::id.eq(id).and(goals::user_id.eq(user.id))))
-        .execute(&mut conn)?;
-
-    if res == 0 {
-        return Err(WebappError::DieselResultError(
-            diesel::result::Error::NotFound,
-        ));
-    }
+
+    let rendered = render_goal_detail(&tera, &goal, &user, tera::Context::new(), &reminders)?;
+    let trigger = HxResponseTrigger::normal([HxEvent::new("trigger_table_reload")]);
+
+    Ok((trigger, Html(rendered)).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn hx_delete_goal(
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    CurrentUser(user): CurrentUser,
+) -> Result<Response, WebappError> {
+    debug!("getting goal with id {}", id);
+    state
+        .db(move |conn| {
+            Ok(conn.transaction(|conn| {
+                let goal = diesel::delete(
+                    goals::table.filter(goals::id.eq(id).and(goals::user_id.eq(user.id))),
+                )
+                .returning(Goal::as_returning())
+                .get_result::<Goal>(conn)?;
+                enqueue_webhook_event(user.id, WebhookEvent::GoalDeleted, &goal, conn)?;
+                publish(user.id, Topic::Goals, conn)
+            })?)
+        })
+        .await?;
 
     // don't need to push url, closing modal via trigger handles url history
     let trigger = HxResponseTrigger::normal([
@@ -235,66 +421,62 @@ pub async fn hx_delete_goal(
     Ok((trigger, "").into_response())
 }
 
+#[tracing::instrument(skip_all)]
 pub async fn hx_get_edit_goal(
     Path(id): Path<i32>,
-    jar: PrivateCookieJar,
+    CurrentUser(user): CurrentUser,
     State(state): State<AppState>,
     State(tera): State<tera::Tera>,
-    HxRequest(hx_request): HxRequest,
 ) -> Result<Response, WebappError> {
-    let username = match jar.get("user") {
-        Some(user) => user.value().to_string(),
-        None => return Err(WebappError::NotLoggedInError),
-    };
-    let mut conn = state.pool.clone().get()?;
-    let user = users::table
-        .filter(users::username.eq(&username))
-        .first::<User>(&mut conn)?;
-
-    let goal = goals::table
-        .filter(goals::id.eq(id).and(goals::user_id.eq(user.id)))
-        .first::<Goal>(&mut conn)?;
+    let goal = load_goal(id, &user, &state).await?;
 
     let mut context = tera::Context::new();
     context.insert("goal", &goal);
     context.insert("edit", &true);
     let rendered = tera.render("fragments/goal-form.html", &context)?;
 
-    return Ok(Html(rendered).into_response());
+    Ok(Html(rendered).into_response())
 }
 
+#[tracing::instrument(skip_all)]
 pub async fn hx_patch_goal(
     Path(id): Path<i32>,
-    jar: PrivateCookieJar,
+    CurrentUser(user): CurrentUser,
     State(state): State<AppState>,
-    State(tera): State<tera::Tera>,
     Form(goal_form): Form<GoalForm>,
 ) -> Result<Response, WebappError> {
-    let username = match jar.get("user") {
-        Some(user) => user.value().to_string(),
-        None => return Err(WebappError::NotLoggedInError),
-    };
-    let mut conn = state.pool.clone().get()?;
-    let user = users::table
-        .filter(users::username.eq(&username))
-        .first::<User>(&mut conn)?;
-    let goal = goals::table
-        .filter(goals::user_id.eq(user.id).and(goals::id.eq(id)))
-        .first::<Goal>(&mut conn)?;
+    let goal = load_goal(id, &user, &state).await?;
     debug!("goal: {:#?}", goal);
 
-    let mut context = GoalContext {
-        conn: &mut conn,
-        current_title: Some(&goal.title),
-    };
-    let alert = validate_goal_form_extract_alert(&goal_form, &mut context);
+    let alert = state
+        .db(move |conn| {
+            let mut context = GoalContext {
+                conn: &mut *conn,
+                current_title: Some(&goal.title),
+            };
+            let alert = validate_goal_form_extract_alert(&goal_form, &mut context);
+
+            if alert.is_some() {
+                return Ok(alert);
+            }
+
+            conn.transaction(|conn| {
+                let goal = diesel::update(&goal)
+                    .set(&goal_form)
+                    .returning(Goal::as_returning())
+                    .get_result::<Goal>(conn)?;
+                reschedule_reminders(&goal, &user, conn)?;
+                enqueue_webhook_event(user.id, WebhookEvent::GoalUpdated, &goal, conn)?;
+                publish(user.id, Topic::Goals, conn)
+            })?;
+            Ok(None)
+        })
+        .await?;
 
     if let Some(alert) = alert {
         return Ok(Html(alert).into_response());
     }
 
-    let _ = diesel::update(&goal).set(&goal_form).execute(&mut conn)?;
-    //
     // don't need to push url, closing modal via trigger handles url history
     let trigger = HxResponseTrigger::normal([
         HxEvent::new("trigger_close"),
@@ -0,0 +1,135 @@
+use super::super::state::AppState;
+use crate::{db::has_pending_migrations, metrics::metrics};
+use axum::{
+    Json,
+    extract::State,
+    http::{StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+};
+use chrono::DateTime;
+use serde::Serialize;
+use std::time::Duration;
+use tracing::warn;
+
+// a probe should fail fast rather than wait out the pool's 30s, the check itself runs on a
+// blocking thread like any other query
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// these are routed outside auth and the error page, probes want the plain status
+
+// the process is up and serving, nothing else is checked so a database outage doesn't get
+// every instance restarted
+pub async fn get_healthz() -> &'static str {
+    "ok"
+}
+
+#[derive(Debug, Serialize)]
+struct Readiness {
+    ready: bool,
+    shutting_down: bool,
+    database: Check,
+    migrations: Check,
+    templates: Check,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Check {
+    Ok,
+    Failed,
+    // the database check failed first
+    Skipped,
+}
+
+// for the load balancer, turns unavailable as soon as shutdown starts so no new requests arrive
+// while the open ones drain
+pub async fn get_readyz(State(state): State<AppState>) -> Response {
+    let shutting_down = state.shutdown.is_cancelled();
+
+    let checked = tokio::time::timeout(
+        CHECK_TIMEOUT,
+        state.db(|conn| Ok(has_pending_migrations(conn))),
+    )
+    .await;
+    let (database, migrations) = match checked {
+        Ok(Ok(Ok(false))) => (Check::Ok, Check::Ok),
+        Ok(Ok(Ok(true))) => {
+            warn!("readyz: the database has pending migrations");
+            (Check::Ok, Check::Failed)
+        }
+        Ok(Ok(Err(e))) => {
+            warn!("readyz: could not check migrations: {}", e);
+            (Check::Ok, Check::Failed)
+        }
+        Ok(Err(e)) => {
+            warn!("readyz: no database connection: {}", e);
+            (Check::Failed, Check::Skipped)
+        }
+        Err(_) => {
+            warn!("readyz: no database answer within {:?}", CHECK_TIMEOUT);
+            (Check::Failed, Check::Skipped)
+        }
+    };
+
+    let templates = if state.tera.get_template_names().next().is_some() {
+        Check::Ok
+    } else {
+        Check::Failed
+    };
+
+    let ready = !shutting_down
+        && matches!(database, Check::Ok)
+        && matches!(migrations, Check::Ok)
+        && matches!(templates, Check::Ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(Readiness {
+            ready,
+            shutting_down,
+            database,
+            migrations,
+            templates,
+        }),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Serialize)]
+pub struct Version {
+    version: &'static str,
+    git_hash: &'static str,
+    build_time: String,
+}
+
+// set by build.rs
+pub async fn get_version() -> Json<Version> {
+    let build_time = env!("BUILD_TIMESTAMP")
+        .parse()
+        .ok()
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+        .map(|time| time.to_rfc3339())
+        .unwrap_or_else(|| env!("BUILD_TIMESTAMP").to_string());
+
+    Json(Version {
+        version: env!("CARGO_PKG_VERSION"),
+        git_hash: env!("GIT_HASH"),
+        build_time,
+    })
+}
+
+// prometheus text format
+pub async fn get_metrics(State(state): State<AppState>) -> Response {
+    let rendered = metrics().render(state.pool.state(), state.pool.max_size());
+
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        rendered,
+    )
+        .into_response()
+}
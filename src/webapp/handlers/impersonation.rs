@@ -0,0 +1,232 @@
+use super::{
+    super::{WebappError, state::AppState},
+    admin::{load_user, render_user},
+    middleware::{ClientIp, CurrentUser},
+    session::{CsrfForm, NoFields, clear_session, insert_csrf_token},
+};
+use crate::db::{
+    models::{
+        Role, User, UserSession,
+        audit::{AuditAction, record_audit},
+        session::{create_impersonation_session, delete_session, get_session_by_token},
+    },
+    schema::users,
+};
+use axum::{
+    Extension,
+    extract::{Path, State},
+    http::{HeaderMap, header::USER_AGENT},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::{
+    PrivateCookieJar,
+    cookie::{Cookie, SameSite},
+};
+use diesel::prelude::*;
+use tracing::info;
+
+// the admin's own session token, kept aside until they exit
+const IMPERSONATOR_COOKIE: &str = "impersonator_session";
+
+// admin only, switches the session over to the user while keeping the admin's session
+#[tracing::instrument(skip_all)]
+pub async fn post_admin_impersonate(
+    CurrentUser(admin): CurrentUser,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    jar: PrivateCookieJar,
+    CsrfForm(NoFields {}): CsrfForm<NoFields>,
+) -> Result<(PrivateCookieJar, Response), WebappError> {
+    let user = load_user(id, &state).await?;
+
+    // other admins are off limits, that would be a way around the audit trail
+    let alert = if user.id == admin.id || user.role >= Role::Admin {
+        Some("Admins can't be impersonated.")
+    } else if user.is_disabled() {
+        Some("Disabled users can't be impersonated.")
+    } else {
+        None
+    };
+    if let Some(alert) = alert {
+        let mut context = tera::Context::new();
+        context.insert("alert", alert);
+        let rendered = render_user(&state, &admin, &jar, &user, context).await?;
+        return Ok((jar, rendered.into_response()));
+    }
+
+    let Some(admin_token) = jar.get("session").map(|token| token.value().to_string()) else {
+        return Err(WebappError::NotLoggedInError);
+    };
+
+    let ip = ip.to_string();
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(|user_agent| user_agent.to_string());
+    let token = {
+        let (user, admin) = (user.clone(), admin.clone());
+        state
+            .db(move |conn| {
+                Ok(conn.transaction(|conn| {
+                    let (_session, token) = create_impersonation_session(
+                        &user,
+                        &admin,
+                        Some(ip.clone()),
+                        user_agent,
+                        conn,
+                    )?;
+                    record_audit(
+                        &admin,
+                        AuditAction::ImpersonationStart,
+                        Some(&user),
+                        Some(&ip),
+                        conn,
+                    )?;
+                    Ok::<_, diesel::result::Error>(token)
+                })?)
+            })
+            .await?
+    };
+    info!("{} started impersonating {}", admin.username, user.username);
+
+    let updated_jar = jar
+        .add(
+            Cookie::build((IMPERSONATOR_COOKIE, admin_token))
+                .path("/")
+                .same_site(SameSite::Lax),
+        )
+        .add(
+            Cookie::build(("user", user.username))
+                .path("/")
+                .same_site(SameSite::Lax),
+        )
+        .add(
+            Cookie::build(("session", token))
+                .path("/")
+                .same_site(SameSite::Lax),
+        );
+
+    Ok((updated_jar, Redirect::to("/").into_response()))
+}
+
+// back to the admin's own session, or the login page if that has gone away meanwhile
+#[tracing::instrument(skip_all)]
+pub async fn post_impersonation_exit(
+    CurrentUser(user): CurrentUser,
+    Extension(session): Extension<UserSession>,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    jar: PrivateCookieJar,
+    CsrfForm(NoFields {}): CsrfForm<NoFields>,
+) -> Result<(PrivateCookieJar, Response), WebappError> {
+    let Some(impersonator_id) = session.impersonator_id else {
+        return Ok((jar, Redirect::to("/").into_response()));
+    };
+
+    let admin_token = jar
+        .get(IMPERSONATOR_COOKIE)
+        .map(|token| token.value().to_string());
+    let (admin, admin_session) = {
+        let user = user.clone();
+        let ip = ip.to_string();
+        state
+            .db(move |conn| {
+                let admin = stop_impersonation(&session, &user, Some(&ip), conn)?;
+                let admin_session = match admin_token {
+                    Some(token) => get_session_by_token(&token, conn)?
+                        .filter(|(admin_session, session_user)| {
+                            session_user.id == impersonator_id
+                                && admin_session.impersonator_id.is_none()
+                        })
+                        .map(|_| token),
+                    None => None,
+                };
+                Ok((admin, admin_session))
+            })
+            .await?
+    };
+    info!("{} stopped impersonating {}", admin.username, user.username);
+
+    let jar = jar.remove(Cookie::build(IMPERSONATOR_COOKIE).path("/"));
+    let Some(admin_token) = admin_session else {
+        return Ok((clear_session(jar), Redirect::to("/login").into_response()));
+    };
+
+    let updated_jar = jar
+        .add(
+            Cookie::build(("user", admin.username))
+                .path("/")
+                .same_site(SameSite::Lax),
+        )
+        .add(
+            Cookie::build(("session", admin_token))
+                .path("/")
+                .same_site(SameSite::Lax),
+        );
+
+    Ok((
+        updated_jar,
+        Redirect::to(&format!("/admin/users/{}", user.id)).into_response(),
+    ))
+}
+
+// ends the impersonation session and records it, returns the admin
+pub fn stop_impersonation(
+    session: &UserSession,
+    user: &User,
+    ip: Option<&str>,
+    conn: &mut PgConnection,
+) -> Result<User, WebappError> {
+    let Some(impersonator_id) = session.impersonator_id else {
+        return Err(WebappError::NotLoggedInError);
+    };
+
+    let admin = conn.transaction(|conn| {
+        delete_session(session, conn)?;
+        let admin = users::table.find(impersonator_id).first::<User>(conn)?;
+        record_audit(&admin, AuditAction::ImpersonationStop, Some(user), ip, conn)?;
+        Ok::<_, diesel::result::Error>(admin)
+    })?;
+
+    Ok(admin)
+}
+
+// loaded by layout.html on every page, empty unless impersonating
+#[tracing::instrument(skip_all)]
+pub async fn hx_get_impersonation_banner(
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let Some(token) = jar.get("session") else {
+        return Ok(Html("").into_response());
+    };
+
+    let token = token.value().to_string();
+    let impersonation = state
+        .db(move |conn| {
+            let Some((session, user)) = get_session_by_token(&token, conn)? else {
+                return Ok(None);
+            };
+            let Some(impersonator_id) = session.impersonator_id else {
+                return Ok(None);
+            };
+            let admin = users::table.find(impersonator_id).first::<User>(conn)?;
+            Ok(Some((user, admin)))
+        })
+        .await?;
+    let Some((user, admin)) = impersonation else {
+        return Ok(Html("").into_response());
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("user", &user.username);
+    context.insert("impersonator", &admin.username);
+    insert_csrf_token(&mut context, &jar);
+    let rendered = state
+        .tera
+        .render("fragments/impersonation-banner.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
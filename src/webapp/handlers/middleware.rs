@@ -1,54 +1,574 @@
-use super::super::WebappError;
+use super::super::{WebappError, api::ApiError, state::AppState};
+use super::session::clear_session;
+use crate::db::models::{
+    User,
+    api_token::{TokenScope, get_user_by_api_token, touch_api_token},
+    role::Permission,
+    session::{get_session_by_token, touch_session},
+};
+use crate::metrics::metrics;
 use axum::{
-    extract::Request,
-    http::StatusCode,
+    extract::{
+        ConnectInfo, FromRequestParts, MatchedPath, Request, State, rejection::ExtensionRejection,
+    },
+    http::{
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+        header::{AUTHORIZATION, SET_COOKIE, WWW_AUTHENTICATE},
+        request::Parts,
+    },
     middleware::Next,
-    response::{IntoResponse, Redirect, Response},
+    response::{Html, IntoResponse, Redirect, Response},
 };
 use axum_extra::extract::PrivateCookieJar;
-use axum_htmx::{HxRedirect, HxRequest};
-use tracing::debug;
+use axum_htmx::{HxBoosted, HxRedirect, HxRequest, HxReswap, HxRetarget, SwapOption};
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
+use tera::Tera;
+use tracing::{Span, debug, error, warn};
+use url::form_urlencoded;
+use uuid::Uuid;
+
+const PASSWORD_PATH: &str = "/profile/password";
+
+// json clients get a status code instead of the login redirect or the error page
+const API_PATH: &str = "/api/";
+
+// credentials stay with the real user, even while an admin is impersonating them,
+// so everything under /profile/ is blocked unless it is listed here
+const IMPERSONATION_PROFILE_PREFIX: &str = "/profile/";
+const IMPERSONATION_ALLOWED_PATHS: [&str; 1] = ["/profile/time-zone"];
+
+// account settings and admin need a real login, not a script's token
+const TOKEN_BLOCKED_PATHS: [&str; 3] = ["/profile", "/admin", "/impersonation"];
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+// ids from a proxy in front are kept, anything else could fill the logs with junk
+const REQUEST_ID_MAX_LENGTH: usize = 128;
+
+// set by request_id_middleware on every request, for the logs and the error page
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+fn is_blocked_while_impersonating(path: &str) -> bool {
+    path.starts_with(IMPERSONATION_PROFILE_PREFIX) && !IMPERSONATION_ALLOWED_PATHS.contains(&path)
+}
+
+// the uri is a query parameter, so its own ? and & must not end up in the login url
+fn login_redirect_url(uri: &str) -> String {
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("next_url", uri)
+        .finish();
+    format!("/login?{}", query)
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= REQUEST_ID_MAX_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// the user auth_middleware let through, from a session cookie or an api token
+pub struct CurrentUser(pub User);
+
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = WebappError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<User>()
+            .cloned()
+            .map(CurrentUser)
+            .ok_or(WebappError::NotLoggedInError)
+    }
+}
+
+// where the request came from, for the login throttle, sessions and the audit log
+pub struct ClientIp(pub IpAddr);
+
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = ExtensionRejection;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(peer) = ConnectInfo::<SocketAddr>::from_request_parts(parts, state).await?;
+        Ok(ClientIp(client_ip(
+            peer.ip(),
+            &parts.headers,
+            state.trusted_proxies,
+        )))
+    }
+}
+
+// each trusted proxy appends the address it was connected from to X-Forwarded-For, so the
+// client is that many entries from the end. anything further left came from the client itself
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: usize) -> IpAddr {
+    if trusted_proxies == 0 {
+        return peer;
+    }
+    let forwarded: Vec<&str> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect();
+    forwarded
+        .iter()
+        .rev()
+        .nth(trusted_proxies - 1)
+        .and_then(|ip| ip.parse().ok())
+        .unwrap_or(peer)
+}
 
-// to be used as middleware
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+// to be used as middleware, checks the session or bearer token against the db on every
+// request and hands the user and session (or token) on to handlers as extensions
 pub async fn auth_middleware(
+    State(state): State<AppState>,
     jar: PrivateCookieJar,
     HxRequest(hx_request): HxRequest,
-    request: Request,
+    mut request: Request,
     next: Next,
-) -> Result<Response, WebappError> {
-    if let Some(user) = jar.get("user") {
-        debug!("logged in user: {}", user);
-    } else {
-        let redirect_url = "/login?next_url=".to_string() + request.uri().to_string().as_str();
+) -> Result<(PrivateCookieJar, Response), WebappError> {
+    if let Some(token) = bearer_token(request.headers()) {
+        let token = token.to_string();
+        let found = state
+            .db(move |conn| Ok(get_user_by_api_token(&token, conn)?))
+            .await?
+            .filter(|(_, user)| {
+                !user.is_disabled() && !user.is_locked() && !user.password_reset_required
+            });
+        let Some((api_token, user)) = found else {
+            return Ok((
+                jar,
+                (StatusCode::UNAUTHORIZED, [(WWW_AUTHENTICATE, "Bearer")], "").into_response(),
+            ));
+        };
+
+        let path = request.uri().path();
+        let blocked = TOKEN_BLOCKED_PATHS
+            .iter()
+            .any(|blocked| path.starts_with(blocked));
+        // read tokens can't change anything
+        if blocked || (api_token.scope < TokenScope::Write && !request.method().is_safe()) {
+            warn!(
+                "{} token {} denied {} {}",
+                user.username,
+                api_token.id,
+                request.method(),
+                path
+            );
+            return Ok((jar, StatusCode::FORBIDDEN.into_response()));
+        }
+
+        {
+            let api_token = api_token.clone();
+            state
+                .db(move |conn| Ok(touch_api_token(&api_token, conn)?))
+                .await?;
+        }
+        Span::current().record("user_id", user.id);
+        debug!("token user: {}", user.username);
+        request.extensions_mut().insert(user);
+        request.extensions_mut().insert(api_token);
+        let response = next.run(request).await;
+
+        return Ok((jar, response));
+    }
+
+    let session = match (jar.get("user"), jar.get("session")) {
+        (Some(username), Some(token)) => {
+            let (username, token) = (username.value().to_string(), token.value().to_string());
+            state
+                .db(move |conn| {
+                    Ok(get_session_by_token(&token, conn)?
+                        .filter(|(_, user)| user.username == username && !user.is_disabled())
+                        .map(|(session, user)| {
+                            touch_session(&session, conn).map(|_| (session, user))
+                        })
+                        .transpose()?)
+                })
+                .await?
+        }
+        _ => None,
+    };
+
+    let Some((session, user)) = session else {
+        // stale cookies from a revoked session or a disabled user
+        let jar = clear_session(jar);
+        if request.uri().path().starts_with(API_PATH) {
+            let error = ApiError::from(WebappError::NotLoggedInError);
+            return Ok((jar, error.into_response()));
+        }
+        let redirect_url = login_redirect_url(&request.uri().to_string());
         if hx_request {
-            return Ok((HxRedirect(redirect_url), "").into_response());
+            return Ok((jar, (HxRedirect(redirect_url), "").into_response()));
         }
-        return Ok((StatusCode::FOUND, Redirect::to(redirect_url.as_str())).into_response());
+        return Ok((
+            jar,
+            (StatusCode::FOUND, Redirect::to(redirect_url.as_str())).into_response(),
+        ));
+    };
+    Span::current().record("user_id", user.id);
+    debug!("logged in user: {}", user.username);
+
+    if session.impersonator_id.is_some() {
+        let path = request.uri().path();
+        if is_blocked_while_impersonating(path) {
+            warn!("blocked {} while impersonating {}", path, user.username);
+            return Ok((jar, StatusCode::FORBIDDEN.into_response()));
+        }
+    }
+
+    // an admin asked for a new password, nothing else until it is set
+    if user.password_reset_required
+        && session.impersonator_id.is_none()
+        && request.uri().path() != PASSWORD_PATH
+    {
+        if request.uri().path().starts_with(API_PATH) {
+            return Ok((jar, StatusCode::FORBIDDEN.into_response()));
+        }
+        if hx_request {
+            return Ok((
+                jar,
+                (HxRedirect(PASSWORD_PATH.to_string()), "").into_response(),
+            ));
+        }
+        return Ok((jar, Redirect::to(PASSWORD_PATH).into_response()));
     }
+
+    request.extensions_mut().insert(user);
+    request.extensions_mut().insert(session);
+    let response = next.run(request).await;
+
+    Ok((jar, response))
+}
+
+// to be used inside auth_middleware through a closure that picks the permission
+pub async fn require_permission(
+    permission: Permission,
+    request: Request,
+    next: Next,
+) -> Result<Response, WebappError> {
+    let Some(user) = request.extensions().get::<User>() else {
+        return Err(WebappError::NotLoggedInError);
+    };
+
+    if !user.role.can(permission) {
+        warn!(
+            "{} ({}) denied access to {}",
+            user.username,
+            user.role,
+            request.uri()
+        );
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+
     let response = next.run(request).await;
 
     Ok(response)
 }
 
-// to be used with middleware::from_fn_with_state
+// to be used with middleware::from_fn_with_state. errors are rendered in place, so the url stays
+// the one that failed and the status is the real one
 pub async fn error_middleware(
+    State(tera): State<Tera>,
     HxRequest(hx_request): HxRequest,
+    HxBoosted(boosted): HxBoosted,
     request: Request,
     next: Next,
-) -> Result<Response, WebappError> {
-    let response = next.run(request).await;
+) -> Response {
+    // scripts using api tokens want the real status, not the error page
+    let token_request =
+        bearer_token(request.headers()).is_some() || request.uri().path().starts_with(API_PATH);
+    let request_id = request.extensions().get::<RequestId>().cloned();
+    let uri = request.uri().to_string();
 
-    let status_code = response.status();
+    let response = next.run(request).await;
 
-    if status_code.is_server_error() || status_code.is_client_error() {
-        tracing::error!("{:#?}", response);
+    let status = response.status();
+    if token_request || !(status.is_client_error() || status.is_server_error()) {
+        return response;
+    }
 
+    // a handler that needs a login sends the browser there, and back afterwards
+    if status == StatusCode::UNAUTHORIZED {
+        let redirect_url = login_redirect_url(&uri);
         if hx_request {
-            return Ok((status_code, HxRedirect("/error".to_string()), "").into_response());
+            return (HxRedirect(redirect_url), "").into_response();
+        }
+        return Redirect::to(&redirect_url).into_response();
+    }
+
+    // boosted links swap the whole page, anything else only gets an alert
+    error_page(&tera, status, request_id, hx_request && !boosted)
+}
+
+fn error_page(
+    tera: &Tera,
+    status: StatusCode,
+    request_id: Option<RequestId>,
+    fragment: bool,
+) -> Response {
+    let template = match status {
+        _ if fragment => "fragments/error-alert.html",
+        StatusCode::FORBIDDEN => "error-403.html",
+        StatusCode::NOT_FOUND => "error-404.html",
+        _ if status.is_server_error() => "error-500.html",
+        _ => "error.html",
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("status", &status.as_u16());
+    context.insert("reason", status.canonical_reason().unwrap_or("Error"));
+    context.insert(
+        "content",
+        "Unfortunately, we've encountered an error. Please try again.",
+    );
+    // shown to the user, so a support ticket can be matched to the logs
+    if let Some(RequestId(id)) = request_id {
+        context.insert("request_id", &id);
+    }
+
+    let rendered = match tera.render(template, &context) {
+        Ok(rendered) => rendered,
+        Err(e) => {
+            error!("could not render {}: {:?}", template, e);
+            return (status, status.canonical_reason().unwrap_or_default()).into_response();
+        }
+    };
+
+    if fragment {
+        // into the layout's alert area, whatever the request was going to swap
+        return (
+            status,
+            HxRetarget("#errors".to_string()),
+            HxReswap(SwapOption::InnerHtml),
+            Html(rendered),
+        )
+            .into_response();
+    }
+    (status, Html(rendered)).into_response()
+}
+
+// outermost, so the trace span and every layer inside it see the id. a valid incoming
+// X-Request-Id is kept, so a proxy's id follows the request through
+pub async fn request_id_middleware(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    // only made of header safe characters, checked above or a uuid
+    let value = HeaderValue::from_str(&id).expect("request id is a valid header value");
+
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, value.clone());
+    request.extensions_mut().insert(RequestId(id));
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, value);
+
+    response
+}
+
+// cookies.secure in the config, every cookie the app sets is only sent back over https
+pub async fn secure_cookies_middleware(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+
+    if let axum::http::header::Entry::Occupied(mut entry) = response.headers_mut().entry(SET_COOKIE)
+    {
+        for cookie in entry.iter_mut() {
+            if let Ok(value) = cookie.to_str()
+                && !value.to_ascii_lowercase().contains("; secure")
+                && let Ok(secure) = HeaderValue::from_str(&format!("{value}; Secure"))
+            {
+                *cookie = secure;
+            }
+        }
+    }
+
+    response
+}
+
+// counts and times every request by route template, inside error_middleware so a 401 is
+// counted as such rather than as the redirect to /login
+pub async fn metrics_middleware(request: Request, next: Next) -> Response {
+    // anything else is a 405, and would add a series per made up method
+    let method = match *request.method() {
+        Method::GET | Method::POST | Method::PUT | Method::PATCH | Method::DELETE => {
+            request.method().as_str()
+        }
+        _ => "other",
+    }
+    .to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let start = Instant::now();
+    let response = next.run(request).await;
+    metrics().observe_request(&method, &route, response.status().as_u16(), start.elapsed());
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{Router, body::Body, middleware, routing::get};
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_client_ip() {
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        assert_eq!(client_ip(peer, &headers, 1), peer);
+
+        headers.insert(X_FORWARDED_FOR, "1.2.3.4, 203.0.113.7".parse().unwrap());
+        assert_eq!(client_ip(peer, &headers, 0), peer);
+        // the proxy appended 203.0.113.7, 1.2.3.4 came from the client
+        assert_eq!(
+            client_ip(peer, &headers, 1),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            client_ip(peer, &headers, 2),
+            "1.2.3.4".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(client_ip(peer, &headers, 3), peer);
+
+        headers.append(X_FORWARDED_FOR, "198.51.100.1".parse().unwrap());
+        assert_eq!(
+            client_ip(peer, &headers, 1),
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+        headers.insert(X_FORWARDED_FOR, "not an ip".parse().unwrap());
+        assert_eq!(client_ip(peer, &headers, 1), peer);
+    }
+
+    #[test]
+    fn test_is_valid_request_id() {
+        assert!(is_valid_request_id(&Uuid::new_v4().to_string()));
+        assert!(is_valid_request_id("lb-1.abc_DEF"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("has space"));
+        assert!(!is_valid_request_id("<script>"));
+        assert!(!is_valid_request_id(&"a".repeat(REQUEST_ID_MAX_LENGTH + 1)));
+    }
+
+    #[test]
+    fn test_is_blocked_while_impersonating() {
+        assert!(is_blocked_while_impersonating("/profile/password"));
+        assert!(is_blocked_while_impersonating("/profile/tokens"));
+        assert!(is_blocked_while_impersonating("/profile/tokens/1/delete"));
+        assert!(is_blocked_while_impersonating("/profile/anything-new"));
+        assert!(!is_blocked_while_impersonating("/profile"));
+        assert!(!is_blocked_while_impersonating("/profile/time-zone"));
+        assert!(!is_blocked_while_impersonating("/goals"));
+    }
+
+    async fn error_response(uri: &str, headers: &[(&str, &str)]) -> (Response, String) {
+        let tera = Tera::new(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/webapp/templates/**/*.html"
+        ))
+        .unwrap();
+        let app = Router::new()
+            .route(
+                "/missing",
+                get(|| async { Err::<(), _>(WebappError::from(diesel::result::Error::NotFound)) }),
+            )
+            .route(
+                "/broken",
+                get(|| async { Err::<(), _>(WebappError::TestError) }),
+            )
+            .route(
+                "/private",
+                get(|| async { Err::<(), _>(WebappError::NotLoggedInError) }),
+            )
+            .layer(middleware::from_fn_with_state(tera, error_middleware));
+
+        let mut request = Request::get(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
         }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let (parts, body) = response.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        (
+            Response::from_parts(parts, Body::empty()),
+            String::from_utf8(body.to_vec()).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_error_pages() {
+        let (response, body) = error_response("/missing", &[]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(body.contains("This page doesn't exist"), "{body}");
+
+        let (response, body) = error_response("/nothing/here", &[]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert!(body.contains("This page doesn't exist"), "{body}");
+
+        // never the error's debug output
+        let (response, body) = error_response("/broken", &[]).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.contains("Something went wrong on our side"), "{body}");
+        assert!(!body.contains("TestError"), "{body}");
+
+        let (response, _) = error_response("/private?page=2&sort=title", &[]).await;
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(
+            response.headers()["location"],
+            "/login?next_url=%2Fprivate%3Fpage%3D2%26sort%3Dtitle"
+        );
+
+        // htmx gets an alert for the layout's error area
+        let (response, body) = error_response("/missing", &[("hx-request", "true")]).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()["hx-retarget"], "#errors");
+        assert!(body.starts_with("<div class=\"alert"), "{body}");
+
+        let (response, body) = error_response(
+            "/missing",
+            &[("hx-request", "true"), ("hx-boosted", "true")],
+        )
+        .await;
+        assert!(!response.headers().contains_key("hx-retarget"));
+        assert!(body.contains("This page doesn't exist"), "{body}");
 
-        return Ok(Redirect::to("/error").into_response());
-    } else {
-        Ok(response)
+        // scripts get the plain status
+        let (response, body) =
+            error_response("/broken", &[("authorization", "Bearer token")]).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body, "Internal Server Error");
     }
 }
@@ -1,31 +1,44 @@
-use crate::db::models::user::{get_user_by_username, verify_password};
+use crate::{
+    db::models::{
+        password::rehash_password_if_needed,
+        user::{
+            dummy_verify_password, get_user_by_username, record_failed_login, reset_failed_logins,
+            verify_password,
+        },
+    },
+    metrics::{LoginMethod, metrics},
+};
 use axum::{
-    extract::{Form, Query, State},
+    extract::{Form, State},
     http::HeaderMap,
-    middleware::Next,
     response::{Html, IntoResponse, Redirect, Response},
 };
-use axum_extra::extract::{PrivateCookieJar, cookie::Cookie};
+use axum_extra::extract::PrivateCookieJar;
+use middleware::ClientIp;
 use serde::Deserialize;
 use std::str::FromStr;
 use tracing::debug;
 use url::Url;
 use validator::{Validate, ValidationErrorsKind};
 
+pub mod admin;
 pub mod calendar;
+pub mod events;
 pub mod goal;
+pub mod health;
+pub mod impersonation;
 pub mod middleware;
+pub mod notifications;
+pub mod profile;
+pub mod session;
+pub mod tokens;
+pub mod totp;
+pub mod webhooks;
 
 use super::{WebappError, state::AppState};
 
-#[derive(Debug, Deserialize)]
-pub struct Params {
-    next_url: Option<String>,
-    alert: Option<bool>,
-}
-
+#[tracing::instrument(skip_all)]
 pub async fn get_login(
-    params: Query<Params>,
     jar: PrivateCookieJar,
     State(state): State<AppState>,
 ) -> Result<(PrivateCookieJar, Response), WebappError> {
@@ -46,8 +59,13 @@ pub struct LoginPayload {
     password: String,
 }
 
+pub const TOO_MANY_ATTEMPTS: &str = "Too many login attempts, please try again later.";
+pub const ACCOUNT_DISABLED: &str = "This account has been disabled.";
+
+#[tracing::instrument(skip_all)]
 pub async fn post_login(
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     jar: PrivateCookieJar,
     headers: HeaderMap,
     Form(login_payload): Form<LoginPayload>,
@@ -72,32 +90,100 @@ pub async fn post_login(
         return Ok((jar, render_login_with_context(state, context)?));
     }
 
-    let mut conn = state.pool.clone().get()?;
+    let ip = ip.to_string();
+    let username = &login_payload.username;
 
-    if let Some(user) = get_user_by_username(&login_payload.username, &mut conn) {
-        // empty password means no password login
-        if let Some(hashed_password) = user.hashed_password {
-            if verify_password(&login_payload.password, &hashed_password)
-                .ok()
-                .unwrap_or_else(|| false)
-            {
-                let updated_jar = jar.add(Cookie::build(("user", user.username)).path("/"));
+    if state.login_throttle.retry_after(username, &ip).is_some() {
+        metrics().record_login(LoginMethod::Password, false);
+        let mut context = tera::Context::new();
+        context.insert("alert", TOO_MANY_ATTEMPTS);
+        return Ok((jar, render_login_with_context(state, context)?));
+    }
 
-                // get next_url from REFERER header
-                let next_url = get_next_url_from_headers(headers);
+    // hashing is as slow as a query, so it runs on the blocking thread too
+    let (user, verified) = {
+        let username = username.clone();
+        let password = login_payload.password.clone();
+        state
+            .db(move |conn| {
+                let user = get_user_by_username(&username, conn);
+
+                // unknown, locked and password-less users take as long as a real check
+                let hashed_password = user
+                    .as_ref()
+                    .filter(|user| !user.is_locked())
+                    .and_then(|user| user.hashed_password.as_deref());
+                let verified = match hashed_password {
+                    Some(hashed_password) => {
+                        verify_password(&password, hashed_password).unwrap_or(false)
+                    }
+                    None => {
+                        dummy_verify_password(&password);
+                        false
+                    }
+                };
+
+                // upgrade bcrypt or outdated argon2 hashes while we have the plaintext
+                if verified && let Some(user) = &user {
+                    rehash_password_if_needed(user, &password, conn)?;
+                }
+
+                Ok((user, verified))
+            })
+            .await?
+    };
 
-                return Ok((updated_jar, Redirect::to(next_url.as_str()).into_response()));
+    if let Some(user) = user {
+        if verified {
+            if user.is_disabled() {
+                metrics().record_login(LoginMethod::Password, false);
+                let mut context = tera::Context::new();
+                context.insert("alert", ACCOUNT_DISABLED);
+                return Ok((jar, render_login_with_context(state, context)?));
             }
+
+            // get next_url from REFERER header
+            let next_url = get_next_url_from_headers(&headers);
+
+            // second factor required before the user cookie is set, counted once that's done
+            if user.totp_secret.is_some() {
+                let updated_jar = totp::start_second_step(jar, &user, next_url);
+                return Ok((updated_jar, Redirect::to("/login/totp").into_response()));
+            }
+
+            metrics().record_login(LoginMethod::Password, true);
+            state.login_throttle.record_success(username);
+            {
+                let user = user.clone();
+                state
+                    .db(move |conn| Ok(reset_failed_logins(&user, conn)?))
+                    .await?;
+            }
+
+            let updated_jar =
+                session::start_session(jar, &user, Some(ip), &headers, &state).await?;
+
+            return Ok((updated_jar, Redirect::to(next_url.as_str()).into_response()));
+        }
+
+        // a locked account answers like an unknown one, so lockouts don't reveal usernames
+        if !user.is_locked() {
+            state
+                .db(move |conn| Ok(record_failed_login(&user, conn)?))
+                .await?;
         }
     };
 
+    metrics().record_login(LoginMethod::Password, false);
+    state.login_throttle.record_failure(username, &ip);
+
     let mut context = tera::Context::new();
     context.insert("alert", "Wrong username or password");
     Ok((jar, render_login_with_context(state, context)?))
 }
 
-pub fn get_next_url_from_headers(headers: HeaderMap) -> String {
-    let next_url = headers
+pub fn get_next_url_from_headers(headers: &HeaderMap) -> String {
+    headers
         .get("REFERER")
         .and_then(|x| x.to_str().ok())
         .and_then(|x| Url::from_str(x).ok())
@@ -106,8 +192,7 @@ pub fn get_next_url_from_headers(headers: HeaderMap) -> String {
                 .query_pairs()
                 .find_map(|(k, v)| (k == "next_url").then(|| v.into_owned()))
         })
-        .unwrap_or_else(|| "/".to_string());
-    next_url
+        .unwrap_or_else(|| "/".to_string())
 }
 
 pub fn render_login_with_context(
@@ -119,13 +204,16 @@ pub fn render_login_with_context(
     Ok(Html(rendered).into_response())
 }
 
+#[tracing::instrument(skip_all)]
 pub async fn get_logout(
     jar: PrivateCookieJar,
+    State(state): State<AppState>,
 ) -> Result<(PrivateCookieJar, Response), WebappError> {
-    let updated_jar = jar.remove(Cookie::from("user"));
+    let updated_jar = session::end_session(jar, &state).await?;
     Ok((updated_jar, Redirect::to("/").into_response()))
 }
 
+#[tracing::instrument(skip_all)]
 pub async fn get_index(
     jar: PrivateCookieJar,
     State(tera): State<tera::Tera>,
@@ -144,17 +232,7 @@ pub async fn get_index(
     Ok(Html(rendered))
 }
 
-pub async fn get_error_page(State(tera): State<tera::Tera>) -> Result<Response, WebappError> {
-    let mut context = tera::Context::new();
-    context.insert(
-        "content",
-        "Unfortunately, we've encountered an error. Please try again.",
-    );
-
-    let rendered = tera.render("error.html", &context)?;
-    Ok(Html(rendered).into_response())
-}
-
+#[tracing::instrument(skip_all)]
 pub async fn get_test_error_page() -> Result<Response, WebappError> {
     Err(WebappError::TestError)
 }
@@ -0,0 +1,118 @@
+use super::{
+    super::{WebappError, state::AppState},
+    middleware::CurrentUser,
+    session::{CsrfForm, NoFields, insert_csrf_token},
+};
+use crate::db::models::notification::{
+    count_unread, get_notifications_for_user, mark_all_read, mark_read,
+};
+use axum::{
+    extract::{Path, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+
+const PAGE_SIZE: i64 = 100;
+
+#[tracing::instrument(skip_all)]
+pub async fn get_notifications(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let notifications = {
+        let user = user.clone();
+        state
+            .db(move |conn| Ok(get_notifications_for_user(&user, PAGE_SIZE, conn)?))
+            .await?
+    };
+
+    let mut context = tera::Context::new();
+    insert_csrf_token(&mut context, &jar);
+    context.insert("notifications", &notifications);
+    context.insert(
+        "has_unread",
+        &notifications.iter().any(|n| n.read_at.is_none()),
+    );
+    context.insert("user", &user.username);
+    context.insert("title", "axum-boilerplate | Notifications");
+    context.insert("active", "notifications");
+    let rendered = state.tera.render("notifications.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+// polled by the navbar
+#[tracing::instrument(skip_all)]
+pub async fn hx_get_notification_bell(
+    CurrentUser(user): CurrentUser,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let unread = {
+        let user = user.clone();
+        state.db(move |conn| Ok(count_unread(&user, conn)?)).await?
+    };
+
+    let mut context = tera::Context::new();
+    context.insert("unread", &unread);
+    let rendered = state
+        .tera
+        .render("fragments/notification-bell.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+// opening a notification marks it read and follows its link
+#[tracing::instrument(skip_all)]
+pub async fn get_notification(
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let notification = state
+        .db(move |conn| Ok(mark_read(id, &user, conn)?))
+        .await?
+        .ok_or(WebappError::DieselResultError(
+            diesel::result::Error::NotFound,
+        ))?;
+
+    // links are only ever paths in the app, `//host` would leave it
+    let link = notification
+        .link
+        .filter(|link| link.starts_with('/') && !link.starts_with("//"))
+        .unwrap_or_else(|| "/notifications".to_string());
+
+    Ok(Redirect::to(&link).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_notification_read(
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    CsrfForm(NoFields {}): CsrfForm<NoFields>,
+) -> Result<Response, WebappError> {
+    let notification = state
+        .db(move |conn| Ok(mark_read(id, &user, conn)?))
+        .await?;
+    if notification.is_none() {
+        return Err(WebappError::DieselResultError(
+            diesel::result::Error::NotFound,
+        ));
+    }
+
+    Ok(Redirect::to("/notifications").into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_notifications_read_all(
+    CurrentUser(user): CurrentUser,
+    State(state): State<AppState>,
+    CsrfForm(NoFields {}): CsrfForm<NoFields>,
+) -> Result<Response, WebappError> {
+    state
+        .db(move |conn| Ok(mark_all_read(&user, conn)?))
+        .await?;
+
+    Ok(Redirect::to("/notifications").into_response())
+}
@@ -0,0 +1,261 @@
+use super::{
+    super::{WebappError, state::AppState},
+    middleware::CurrentUser,
+    session::{CsrfForm, insert_csrf_token},
+};
+use crate::db::models::{
+    User, UserSession,
+    identity::{delete_identity, get_identities_for_user},
+    reminder::reschedule_reminders_for_user,
+    role::Permission,
+    session::delete_sessions_for_user,
+    user::{
+        get_user_by_username, hash_password, set_password, set_time_zone, validate_password,
+        verify_password,
+    },
+};
+use axum::{
+    Extension,
+    extract::{Path, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono_tz::{TZ_VARIANTS, Tz};
+use diesel::Connection;
+use serde::Deserialize;
+
+#[tracing::instrument(skip_all)]
+pub async fn get_profile(
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let username = match jar.get("user") {
+        Some(user) => user.value().to_string(),
+        None => return Err(WebappError::NotLoggedInError),
+    };
+    let user = state
+        .db(move |conn| Ok(get_user_by_username(&username, conn)))
+        .await?
+        .ok_or(WebappError::NotLoggedInError)?;
+
+    let rendered = render_profile(&state, &user, &jar, tera::Context::new()).await?;
+
+    Ok(rendered.into_response())
+}
+
+pub async fn render_profile(
+    state: &AppState,
+    user: &User,
+    jar: &PrivateCookieJar,
+    mut context: tera::Context,
+) -> Result<Html<String>, WebappError> {
+    insert_identities(state, user, jar, &mut context).await?;
+    context.insert("user", &user.username);
+    context.insert("email", &user.email.as_ref().map(|email| email.as_ref()));
+    context.insert("has_password", &user.hashed_password.is_some());
+    context.insert("totp_enabled", &user.totp_secret.is_some());
+    context.insert("is_admin", &user.role.can(Permission::ViewAdmin));
+    context.insert("time_zone", &user.time_zone);
+    context.insert(
+        "time_zones",
+        &TZ_VARIANTS.iter().map(|tz| tz.name()).collect::<Vec<_>>(),
+    );
+    context.insert("title", "axum-boilerplate | Profile");
+    context.insert("active", "profile");
+    let rendered = state.tera.render("profile.html", &context)?;
+
+    Ok(Html(rendered))
+}
+
+async fn insert_identities(
+    state: &AppState,
+    user: &User,
+    jar: &PrivateCookieJar,
+    context: &mut tera::Context,
+) -> Result<(), WebappError> {
+    let identities = {
+        let user = user.clone();
+        state
+            .db(move |conn| Ok(get_identities_for_user(&user, conn)?))
+            .await?
+    };
+    // configured providers the user can still link
+    let linkable: Vec<_> = state
+        .sso
+        .names()
+        .into_iter()
+        .filter(|name| !identities.iter().any(|identity| identity.provider == *name))
+        .collect();
+    context.insert("identities", &identities);
+    context.insert("linkable", &linkable);
+    insert_csrf_token(context, jar);
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn hx_delete_identity(
+    Path(id): Path<i32>,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let username = match jar.get("user") {
+        Some(user) => user.value().to_string(),
+        None => return Err(WebappError::NotLoggedInError),
+    };
+    let user = state
+        .db(move |conn| Ok(get_user_by_username(&username, conn)))
+        .await?
+        .ok_or(WebappError::NotLoggedInError)?;
+
+    let mut context = tera::Context::new();
+
+    let only_login = {
+        let user = user.clone();
+        state
+            .db(move |conn| {
+                // without a password, the last linked identity is the only way to log in
+                if user.hashed_password.is_none()
+                    && get_identities_for_user(&user, conn)?.len() <= 1
+                {
+                    return Ok(true);
+                }
+                if delete_identity(id, &user, conn)? == 0 {
+                    return Err(WebappError::DieselResultError(
+                        diesel::result::Error::NotFound,
+                    ));
+                }
+                Ok(false)
+            })
+            .await?
+    };
+    if only_login {
+        context.insert(
+            "alert",
+            "You can't unlink your only login method. Set a password first.",
+        );
+    }
+
+    insert_identities(&state, &user, &jar, &mut context).await?;
+    let rendered = state
+        .tera
+        .render("fragments/identities-table.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TimeZonePayload {
+    time_zone: String,
+}
+
+// reminders are kept at the same local time, so they all move with the zone
+#[tracing::instrument(skip_all)]
+pub async fn post_profile_time_zone(
+    CurrentUser(user): CurrentUser,
+    State(state): State<AppState>,
+    jar: PrivateCookieJar,
+    CsrfForm(payload): CsrfForm<TimeZonePayload>,
+) -> Result<Response, WebappError> {
+    let Ok(tz) = payload.time_zone.parse::<Tz>() else {
+        let mut context = tera::Context::new();
+        context.insert("time_zone_alert", "Pick a time zone from the list.");
+        return Ok(render_profile(&state, &user, &jar, context)
+            .await?
+            .into_response());
+    };
+
+    state
+        .db(move |conn| {
+            Ok(conn.transaction(|conn| {
+                let user = set_time_zone(&user, tz, conn)?;
+                reschedule_reminders_for_user(&user, conn)
+            })?)
+        })
+        .await?;
+
+    Ok(Redirect::to("/profile").into_response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasswordPayload {
+    current_password: Option<String>,
+    new_password: String,
+    confirm_password: String,
+}
+
+// the session is put into the request by auth_middleware, tokens never get here
+#[tracing::instrument(skip_all)]
+pub async fn get_profile_password(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    Ok(render_password(&state, &user, &jar, tera::Context::new())?.into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_profile_password(
+    CurrentUser(user): CurrentUser,
+    Extension(session): Extension<UserSession>,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+    CsrfForm(payload): CsrfForm<PasswordPayload>,
+) -> Result<Response, WebappError> {
+    // a forced reset comes straight after logging in, so the current password isn't asked again
+    let needs_current = user.hashed_password.is_some() && !user.password_reset_required;
+    let current_ok = match (&user.hashed_password, &payload.current_password) {
+        _ if !needs_current => true,
+        (Some(hashed_password), Some(current_password)) => {
+            verify_password(current_password, hashed_password)?
+        }
+        _ => false,
+    };
+
+    let alert = if !current_ok {
+        Some("Current password is incorrect.".to_string())
+    } else if payload.new_password != payload.confirm_password {
+        Some("Passwords don't match.".to_string())
+    } else if let Err(e) = validate_password(&payload.new_password) {
+        Some(e.to_string())
+    } else {
+        None
+    };
+
+    if let Some(alert) = alert {
+        let mut context = tera::Context::new();
+        context.insert("alert", &alert);
+        return Ok(render_password(&state, &user, &jar, context)?.into_response());
+    }
+
+    state
+        .db(move |conn| {
+            let hashed_password = hash_password(payload.new_password)?;
+            set_password(&user, &hashed_password, conn)?;
+            // anyone else holding an old session has to log in with the new password
+            delete_sessions_for_user(&user, Some(session.id), conn)?;
+            Ok(())
+        })
+        .await?;
+
+    Ok(Redirect::to("/profile").into_response())
+}
+
+fn render_password(
+    state: &AppState,
+    user: &User,
+    jar: &PrivateCookieJar,
+    mut context: tera::Context,
+) -> Result<Html<String>, WebappError> {
+    insert_csrf_token(&mut context, jar);
+    context.insert(
+        "needs_current",
+        &(user.hashed_password.is_some() && !user.password_reset_required),
+    );
+    context.insert("reset_required", &user.password_reset_required);
+    context.insert("user", &user.username);
+    context.insert("title", "axum-boilerplate | Password");
+    context.insert("active", "profile");
+    let rendered = state.tera.render("password.html", &context)?;
+
+    Ok(Html(rendered))
+}
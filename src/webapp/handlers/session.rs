@@ -0,0 +1,151 @@
+use super::{
+    super::{WebappError, state::AppState},
+    impersonation::stop_impersonation,
+};
+use crate::db::models::{
+    User,
+    session::{create_session, delete_session_by_token, get_session_by_token},
+};
+use axum::{
+    Form,
+    body::{Body, Bytes},
+    extract::{FromRequest, FromRequestParts, Request},
+    http::{HeaderMap, header::USER_AGENT},
+    response::{IntoResponse, Response},
+};
+use axum_extra::extract::{
+    PrivateCookieJar,
+    cookie::{Cookie, SameSite},
+};
+use serde::{Deserialize, de::DeserializeOwned};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tera::Context;
+use url::form_urlencoded;
+
+// the user is fully authenticated, record the session and set the cookies
+pub async fn start_session(
+    jar: PrivateCookieJar,
+    user: &User,
+    ip: Option<String>,
+    headers: &HeaderMap,
+    state: &AppState,
+) -> Result<PrivateCookieJar, WebappError> {
+    let user_agent = headers
+        .get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(|user_agent| user_agent.to_string());
+    let token = {
+        let user = user.clone();
+        state
+            .db(move |conn| {
+                let (_session, token) = create_session(&user, ip, user_agent, conn)?;
+                Ok(token)
+            })
+            .await?
+    };
+
+    Ok(jar
+        .add(
+            Cookie::build(("user", user.username.clone()))
+                .path("/")
+                .same_site(SameSite::Lax),
+        )
+        .add(
+            Cookie::build(("session", token))
+                .path("/")
+                .same_site(SameSite::Lax),
+        ))
+}
+
+// logging out while impersonating ends the admin's own session too
+pub async fn end_session(
+    jar: PrivateCookieJar,
+    state: &AppState,
+) -> Result<PrivateCookieJar, WebappError> {
+    let token = jar.get("session").map(|token| token.value().to_string());
+    let impersonator_token = jar
+        .get("impersonator_session")
+        .map(|token| token.value().to_string());
+    state
+        .db(move |conn| {
+            if let Some(token) = token {
+                match get_session_by_token(&token, conn)? {
+                    Some((session, user)) if session.impersonator_id.is_some() => {
+                        stop_impersonation(&session, &user, None, conn)?;
+                    }
+                    _ => {
+                        delete_session_by_token(&token, conn)?;
+                    }
+                }
+            }
+            if let Some(token) = impersonator_token {
+                delete_session_by_token(&token, conn)?;
+            }
+            Ok(())
+        })
+        .await?;
+
+    Ok(clear_session(jar))
+}
+
+pub fn clear_session(jar: PrivateCookieJar) -> PrivateCookieJar {
+    jar.remove(Cookie::build("user").path("/"))
+        .remove(Cookie::build("session").path("/"))
+        .remove(Cookie::build("impersonator_session").path("/"))
+}
+
+// hidden in forms that start something sensitive. derived from the session token, which only
+// ever lives in the encrypted cookie, so another site can't know it
+pub fn csrf_token(jar: &PrivateCookieJar) -> Option<String> {
+    let token = jar.get("session")?;
+    Some(format!(
+        "{:x}",
+        Sha256::new()
+            .chain_update("csrf:")
+            .chain_update(token.value())
+            .finalize()
+    ))
+}
+
+pub fn verify_csrf_token(jar: &PrivateCookieJar, submitted: &str) -> bool {
+    csrf_token(jar).is_some_and(|token| token.as_bytes().ct_eq(submitted.as_bytes()).into())
+}
+
+// every template with a state-changing form renders the token into a hidden field
+pub fn insert_csrf_token(context: &mut Context, jar: &PrivateCookieJar) {
+    context.insert("csrf_token", &csrf_token(jar).unwrap_or_default());
+}
+
+// for forms that are nothing but a button and the hidden csrf_token
+#[derive(Deserialize)]
+pub struct NoFields {}
+
+// like Form, but the request is refused unless it carries the session's csrf_token
+pub struct CsrfForm<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest<AppState> for CsrfForm<T> {
+    type Rejection = Response;
+
+    async fn from_request(request: Request, state: &AppState) -> Result<Self, Self::Rejection> {
+        let (mut parts, body) = request.into_parts();
+        let jar = PrivateCookieJar::from_request_parts(&mut parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let bytes = Bytes::from_request(Request::from_parts(parts.clone(), body), state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+        let submitted = form_urlencoded::parse(&bytes)
+            .find(|(name, _)| name == "csrf_token")
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_default();
+        if !verify_csrf_token(&jar, &submitted) {
+            return Err(WebappError::CsrfError.into_response());
+        }
+        let Form(form) =
+            Form::<T>::from_request(Request::from_parts(parts, Body::from(bytes)), state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+        Ok(CsrfForm(form))
+    }
+}
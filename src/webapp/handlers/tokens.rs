@@ -0,0 +1,134 @@
+use super::{
+    super::{WebappError, state::AppState},
+    middleware::CurrentUser,
+    session::{CsrfForm, NoFields, insert_csrf_token},
+};
+use crate::db::models::{
+    ApiToken, User,
+    api_token::{TokenScope, create_api_token, delete_api_token, get_api_tokens_for_user},
+};
+use axum::{
+    extract::{Path, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+// tera can't compare timestamps
+#[derive(Serialize)]
+struct TokenRow<'a> {
+    #[serde(flatten)]
+    token: &'a ApiToken,
+    expired: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TokenPayload {
+    name: String,
+    scope: TokenScope,
+    expires_in_days: Option<String>,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_profile_tokens(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    Ok(render_tokens(&state, &user, &jar, tera::Context::new())
+        .await?
+        .into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_profile_tokens(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+    CsrfForm(payload): CsrfForm<TokenPayload>,
+) -> Result<Response, WebappError> {
+    let mut context = tera::Context::new();
+
+    let name = payload.name.trim().to_string();
+    // Some(None) is a token that never expires
+    let expires_in_days = match payload.expires_in_days.as_deref().map(str::trim) {
+        None | Some("") => Some(None),
+        Some(days) => days
+            .parse::<i64>()
+            .ok()
+            .filter(|days| (1..=365).contains(days))
+            .map(Some),
+    };
+
+    if name.is_empty() || name.chars().count() > 50 {
+        context.insert("alert", "Name must be between 1 and 50 characters.");
+    } else if let Some(expires_in_days) = expires_in_days {
+        let expires_at = expires_in_days.map(|days| Utc::now() + Duration::days(days));
+        let token = {
+            let user = user.clone();
+            state
+                .db(move |conn| {
+                    let (_api_token, token) =
+                        create_api_token(&user, &name, payload.scope, expires_at, conn)?;
+                    Ok(token)
+                })
+                .await?
+        };
+        context.insert("new_token", &token);
+    } else {
+        context.insert("alert", "Expiry must be between 1 and 365 days.");
+    }
+
+    Ok(render_tokens(&state, &user, &jar, context)
+        .await?
+        .into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_profile_token_delete(
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    CsrfForm(NoFields {}): CsrfForm<NoFields>,
+) -> Result<Response, WebappError> {
+    let deleted = state
+        .db(move |conn| Ok(delete_api_token(id, &user, conn)?))
+        .await?;
+    if deleted == 0 {
+        return Err(WebappError::DieselResultError(
+            diesel::result::Error::NotFound,
+        ));
+    }
+
+    Ok(Redirect::to("/profile/tokens").into_response())
+}
+
+async fn render_tokens(
+    state: &AppState,
+    user: &User,
+    jar: &PrivateCookieJar,
+    mut context: tera::Context,
+) -> Result<Html<String>, WebappError> {
+    insert_csrf_token(&mut context, jar);
+    let tokens = {
+        let user = user.clone();
+        state
+            .db(move |conn| Ok(get_api_tokens_for_user(&user, conn)?))
+            .await?
+    };
+    let rows: Vec<TokenRow> = tokens
+        .iter()
+        .map(|token| TokenRow {
+            token,
+            expired: token.is_expired(),
+        })
+        .collect();
+    context.insert("tokens", &rows);
+    context.insert("user", &user.username);
+    context.insert("title", "axum-boilerplate | API tokens");
+    context.insert("active", "profile");
+    let rendered = state.tera.render("tokens.html", &context)?;
+
+    Ok(Html(rendered))
+}
@@ -0,0 +1,261 @@
+use super::{
+    super::{WebappError, state::AppState},
+    ACCOUNT_DISABLED, TOO_MANY_ATTEMPTS,
+    middleware::{ClientIp, CurrentUser},
+    session::{self, CsrfForm, insert_csrf_token},
+};
+use crate::db::{
+    models::{
+        User,
+        totp::{
+            disable_totp, enable_totp, generate_totp_secret, totp_for, use_recovery_code,
+            use_totp_code, verify_totp_code,
+        },
+        user::{get_user_by_username, record_failed_login, reset_failed_logins},
+    },
+    schema::users,
+};
+use crate::metrics::{LoginMethod, metrics};
+use axum::{
+    extract::{Form, State},
+    http::HeaderMap,
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::{PrivateCookieJar, cookie::Cookie};
+use chrono::Utc;
+use diesel::prelude::*;
+use qrcode::{QrCode, render::svg};
+use serde::Deserialize;
+
+// how long the second login step stays open after the password was verified
+const PENDING_SECONDS: i64 = 5 * 60;
+
+#[derive(Deserialize, Debug)]
+pub struct TotpPayload {
+    code: String,
+}
+
+// password was correct, remember who is mid-login until the code is checked
+pub fn start_second_step(jar: PrivateCookieJar, user: &User, next_url: String) -> PrivateCookieJar {
+    let pending = format!("{}|{}", user.id, Utc::now().timestamp());
+    jar.add(Cookie::build(("totp_pending", pending)).path("/"))
+        .add(Cookie::build(("next_url", next_url)).path("/"))
+}
+
+fn pending_user_id(jar: &PrivateCookieJar) -> Option<i32> {
+    let pending = jar.get("totp_pending")?;
+    let (user_id, started_at) = pending.value().split_once('|')?;
+    let started_at: i64 = started_at.parse().ok()?;
+    if Utc::now().timestamp() - started_at > PENDING_SECONDS {
+        return None;
+    }
+    user_id.parse().ok()
+}
+
+// accepts a current totp code or an unused recovery code
+async fn verify_second_factor(
+    user: &User,
+    code: String,
+    state: &AppState,
+) -> Result<bool, WebappError> {
+    if user.totp_secret.is_none() {
+        return Ok(false);
+    }
+    let user = user.clone();
+    state
+        .db(move |conn| {
+            if use_totp_code(&user, &code, conn)? {
+                return Ok(true);
+            }
+            Ok(use_recovery_code(&user, &code, conn)?)
+        })
+        .await
+}
+
+async fn load_user_by_username(username: String, state: &AppState) -> Result<User, WebappError> {
+    state
+        .db(move |conn| Ok(get_user_by_username(&username, conn)))
+        .await?
+        .ok_or(WebappError::NotLoggedInError)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_login_totp(
+    jar: PrivateCookieJar,
+    State(tera): State<tera::Tera>,
+) -> Result<Response, WebappError> {
+    if pending_user_id(&jar).is_none() {
+        return Ok(Redirect::to("/login").into_response());
+    }
+
+    let rendered = tera.render("login-totp.html", &tera::Context::new())?;
+    Ok(Html(rendered).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_login_totp(
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    Form(payload): Form<TotpPayload>,
+) -> Result<(PrivateCookieJar, Response), WebappError> {
+    let Some(user_id) = pending_user_id(&jar) else {
+        let jar = jar.remove(Cookie::from("totp_pending"));
+        return Ok((jar, Redirect::to("/login").into_response()));
+    };
+
+    let ip = ip.to_string();
+    let user = state
+        .db(move |conn| Ok(users::table.find(user_id).first::<User>(conn)?))
+        .await?;
+
+    // codes are only 6 digits, so they get the same throttling as passwords
+    let alert = if state
+        .login_throttle
+        .retry_after(&user.username, &ip)
+        .is_some()
+        || user.is_locked()
+    {
+        Some(TOO_MANY_ATTEMPTS)
+    } else if user.is_disabled() {
+        Some(ACCOUNT_DISABLED)
+    } else if !verify_second_factor(&user, payload.code, &state).await? {
+        state.login_throttle.record_failure(&user.username, &ip);
+        let user = user.clone();
+        state
+            .db(move |conn| Ok(record_failed_login(&user, conn)?))
+            .await?;
+        Some("Invalid code")
+    } else {
+        None
+    };
+
+    if let Some(alert) = alert {
+        metrics().record_login(LoginMethod::Totp, false);
+        let mut context = tera::Context::new();
+        context.insert("alert", alert);
+        let rendered = state.tera.render("login-totp.html", &context)?;
+        return Ok((jar, Html(rendered).into_response()));
+    }
+
+    metrics().record_login(LoginMethod::Totp, true);
+    state.login_throttle.record_success(&user.username);
+    {
+        let user = user.clone();
+        state
+            .db(move |conn| Ok(reset_failed_logins(&user, conn)?))
+            .await?;
+    }
+
+    let next_url = jar
+        .get("next_url")
+        .map(|next_url| next_url.value().to_string())
+        .unwrap_or_else(|| "/".to_string());
+    let jar = jar
+        .remove(Cookie::from("totp_pending"))
+        .remove(Cookie::from("next_url"));
+    let updated_jar = session::start_session(jar, &user, Some(ip), &headers, &state).await?;
+
+    Ok((updated_jar, Redirect::to(next_url.as_str()).into_response()))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_profile_totp(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<(PrivateCookieJar, Response), WebappError> {
+    if user.totp_secret.is_some() {
+        let rendered = render_totp(&state, &user, &jar, None, tera::Context::new())?;
+        return Ok((jar, rendered.into_response()));
+    }
+
+    // secret only lives in the encrypted cookie until a code confirms it
+    let secret = generate_totp_secret();
+    let rendered = render_totp(&state, &user, &jar, Some(&secret), tera::Context::new())?;
+    let updated_jar = jar.add(Cookie::build(("totp_enroll", secret)).path("/profile"));
+
+    Ok((updated_jar, rendered.into_response()))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_profile_totp(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+    CsrfForm(payload): CsrfForm<TotpPayload>,
+) -> Result<(PrivateCookieJar, Response), WebappError> {
+    let Some(secret) = jar
+        .get("totp_enroll")
+        .map(|secret| secret.value().to_string())
+    else {
+        return Ok((jar, Redirect::to("/profile/totp").into_response()));
+    };
+
+    let mut context = tera::Context::new();
+
+    if !verify_totp_code(&secret, &user.username, &payload.code)? {
+        context.insert("alert", "Invalid code, please try again.");
+        let rendered = render_totp(&state, &user, &jar, Some(&secret), context)?;
+        return Ok((jar, rendered.into_response()));
+    }
+
+    let recovery_codes = {
+        let (user, secret) = (user.clone(), secret.clone());
+        state
+            .db(move |conn| Ok(enable_totp(&user, &secret, conn)?))
+            .await?
+    };
+    let user = load_user_by_username(user.username, &state).await?;
+    context.insert("recovery_codes", &recovery_codes);
+    let rendered = render_totp(&state, &user, &jar, None, context)?;
+    let updated_jar = jar.remove(Cookie::build("totp_enroll").path("/profile"));
+
+    Ok((updated_jar, rendered.into_response()))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_profile_totp_disable(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+    CsrfForm(payload): CsrfForm<TotpPayload>,
+) -> Result<Response, WebappError> {
+    if !verify_second_factor(&user, payload.code, &state).await? {
+        let mut context = tera::Context::new();
+        context.insert("alert", "Invalid code");
+        return Ok(render_totp(&state, &user, &jar, None, context)?.into_response());
+    }
+
+    state.db(move |conn| Ok(disable_totp(&user, conn)?)).await?;
+
+    Ok(Redirect::to("/profile").into_response())
+}
+
+// enrolling when secret is set, otherwise shows the enabled state
+fn render_totp(
+    state: &AppState,
+    user: &User,
+    jar: &PrivateCookieJar,
+    secret: Option<&str>,
+    mut context: tera::Context,
+) -> Result<Html<String>, WebappError> {
+    insert_csrf_token(&mut context, jar);
+    if let Some(secret) = secret {
+        let url = totp_for(secret, &user.username)?.get_url();
+        let qr_code = QrCode::new(url.as_bytes())?
+            .render::<svg::Color>()
+            .min_dimensions(200, 200)
+            .build();
+        context.insert("secret", secret);
+        context.insert("qr_code", &qr_code);
+    }
+    context.insert("enabled", &user.totp_secret.is_some());
+    context.insert("user", &user.username);
+    context.insert("title", "axum-boilerplate | Two-factor authentication");
+    context.insert("active", "profile");
+    let rendered = state.tera.render("totp.html", &context)?;
+
+    Ok(Html(rendered))
+}
@@ -0,0 +1,161 @@
+use super::{
+    super::{WebappError, state::AppState},
+    middleware::CurrentUser,
+    session::{CsrfForm, NoFields, insert_csrf_token},
+};
+use crate::db::models::{
+    User,
+    webhook::{
+        create_webhook, delete_webhook, get_deliveries_for_webhook, get_webhook,
+        get_webhooks_for_user, retry_delivery,
+    },
+};
+use axum::{
+    extract::{Path, State},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::PrivateCookieJar;
+use serde::Deserialize;
+use url::Url;
+
+const DELIVERY_LOG_SIZE: i64 = 50;
+
+#[derive(Deserialize, Debug)]
+pub struct WebhookPayload {
+    url: String,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_profile_webhooks(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    Ok(render_webhooks(&state, &user, &jar, tera::Context::new())
+        .await?
+        .into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_profile_webhooks(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    State(state): State<AppState>,
+    CsrfForm(payload): CsrfForm<WebhookPayload>,
+) -> Result<Response, WebappError> {
+    let mut context = tera::Context::new();
+
+    let url = payload.url.trim().to_string();
+    let valid = url.len() <= 2000
+        && Url::parse(&url).is_ok_and(|url| matches!(url.scheme(), "http" | "https"));
+
+    if valid {
+        let (webhook, secret) = {
+            let user = user.clone();
+            state
+                .db(move |conn| Ok(create_webhook(&user, &url, conn)?))
+                .await?
+        };
+        context.insert("new_secret", &secret);
+        context.insert("new_url", &webhook.url);
+    } else {
+        context.insert("alert", "Enter a full http or https URL.");
+    }
+
+    Ok(render_webhooks(&state, &user, &jar, context)
+        .await?
+        .into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_profile_webhook(
+    CurrentUser(user): CurrentUser,
+    jar: PrivateCookieJar,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+) -> Result<Response, WebappError> {
+    let (webhook, deliveries) = {
+        let user = user.clone();
+        state
+            .db(move |conn| {
+                let webhook = get_webhook(id, &user, conn)?;
+                let deliveries = get_deliveries_for_webhook(&webhook, DELIVERY_LOG_SIZE, conn)?;
+                Ok((webhook, deliveries))
+            })
+            .await?
+    };
+
+    let mut context = tera::Context::new();
+    insert_csrf_token(&mut context, &jar);
+    context.insert("webhook", &webhook);
+    context.insert("deliveries", &deliveries);
+    context.insert("user", &user.username);
+    context.insert("title", "axum-boilerplate | Webhook");
+    context.insert("active", "profile");
+    let rendered = state.tera.render("webhook.html", &context)?;
+
+    Ok(Html(rendered).into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_profile_webhook_delete(
+    CurrentUser(user): CurrentUser,
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    CsrfForm(NoFields {}): CsrfForm<NoFields>,
+) -> Result<Response, WebappError> {
+    let deleted = state
+        .db(move |conn| Ok(delete_webhook(id, &user, conn)?))
+        .await?;
+    if deleted == 0 {
+        return Err(WebappError::DieselResultError(
+            diesel::result::Error::NotFound,
+        ));
+    }
+
+    Ok(Redirect::to("/profile/webhooks").into_response())
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn post_profile_webhook_retry(
+    CurrentUser(user): CurrentUser,
+    Path((id, delivery_id)): Path<(i32, i32)>,
+    State(state): State<AppState>,
+    CsrfForm(NoFields {}): CsrfForm<NoFields>,
+) -> Result<Response, WebappError> {
+    let retried = state
+        .db(move |conn| {
+            let webhook = get_webhook(id, &user, conn)?;
+            Ok(retry_delivery(delivery_id, &webhook, conn)?)
+        })
+        .await?;
+    if retried == 0 {
+        return Err(WebappError::DieselResultError(
+            diesel::result::Error::NotFound,
+        ));
+    }
+
+    Ok(Redirect::to(&format!("/profile/webhooks/{}", id)).into_response())
+}
+
+async fn render_webhooks(
+    state: &AppState,
+    user: &User,
+    jar: &PrivateCookieJar,
+    mut context: tera::Context,
+) -> Result<Html<String>, WebappError> {
+    insert_csrf_token(&mut context, jar);
+    let webhooks = {
+        let user = user.clone();
+        state
+            .db(move |conn| Ok(get_webhooks_for_user(&user, conn)?))
+            .await?
+    };
+    context.insert("webhooks", &webhooks);
+    context.insert("user", &user.username);
+    context.insert("title", "axum-boilerplate | Webhooks");
+    context.insert("active", "profile");
+    let rendered = state.tera.render("webhooks.html", &context)?;
+
+    Ok(Html(rendered))
+}
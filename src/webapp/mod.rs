@@ -1,26 +1,40 @@
-use crate::{db::get_connection_pool, get_config};
+use crate::{
+    config::Config,
+    db::{
+        get_connection_pool,
+        models::{password::init_password_policy, role::Permission},
+    },
+    jobs, live, logging, mailer, shutdown,
+};
 use axum::{
     Router,
+    extract::{MatchedPath, Request},
     http::StatusCode,
     middleware,
     response::IntoResponse,
     routing::{delete, get, patch, post},
 };
-use axum_extra::extract::cookie::Key;
 use axum_htmx::{AutoVaryLayer, HxRequestGuardLayer};
-use handlers::calendar::DateError;
-use rand::distr::{Alphanumeric, SampleString};
-use state::{AppState, InnerState};
-use std::{env, sync::Arc};
+use handlers::{
+    calendar::DateError,
+    middleware::{RequestId, require_permission},
+};
+use state::AppState;
+use std::{future::IntoFuture, net::SocketAddr, sync::Arc, time::Duration};
 use tera::Tera;
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, time::Instant};
+use tokio_util::sync::CancellationToken;
 use tower::ServiceBuilder;
-use tower_http::trace::TraceLayer;
-use tracing::info;
+use tower_http::{services::ServeDir, trace::TraceLayer};
+use tracing::{Span, error, info, info_span, warn};
 
+mod api;
+mod cookie_keys;
 mod handlers;
-mod sso;
+pub mod sso;
 pub mod state;
+mod throttle;
+pub mod webhooks;
 
 #[derive(Debug, thiserror::Error)]
 pub enum WebappError {
@@ -75,6 +89,20 @@ pub enum WebappError {
     #[error(transparent)]
     BcryptError(#[from] bcrypt::BcryptError),
 
+    #[error(transparent)]
+    PasswordHashError(#[from] argon2::password_hash::Error),
+
+    // totp errors
+    // -----------
+    #[error(transparent)]
+    TotpUrlError(#[from] totp_rs::TotpUrlError),
+
+    #[error(transparent)]
+    TotpSecretError(#[from] totp_rs::SecretParseError),
+
+    #[error(transparent)]
+    QrError(#[from] qrcode::types::QrError),
+
     #[error("Not logged in")]
     NotLoggedInError,
 
@@ -87,6 +115,20 @@ pub enum WebappError {
     #[error(transparent)]
     UnreachableDateError(#[from] DateError),
 
+    #[error(transparent)]
+    ValidationError(#[from] validator::ValidationErrors),
+
+    // a panic in database work run by state.db
+    #[error(transparent)]
+    JoinError(#[from] tokio::task::JoinError),
+
+    // sso callback without a matching state from our own redirect
+    #[error("sso state mismatch")]
+    SsoStateError,
+
+    #[error("csrf token missing or wrong")]
+    CsrfError,
+
     #[error("Test error")]
     TestError,
     // #[error(transparent)]
@@ -97,83 +139,376 @@ pub enum WebappError {
     // Error(#[from] Box<dyn std::error::Error>),
 }
 
+impl WebappError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            WebappError::NotLoggedInError => StatusCode::UNAUTHORIZED,
+            WebappError::DieselResultError(diesel::result::Error::NotFound) => {
+                StatusCode::NOT_FOUND
+            }
+            WebappError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            WebappError::HxRequestExpectedError
+            | WebappError::ClaimsVerificationError(_)
+            | WebappError::SsoStateError => StatusCode::BAD_REQUEST,
+            WebappError::CsrfError => StatusCode::FORBIDDEN,
+            // an sso provider that isn't configured
+            WebappError::MissingOauthClientError => StatusCode::NOT_FOUND,
+            WebappError::NoMatchingUserEmailError => StatusCode::FORBIDDEN,
+            // the identity provider failed us
+            WebappError::DiscoveryError(_)
+            | WebappError::RequestTokenError(_)
+            | WebappError::MissingIdToken => StatusCode::BAD_GATEWAY,
+            // no connection within the pool's timeout
+            WebappError::R2d2Error(_) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// only the status goes out, error_middleware renders the page for it
 impl IntoResponse for WebappError {
     fn into_response(self) -> axum::response::Response {
-        tracing::error!("WebappError: {:#?}", self);
-        info!("WebappError: {:#?}", self);
-        println!("WebappError: {:#?}", self);
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#?}", self)).into_response()
+        let status = self.status();
+        // inside the request span, so this carries the request and user id
+        if status.is_server_error() {
+            error!(error = ?self, "request failed");
+        } else {
+            info!(error = %self, "request failed");
+        }
+        (status, status.canonical_reason().unwrap_or_default()).into_response()
     }
 }
 
-pub async fn run_server() {
-    tracing_subscriber::fmt::init();
+// user_id is recorded by auth_middleware once it knows who is asking
+fn request_span(request: &Request) -> Span {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.as_str())
+        .unwrap_or_default();
+    // exported spans are named by route, "GET /goals/{id}" rather than every goal's url
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str())
+        .unwrap_or("unmatched");
+    info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id,
+        user_id = tracing::field::Empty,
+        otel.name = format!("{} {}", request.method(), route),
+        otel.kind = "server",
+    )
+}
+
+// every route and layer, without the listener, so tests can drive it with oneshot
+pub fn router(config: &Config, app_state: AppState) -> Router {
+    let app =
+        Router::new()
+            // htmx guarded routes, auth
+            .route("/goals/table", get(handlers::goal::hx_get_goals_table))
+            .route("/goals/new", get(handlers::goal::hx_get_new_goal))
+            .route("/goals/new", post(handlers::goal::hx_post_new_goal))
+            .route("/goals/{id}", get(handlers::goal::hx_get_goal))
+            .route("/goals/{id}", patch(handlers::goal::hx_patch_goal))
+            .route("/goals/{id}", delete(handlers::goal::hx_delete_goal))
+            .route("/goals/{id}/edit", get(handlers::goal::hx_get_edit_goal))
+            .route(
+                "/goals/{id}/completed",
+                post(handlers::goal::hx_post_goal_completed),
+            )
+            .route(
+                "/goals/{id}/reminders",
+                post(handlers::goal::hx_post_goal_reminder),
+            )
+            .route(
+                "/goals/{id}/reminders/{reminder_id}",
+                delete(handlers::goal::hx_delete_goal_reminder),
+            )
+            .route(
+                "/profile/identities/{id}",
+                delete(handlers::profile::hx_delete_identity),
+            )
+            .route(
+                "/notifications/bell",
+                get(handlers::notifications::hx_get_notification_bell),
+            )
+            .route_layer(HxRequestGuardLayer::default())
+            // auth routes
+            .route("/goals", get(handlers::goal::get_goals))
+            .route("/events", get(handlers::events::get_events))
+            .route(
+                "/notifications",
+                get(handlers::notifications::get_notifications),
+            )
+            .route(
+                "/notifications/read-all",
+                post(handlers::notifications::post_notifications_read_all),
+            )
+            .route(
+                "/notifications/{id}",
+                get(handlers::notifications::get_notification),
+            )
+            .route(
+                "/notifications/{id}/read",
+                post(handlers::notifications::post_notification_read),
+            )
+            .route("/profile", get(handlers::profile::get_profile))
+            .route("/{provider}/link", post(sso::post_sso_link))
+            .route(
+                "/profile/time-zone",
+                post(handlers::profile::post_profile_time_zone),
+            )
+            .route(
+                "/impersonation/exit",
+                post(handlers::impersonation::post_impersonation_exit),
+            )
+            .route(
+                "/profile/password",
+                get(handlers::profile::get_profile_password),
+            )
+            .route(
+                "/profile/password",
+                post(handlers::profile::post_profile_password),
+            )
+            .route("/profile/tokens", get(handlers::tokens::get_profile_tokens))
+            .route(
+                "/profile/tokens",
+                post(handlers::tokens::post_profile_tokens),
+            )
+            .route(
+                "/profile/tokens/{id}/delete",
+                post(handlers::tokens::post_profile_token_delete),
+            )
+            .route(
+                "/profile/webhooks",
+                get(handlers::webhooks::get_profile_webhooks),
+            )
+            .route(
+                "/profile/webhooks",
+                post(handlers::webhooks::post_profile_webhooks),
+            )
+            .route(
+                "/profile/webhooks/{id}",
+                get(handlers::webhooks::get_profile_webhook),
+            )
+            .route(
+                "/profile/webhooks/{id}/delete",
+                post(handlers::webhooks::post_profile_webhook_delete),
+            )
+            .route(
+                "/profile/webhooks/{id}/deliveries/{delivery_id}/retry",
+                post(handlers::webhooks::post_profile_webhook_retry),
+            )
+            .route("/profile/totp", get(handlers::totp::get_profile_totp))
+            .route("/profile/totp", post(handlers::totp::post_profile_totp))
+            .route(
+                "/profile/totp/disable",
+                post(handlers::totp::post_profile_totp_disable),
+            )
+            .merge(
+                Router::new()
+                    .route("/admin/users", get(handlers::admin::get_admin_users))
+                    .route("/admin/users/new", get(handlers::admin::get_admin_new_user))
+                    .route(
+                        "/admin/users/new",
+                        post(handlers::admin::post_admin_new_user),
+                    )
+                    .route("/admin/users/{id}", get(handlers::admin::get_admin_user))
+                    .route(
+                        "/admin/users/{id}/impersonate",
+                        post(handlers::impersonation::post_admin_impersonate),
+                    )
+                    .route(
+                        "/admin/users/{id}/edit",
+                        get(handlers::admin::get_admin_edit_user),
+                    )
+                    .route(
+                        "/admin/users/{id}/edit",
+                        post(handlers::admin::post_admin_edit_user),
+                    )
+                    .route(
+                        "/admin/users/{id}/{action}",
+                        post(handlers::admin::post_admin_user_action),
+                    )
+                    .route_layer(middleware::from_fn(|request, next| {
+                        require_permission(Permission::ManageUsers, request, next)
+                    })),
+            )
+            .merge(
+                Router::new()
+                    .route("/admin", get(handlers::admin::get_admin))
+                    .route_layer(middleware::from_fn(|request, next| {
+                        require_permission(Permission::ViewAdmin, request, next)
+                    })),
+            )
+            .route_layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                handlers::middleware::auth_middleware,
+            ))
+            .route("/", get(handlers::get_index))
+            .route("/login", get(handlers::get_login))
+            .route("/login", post(handlers::post_login))
+            .route("/login/totp", get(handlers::totp::get_login_totp))
+            .route("/login/totp", post(handlers::totp::post_login_totp))
+            .route("/logout", get(handlers::get_logout))
+            .route("/test_error", get(handlers::get_test_error_page))
+            .route("/calendar", get(handlers::calendar::get_calendar))
+            .merge(
+                Router::new()
+                    .route(
+                        "/calendar/content",
+                        get(handlers::calendar::hx_get_calendar_content),
+                    )
+                    .route(
+                        "/impersonation/banner",
+                        get(handlers::impersonation::hx_get_impersonation_banner),
+                    )
+                    .route_layer(HxRequestGuardLayer::default()),
+            )
+            .merge(sso::sso_router())
+            .merge(api::api_router(app_state.clone(), config.features.api_docs))
+            .nest_service("/static", ServeDir::new(&config.server.static_dir))
+            .layer(
+                ServiceBuilder::new()
+                    .layer(middleware::from_fn(
+                        handlers::middleware::request_id_middleware,
+                    ))
+                    .layer(TraceLayer::new_for_http().make_span_with(request_span))
+                    .layer(middleware::from_fn_with_state(
+                        app_state.clone(),
+                        handlers::middleware::error_middleware,
+                    ))
+                    .layer(middleware::from_fn(
+                        handlers::middleware::metrics_middleware,
+                    ))
+                    .layer(AutoVaryLayer)
+                    .layer(middleware::from_fn_with_state(
+                        app_state.clone(),
+                        cookie_keys::cookie_keys_middleware,
+                    ))
+                    .option_layer(config.cookies.secure.then(|| {
+                        middleware::from_fn(handlers::middleware::secure_cookies_middleware)
+                    })),
+            )
+            // outside the layers above, probes want the plain status
+            .route("/healthz", get(handlers::health::get_healthz))
+            .route("/readyz", get(handlers::health::get_readyz))
+            .route("/version", get(handlers::health::get_version));
+    let app = if config.features.metrics {
+        app.route("/metrics", get(handlers::health::get_metrics))
+    } else {
+        app
+    };
+    app.with_state(app_state)
+}
+
+pub async fn run_server() -> std::io::Result<()> {
+    // the log format is in the config, so its errors can't be logged yet
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            ::std::process::exit(1);
+        }
+    };
 
-    get_config();
+    let _logging = logging::init(&config.logging);
 
-    let tera = match Tera::new("src/webapp/templates/**/*.html") {
+    let tera = match Tera::new(&config.server.templates) {
         Ok(t) => t,
         Err(e) => {
-            println!("Parsing error(s): {}", e);
+            error!("template parsing error(s): {}", e);
             ::std::process::exit(1);
         }
     };
 
-    let secret = env::var("SECRET").unwrap_or_else(|_| {
-        info!("no secret in env, generating...");
-        Alphanumeric.sample_string(&mut rand::rng(), 64)
-    });
-
-    let key = Key::from(secret.as_bytes());
-
-    let pool = get_connection_pool();
-
-    let app_state = AppState(Arc::new(InnerState { tera, key, pool }));
-
-    let app = Router::new()
-        // htmx guarded routes, auth
-        .route("/goals/table", get(handlers::goal::hx_get_goals_table))
-        .route("/goals/new", get(handlers::goal::hx_get_new_goal))
-        .route("/goals/new", post(handlers::goal::hx_post_new_goal))
-        .route("/goals/{id}", get(handlers::goal::hx_get_goal))
-        .route("/goals/{id}", patch(handlers::goal::hx_patch_goal))
-        .route("/goals/{id}", delete(handlers::goal::hx_delete_goal))
-        .route("/goals/{id}/edit", get(handlers::goal::hx_get_edit_goal))
-        .route_layer(HxRequestGuardLayer::default())
-        // auth routes
-        .route("/goals", get(handlers::goal::get_goals))
-        .route_layer(middleware::from_fn_with_state(
-            app_state.clone(),
-            handlers::middleware::auth_middleware,
-        ))
-        .route("/", get(handlers::get_index))
-        .route("/login", get(handlers::get_login))
-        .route("/login", post(handlers::post_login))
-        .route("/logout", get(handlers::get_logout))
-        .route("/error", get(handlers::get_error_page))
-        .route("/test_error", get(handlers::get_test_error_page))
-        .route("/calendar", get(handlers::calendar::get_calendar))
-        .merge(
-            Router::new()
-                .route(
-                    "/calendar/content",
-                    get(handlers::calendar::hx_get_calendar_content),
-                )
-                .route_layer(HxRequestGuardLayer::default()),
-        )
-        .merge(sso::sso_router())
-        .layer(
-            ServiceBuilder::new()
-                .layer(TraceLayer::new_for_http())
-                .layer(middleware::from_fn_with_state(
-                    app_state.clone(),
-                    handlers::middleware::error_middleware,
-                ))
-                .layer(AutoVaryLayer),
-        )
-        .with_state(app_state);
-
-    let listener = TcpListener::bind("127.0.0.1:3000").await.unwrap();
-
-    axum::serve(listener, app).await.unwrap();
+    let pool = get_connection_pool(&config.database);
+
+    init_password_policy(&config.password);
+
+    let shutdown = CancellationToken::new();
+    shutdown::cancel_on_signal(shutdown.clone());
+
+    let app_state = match AppState::new(&config, tera, pool, shutdown.clone()) {
+        Ok(app_state) => app_state,
+        Err(e) => {
+            error!("SSO configuration error: {}", e);
+            ::std::process::exit(1);
+        }
+    };
+
+    live::spawn_listener(config.database.url.clone(), &app_state.live);
+
+    let mut background_tasks = Vec::new();
+    if config.features.webhook_delivery {
+        background_tasks.push(webhooks::spawn_webhook_worker(
+            app_state.pool.clone(),
+            config.features.webhook_private_addresses,
+            shutdown.clone(),
+        ));
+    }
+
+    if config.features.job_workers > 0 {
+        let context = jobs::JobContext {
+            pool: app_state.pool.clone(),
+            mailer: mailer::mailer_from_config(&config.mail).expect("mail config is validated"),
+        };
+        background_tasks.extend(jobs::spawn_job_workers(
+            Arc::new(jobs::registry()),
+            context,
+            config.features.job_workers,
+            shutdown.clone(),
+        ));
+    }
+    if config.features.reminders {
+        background_tasks.push(jobs::reminders::spawn_reminder_scheduler(
+            app_state.pool.clone(),
+            shutdown.clone(),
+        ));
+    }
+
+    let app = router(&config, app_state);
+
+    let listener = TcpListener::bind(config.server.bind).await?;
+    info!("listening on {}", config.server.bind);
+
+    // client ip is needed for login throttling
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown.clone().cancelled_owned());
+    let mut server = tokio::spawn(server.into_future());
+
+    // the server only stops by itself when it fails, then the background tasks stop with it
+    let failed = tokio::select! {
+        served = &mut server => Some(served),
+        () = shutdown.cancelled() => None,
+    };
+    shutdown.cancel();
+
+    // open requests and background jobs drain side by side, with one timeout from the signal
+    let deadline = Instant::now() + Duration::from_secs(config.server.drain_timeout);
+    let (served, ()) = tokio::join!(
+        async {
+            match failed {
+                Some(served) => Ok(served),
+                None => tokio::time::timeout_at(deadline, server).await,
+            }
+        },
+        shutdown::join_tasks(background_tasks, deadline),
+    );
+    let result = match served {
+        Ok(served) => served.expect("server panicked"),
+        Err(_) => {
+            warn!("requests still open at the drain timeout, closing them");
+            Ok(())
+        }
+    };
+
+    info!("shut down");
+    result
 }
@@ -1,32 +1,53 @@
 use super::WebappError;
-use super::handlers;
+use super::handlers::{
+    self,
+    middleware::{ClientIp, CurrentUser},
+    session::verify_csrf_token,
+};
 use super::state::AppState;
-use crate::db::models::user::get_user_by_email;
+use crate::{
+    config::SsoConfig,
+    db::models::{
+        EmailAddress, NewUser, NewUserIdentity, User, UserSession,
+        identity::{create_new_identity, get_identities_for_user, get_user_by_identity},
+        user::{create_new_user, get_user_by_email, get_user_by_username},
+    },
+    metrics::{LoginMethod, metrics},
+};
+use axum::Extension;
 use axum::Router;
-use axum::extract::{Path, Query, State};
+use axum::extract::{Form, Path, Query, State};
 use axum::http::HeaderMap;
-use axum::response::{IntoResponse, Redirect};
+use axum::response::{IntoResponse, Redirect, Response};
 use axum::routing::get;
 use axum_extra::extract::{PrivateCookieJar, cookie::Cookie};
+use diesel::{Connection, PgConnection};
 use openidconnect::core::CoreErrorResponseType;
+use openidconnect::core::CoreIdTokenClaims;
 use openidconnect::core::CoreRevocableToken;
 use openidconnect::core::CoreTokenType;
 use openidconnect::{
-    AuthenticationFlow, AuthorizationCode, Client, CsrfToken, EmptyAdditionalClaims,
-    EmptyExtraTokenFields, EndpointMaybeSet, EndpointNotSet, EndpointSet, IdTokenFields, Nonce,
-    RevocationErrorResponseType, Scope, StandardErrorResponse, StandardTokenIntrospectionResponse,
-    StandardTokenResponse, TokenResponse,
+    AuthenticationFlow, AuthorizationCode, ClaimsVerificationError, Client, CsrfToken,
+    EmptyAdditionalClaims, EmptyExtraTokenFields, EndpointMaybeSet, EndpointNotSet, EndpointSet,
+    IdTokenFields, Nonce, RevocationErrorResponseType, Scope, SignatureVerificationError,
+    StandardErrorResponse, StandardTokenIntrospectionResponse, StandardTokenResponse,
+    TokenResponse,
     core::{
         CoreAuthDisplay, CoreAuthPrompt, CoreGenderClaim, CoreJsonWebKey,
         CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm, CoreResponseType,
     },
     reqwest,
 };
+use provider::{EmailTrust, SsoProvider};
 use serde::Deserialize;
-use tracing::debug;
+use std::collections::HashMap;
+use tracing::{debug, info, warn};
+use url::Url;
+use validator::Validate;
 
 pub mod google_sso;
 pub mod microsoft_sso;
+pub mod provider;
 
 pub type OauthClient = Client<
     EmptyAdditionalClaims,
@@ -57,30 +78,102 @@ pub type OauthClient = Client<
     EndpointMaybeSet,
 >;
 
+// linking is behind the login, see post_sso_link
 pub fn sso_router() -> Router<AppState> {
-    let route = Router::new()
+    Router::new()
         .route("/{provider}/login", get(get_sso_login))
-        .route("/{provider}/callback", get(get_sso_callback));
+        .route("/{provider}/callback", get(get_sso_callback))
+}
 
-    route
+// the login or link in flight, kept in the private jar from the redirect to the callback
+const PENDING_COOKIE: &str = "sso_pending";
+
+#[derive(Debug, PartialEq)]
+struct PendingSso {
+    provider: String,
+    // link to the logged in user rather than log in
+    link: bool,
+    csrf_state: String,
+    nonce: String,
 }
 
-async fn get_oauth_client(provider: &str) -> Result<OauthClient, WebappError> {
-    match provider {
-        "microsoft" => microsoft_sso::oauth_client().await,
-        "google" => google_sso::oauth_client().await,
-        _ => Err(WebappError::MissingOauthClientError),
+impl PendingSso {
+    fn cookie_value(&self) -> String {
+        format!(
+            "{}|{}|{}|{}",
+            self.provider, self.link, self.csrf_state, self.nonce
+        )
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(4, '|');
+        Some(Self {
+            provider: parts.next()?.to_string(),
+            link: parts.next()?.parse().ok()?,
+            csrf_state: parts.next()?.to_string(),
+            nonce: parts.next()?.to_string(),
+        })
     }
 }
 
-async fn get_sso_login(
-    Path(provider): Path<String>,
-    headers: HeaderMap,
+// configured providers, built once at startup and shared via InnerState
+pub struct SsoProviders {
+    providers: HashMap<&'static str, SsoProvider>,
+    // create users on first sso login from verified claims
+    jit_provisioning: bool,
+}
+
+impl SsoProviders {
+    pub fn from_config(config: &SsoConfig) -> Result<Self, WebappError> {
+        // one connection pool shared by discovery, jwks and token requests
+        let http_client = reqwest::ClientBuilder::new()
+            // Following redirects opens the client up to SSRF vulnerabilities.
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("HTTP Client should build");
+
+        let mut providers = HashMap::new();
+        if let Some(google) = &config.google {
+            let provider = google_sso::sso_provider(google, http_client.clone())?;
+            providers.insert(provider.name(), provider);
+        }
+        if let Some(microsoft) = &config.microsoft {
+            let provider = microsoft_sso::sso_provider(microsoft, http_client.clone())?;
+            providers.insert(provider.name(), provider);
+        }
+        for name in providers.keys() {
+            info!("sso provider configured: {}", name);
+        }
+
+        Ok(Self {
+            providers,
+            jit_provisioning: config.jit_provisioning,
+        })
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.providers.keys().copied().collect();
+        names.sort();
+        names
+    }
+
+    pub fn get(&self, provider: &str) -> Result<&SsoProvider, WebappError> {
+        self.providers
+            .get(provider)
+            .ok_or(WebappError::MissingOauthClientError)
+    }
+}
+
+// the callback only accepts the state and nonce made here
+async fn authorize_url(
+    state: &AppState,
+    provider: &str,
+    link: bool,
     jar: PrivateCookieJar,
-) -> Result<(PrivateCookieJar, impl IntoResponse), WebappError> {
-    let client = get_oauth_client(&provider).await?;
+) -> Result<(PrivateCookieJar, Url), WebappError> {
+    let client = state.sso.get(provider)?.client().await?;
 
-    let (authorize_url, _csrf_state, _nonce) = client
+    let (authorize_url, csrf_state, nonce) = client
         .authorize_url(
             AuthenticationFlow::<CoreResponseType>::AuthorizationCode,
             CsrfToken::new_random,
@@ -89,64 +182,174 @@ async fn get_sso_login(
         .add_scope(Scope::new("email".to_string()))
         .url();
 
+    let pending = PendingSso {
+        provider: provider.to_string(),
+        link,
+        csrf_state: csrf_state.secret().clone(),
+        nonce: nonce.secret().clone(),
+    };
+    let jar = jar.add(Cookie::build((PENDING_COOKIE, pending.cookie_value())).path("/"));
+
+    Ok((jar, authorize_url))
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_sso_login(
+    Path(provider): Path<String>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    jar: PrivateCookieJar,
+) -> Result<(PrivateCookieJar, impl IntoResponse), WebappError> {
+    let (jar, authorize_url) = authorize_url(&state, &provider, false, jar)
+        .await
+        .inspect_err(|e| record_sso_error(&state, &provider, e))?;
+
     // persist next_url in cookie for sso flow
-    let next_url = handlers::get_next_url_from_headers(headers);
+    let next_url = handlers::get_next_url_from_headers(&headers);
     let updated_jar = jar.add(Cookie::build(("next_url", next_url)).path("/"));
 
     Ok((updated_jar, Redirect::to(authorize_url.as_str())))
 }
 
 #[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct CallbackParams {
-    code: String,
-    state: String,
+pub struct LinkPayload {
+    csrf_token: String,
 }
 
-fn always_verify_nonce(_nonce: Option<&Nonce>) -> Result<(), String> {
-    Ok(())
-}
-
-async fn get_sso_callback(
-    Query(params): Query<CallbackParams>,
+// link another provider to the logged in user, posted from the profile page
+#[tracing::instrument(skip_all)]
+pub async fn post_sso_link(
+    CurrentUser(_user): CurrentUser,
+    Extension(session): Extension<UserSession>,
     Path(provider): Path<String>,
     State(state): State<AppState>,
     jar: PrivateCookieJar,
-) -> Result<(PrivateCookieJar, axum::http::Response<axum::body::Body>), WebappError> {
-    let client = get_oauth_client(&provider).await?;
+    Form(payload): Form<LinkPayload>,
+) -> Result<(PrivateCookieJar, Response), WebappError> {
+    if !verify_csrf_token(&jar, &payload.csrf_token) {
+        return Err(WebappError::CsrfError);
+    }
+    // an impersonating admin must not link their own account to the user
+    if session.impersonator_id.is_some() {
+        return Ok((jar, Redirect::to("/profile").into_response()));
+    }
 
-    let http_client = reqwest::ClientBuilder::new()
-        // Following redirects opens the client up to SSRF vulnerabilities.
-        .redirect(reqwest::redirect::Policy::none())
-        .build()
-        .expect("HTTP Client should build");
+    let (jar, authorize_url) = authorize_url(&state, &provider, true, jar)
+        .await
+        .inspect_err(|e| record_sso_error(&state, &provider, e))?;
+
+    Ok((jar, Redirect::to(authorize_url.as_str()).into_response()))
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackParams {
+    code: String,
+    state: String,
+}
+
+// the provider's half of the callback
+async fn verified_claims(
+    state: &AppState,
+    provider: &str,
+    params: &CallbackParams,
+    nonce: &Nonce,
+) -> Result<CoreIdTokenClaims, WebappError> {
+    let sso_provider = state.sso.get(provider)?;
+    let client = sso_provider.client().await?;
 
     let token_response = client
         .exchange_code(AuthorizationCode::new(params.code.clone()))?
-        .request_async(&http_client)
+        .request_async(sso_provider.http_client())
         .await?;
 
     let id_token = token_response
         .id_token()
         .ok_or(WebappError::MissingIdToken)?;
 
-    let id_token_verifier = client.id_token_verifier();
-    let claims = id_token.claims(&id_token_verifier, always_verify_nonce)?;
+    let claims = match id_token.claims(&client.id_token_verifier(), nonce) {
+        Ok(claims) => claims,
+        // signing key not in our cached jwks, provider probably rotated keys
+        Err(ClaimsVerificationError::SignatureVerification(
+            SignatureVerificationError::NoMatchingKey,
+        )) => {
+            warn!("no matching {provider} signing key, refreshing jwks");
+            let client = sso_provider.refresh().await?;
+            id_token.claims(&client.id_token_verifier(), nonce)?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(claims.clone())
+}
+
+// sso_errors_total, by what failed rather than the full error
+fn record_sso_error(state: &AppState, provider: &str, error: &WebappError) {
+    // the provider comes from the path, only configured ones get their own series
+    let provider = if state.sso.get(provider).is_ok() {
+        provider
+    } else {
+        "unknown"
+    };
+    let error = match error {
+        WebappError::MissingOauthClientError => "unknown_provider",
+        WebappError::DiscoveryError(_) => "discovery",
+        WebappError::RequestTokenError(_) => "token",
+        WebappError::MissingIdToken | WebappError::ClaimsVerificationError(_) => "claims",
+        WebappError::SsoStateError => "state",
+        WebappError::ConfigurationError(_) | WebappError::ParseError(_) => "configuration",
+        _ => "other",
+    };
+    metrics().record_sso_error(provider, error);
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_sso_callback(
+    Query(params): Query<CallbackParams>,
+    Path(provider): Path<String>,
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    jar: PrivateCookieJar,
+) -> Result<(PrivateCookieJar, axum::http::Response<axum::body::Body>), WebappError> {
+    // the callback has to finish a redirect this browser started, for this provider
+    let pending = jar
+        .get(PENDING_COOKIE)
+        .and_then(|pending| PendingSso::parse(pending.value()))
+        .filter(|pending| pending.provider == provider && pending.csrf_state == params.state);
+    let jar = jar.remove(Cookie::build(PENDING_COOKIE).path("/"));
+    let Some(pending) = pending else {
+        let error = WebappError::SsoStateError;
+        record_sso_error(&state, &provider, &error);
+        return Err(error);
+    };
 
-    let email = claims.email().ok_or(WebappError::MissingEmailError)?;
-    debug!("sso login email: {email:#?}");
+    let claims = verified_claims(&state, &provider, &params, &Nonce::new(pending.nonce))
+        .await
+        .inspect_err(|e| record_sso_error(&state, &provider, e))?;
 
-    // println!("params: {:#?}", params);
-    // println!("token_response: {:#?}", token_response);
-    // println!("id_token: {:#?}", id_token);
-    // println!("claims: {:#?}", claims);
-    // println!("email: {}", email.as_str());
+    let email_trusted = match state.sso.get(&provider)?.email_trust() {
+        EmailTrust::Verified => claims.email_verified() == Some(true),
+        EmailTrust::Tenant => true,
+    };
+    let identity = SsoIdentity {
+        provider: provider.clone(),
+        subject: claims.subject().to_string(),
+        email: claims.email().map(|email| email.as_str().to_lowercase()),
+        email_trusted,
+    };
+    debug!("sso identity: {identity:#?}");
 
-    let mut conn = state.pool.clone().get()?;
+    if pending.link {
+        return link_identity(state, jar, identity).await;
+    }
 
-    let user = get_user_by_email(email, &mut conn);
+    let jit_provisioning = state.sso.jit_provisioning;
+    let user = state
+        .db(move |conn| sso_user(&identity, jit_provisioning, conn))
+        .await?;
 
     let Some(user) = user else {
+        metrics().record_login(LoginMethod::Sso, false);
         // return Err(WebappError::NoMatchingUserError);
         return Ok((
             jar,
@@ -158,7 +361,17 @@ async fn get_sso_callback(
         ));
     };
 
-    let mut updated_jar = jar.add(Cookie::build(("user", user.username)).path("/"));
+    if user.is_disabled() {
+        metrics().record_login(LoginMethod::Sso, false);
+        let mut context = tera::Context::new();
+        context.insert("alert", handlers::ACCOUNT_DISABLED);
+        return Ok((jar, handlers::render_login_with_context(state, context)?));
+    }
+
+    metrics().record_login(LoginMethod::Sso, true);
+    let mut updated_jar =
+        handlers::session::start_session(jar, &user, Some(ip.to_string()), &headers, &state)
+            .await?;
 
     if let Some(next_url) = updated_jar.get("next_url") {
         debug!("next_url: {:#?}", next_url.value_trimmed());
@@ -174,3 +387,193 @@ async fn get_sso_callback(
         Redirect::to("/").into_response().into_response(),
     ))
 }
+
+#[derive(Debug)]
+pub struct SsoIdentity {
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    // the provider's policy allows this email to claim an account, see EmailTrust
+    pub email_trusted: bool,
+}
+
+impl SsoIdentity {
+    fn new_user_identity(&self, user: &User) -> NewUserIdentity {
+        NewUserIdentity {
+            user_id: user.id,
+            provider: self.provider.clone(),
+            subject: self.subject.clone(),
+            email: self.email.clone(),
+        }
+    }
+}
+
+async fn link_identity(
+    state: AppState,
+    jar: PrivateCookieJar,
+    identity: SsoIdentity,
+) -> Result<(PrivateCookieJar, Response), WebappError> {
+    let username = match jar.get("user") {
+        Some(user) => user.value().to_string(),
+        None => return Err(WebappError::NotLoggedInError),
+    };
+
+    let (user, alert) = state
+        .db(move |conn| {
+            let user =
+                get_user_by_username(&username, conn).ok_or(WebappError::NotLoggedInError)?;
+
+            let linked_user = get_user_by_identity(&identity.provider, &identity.subject, conn)?;
+            let already_linked = get_identities_for_user(&user, conn)?
+                .iter()
+                .any(|linked| linked.provider == identity.provider);
+
+            let alert = match linked_user {
+                Some(linked_user) if linked_user.id == user.id => None,
+                Some(_) => Some(format!(
+                    "This {} account is linked to another user.",
+                    identity.provider
+                )),
+                None if already_linked => Some(format!(
+                    "Unlink your current {} account first.",
+                    identity.provider
+                )),
+                None => {
+                    create_new_identity(&identity.new_user_identity(&user), conn)?;
+                    None
+                }
+            };
+            Ok((user, alert))
+        })
+        .await?;
+
+    if let Some(alert) = alert {
+        let mut context = tera::Context::new();
+        context.insert("alert", &alert);
+        let rendered = handlers::profile::render_profile(&state, &user, &jar, context).await?;
+        return Ok((jar, rendered.into_response()));
+    }
+
+    Ok((jar, Redirect::to("/profile").into_response()))
+}
+
+// the user a verified sso login belongs to, if any
+pub fn sso_user(
+    identity: &SsoIdentity,
+    jit_provisioning: bool,
+    conn: &mut PgConnection,
+) -> Result<Option<User>, WebappError> {
+    match get_user_by_identity(&identity.provider, &identity.subject, conn)? {
+        Some(user) => Ok(Some(user)),
+        None => match_or_provision_user(identity, jit_provisioning, conn),
+    }
+}
+
+// first login with this identity: match an existing user by email, or provision one
+fn match_or_provision_user(
+    identity: &SsoIdentity,
+    jit_provisioning: bool,
+    conn: &mut PgConnection,
+) -> Result<Option<User>, WebappError> {
+    // only an email the provider vouches for gets to claim an account
+    let Some(email) = identity.email.as_deref().filter(|_| identity.email_trusted) else {
+        return Ok(None);
+    };
+
+    if let Some(user) = get_user_by_email(email, conn) {
+        // user already linked a different account from this provider
+        if get_identities_for_user(&user, conn)?
+            .iter()
+            .any(|linked| linked.provider == identity.provider)
+        {
+            return Ok(None);
+        }
+        create_new_identity(&identity.new_user_identity(&user), conn)?;
+        return Ok(Some(user));
+    }
+
+    if !jit_provisioning {
+        return Ok(None);
+    }
+
+    let Ok(email_address) = EmailAddress::new(email) else {
+        return Ok(None);
+    };
+
+    let user = conn.transaction::<_, WebappError, _>(|conn| {
+        let new_user = NewUser {
+            username: provision_username(email, conn),
+            email: Some(email_address),
+            password: None,
+            hashed_password: None,
+        };
+        if new_user.validate().is_err() {
+            return Ok(None);
+        }
+        let user = create_new_user(&new_user, conn)?;
+        create_new_identity(&identity.new_user_identity(&user), conn)?;
+        info!(
+            "provisioned user {} from {} sso",
+            user.username, identity.provider
+        );
+        Ok(Some(user))
+    })?;
+
+    Ok(user)
+}
+
+// usernames are 3-10 characters, so derive one from the email local part
+fn provision_username(email: &str, conn: &mut PgConnection) -> String {
+    let mut base: String = email
+        .split('@')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .take(10)
+        .collect();
+    while base.len() < 3 {
+        base.push('0');
+    }
+
+    let mut username = base.clone();
+    while get_user_by_username(&username, conn).is_some() {
+        let suffix = rand::random_range(1000..10000).to_string();
+        username = format!("{}{}", &base[..base.len().min(6)], suffix);
+    }
+    username
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum_extra::extract::cookie::Key;
+
+    #[test]
+    fn test_pending_sso() {
+        let pending = PendingSso {
+            provider: "google".to_string(),
+            link: true,
+            csrf_state: CsrfToken::new_random().secret().clone(),
+            nonce: Nonce::new_random().secret().clone(),
+        };
+        assert_eq!(PendingSso::parse(&pending.cookie_value()), Some(pending));
+        assert_eq!(PendingSso::parse("google|maybe|state|nonce"), None);
+        assert_eq!(PendingSso::parse("google|false|state"), None);
+    }
+
+    #[test]
+    fn test_link_csrf_token() {
+        let jar = PrivateCookieJar::new(Key::generate());
+        assert!(!verify_csrf_token(&jar, ""));
+
+        let jar = jar.add(Cookie::new("session", "token"));
+        let token = handlers::session::csrf_token(&jar).unwrap();
+        assert!(verify_csrf_token(&jar, &token));
+        assert!(!verify_csrf_token(&jar, "token"));
+
+        // another session, another token
+        let other = jar.clone().add(Cookie::new("session", "other"));
+        assert!(!verify_csrf_token(&other, &token));
+    }
+}
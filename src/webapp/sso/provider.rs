@@ -0,0 +1,146 @@
+use super::OauthClient;
+use crate::webapp::WebappError;
+use openidconnect::{
+    ClientId, ClientSecret, IssuerUrl, RedirectUrl,
+    core::{CoreClient, CoreProviderMetadata},
+    reqwest,
+};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+// how long discovered metadata (and the JWKS fetched with it) is trusted before refreshing
+const METADATA_TTL: Duration = Duration::from_secs(60 * 60);
+
+// when a refresh fails, keep serving the stale client and wait this long before retrying
+const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+struct CachedClient {
+    client: OauthClient,
+    expires_at: Instant,
+}
+
+// when an email in the id token is good enough to match an existing account by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EmailTrust {
+    // only if the provider says it verified the address
+    Verified,
+    // the issuer is a single organisation's directory, whose admins manage the addresses.
+    // its tokens seldom carry email_verified, and the issuer check already ties them to the tenant
+    Tenant,
+}
+
+// one configured OIDC provider, with its discovered client cached across requests
+pub struct SsoProvider {
+    name: &'static str,
+    issuer_url: IssuerUrl,
+    client_id: ClientId,
+    client_secret: ClientSecret,
+    redirect_url: RedirectUrl,
+    http_client: reqwest::Client,
+    email_trust: EmailTrust,
+    cached: RwLock<Option<CachedClient>>,
+}
+
+impl SsoProvider {
+    pub fn new(
+        name: &'static str,
+        issuer_url: IssuerUrl,
+        client_id: ClientId,
+        client_secret: ClientSecret,
+        redirect_url: RedirectUrl,
+        http_client: reqwest::Client,
+        email_trust: EmailTrust,
+    ) -> Self {
+        Self {
+            name,
+            issuer_url,
+            client_id,
+            client_secret,
+            redirect_url,
+            http_client,
+            email_trust,
+            cached: RwLock::new(None),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.http_client
+    }
+
+    pub fn email_trust(&self) -> EmailTrust {
+        self.email_trust
+    }
+
+    // cached client if still fresh, otherwise rediscover
+    pub async fn client(&self) -> Result<OauthClient, WebappError> {
+        if let Some(cached) = self.cached.read().await.as_ref()
+            && cached.expires_at > Instant::now()
+        {
+            return Ok(cached.client.clone());
+        }
+
+        let mut cached = self.cached.write().await;
+
+        // another request may have refreshed while we waited on the lock
+        if let Some(cached) = cached.as_ref()
+            && cached.expires_at > Instant::now()
+        {
+            return Ok(cached.client.clone());
+        }
+
+        self.discover_into(&mut cached).await
+    }
+
+    // skip the ttl, e.g. when the provider has rotated its signing keys
+    pub async fn refresh(&self) -> Result<OauthClient, WebappError> {
+        let mut cached = self.cached.write().await;
+        self.discover_into(&mut cached).await
+    }
+
+    async fn discover_into(
+        &self,
+        cached: &mut Option<CachedClient>,
+    ) -> Result<OauthClient, WebappError> {
+        match self.discover().await {
+            Ok(client) => {
+                info!("refreshed {} provider metadata", self.name);
+                *cached = Some(CachedClient {
+                    client: client.clone(),
+                    expires_at: Instant::now() + METADATA_TTL,
+                });
+                Ok(client)
+            }
+            // degrade to the last known good client rather than failing the login
+            Err(e) => match cached.as_mut() {
+                Some(stale) => {
+                    warn!(
+                        "{} discovery failed, using stale metadata: {:#?}",
+                        self.name, e
+                    );
+                    stale.expires_at = Instant::now() + RETRY_AFTER;
+                    Ok(stale.client.clone())
+                }
+                None => Err(e),
+            },
+        }
+    }
+
+    async fn discover(&self) -> Result<OauthClient, WebappError> {
+        let provider_metadata =
+            CoreProviderMetadata::discover_async(self.issuer_url.clone(), &self.http_client)
+                .await?;
+        let client = CoreClient::from_provider_metadata(
+            provider_metadata,
+            self.client_id.clone(),
+            Some(self.client_secret.clone()),
+        )
+        .set_redirect_uri(self.redirect_url.clone());
+
+        Ok(client)
+    }
+}
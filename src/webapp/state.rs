@@ -1,11 +1,16 @@
+use super::{WebappError, sso::SsoProviders, throttle::LoginThrottle};
+use crate::{config::Config, db, live::LiveHub};
 use axum::extract::FromRef;
 use axum_extra::extract::cookie::Key;
 use diesel::{
     PgConnection,
     r2d2::{ConnectionManager, Pool},
 };
+use rand::distr::{Alphanumeric, SampleString};
 use std::{ops::Deref, sync::Arc};
 use tera::Tera;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
 
 // AppState shenanigans, because CookieJar
 #[derive(Clone)]
@@ -14,7 +19,61 @@ pub struct AppState(pub Arc<InnerState>);
 pub struct InnerState {
     pub tera: Tera,
     pub key: Key,
+    // only to decrypt, see cookie_keys
+    pub previous_keys: Vec<Key>,
     pub pool: Pool<ConnectionManager<PgConnection>>,
+    pub sso: SsoProviders,
+    pub login_throttle: LoginThrottle,
+    // see handlers::middleware::client_ip
+    pub trusted_proxies: usize,
+    pub live: LiveHub,
+    // cancelled on SIGTERM, the instance stops reporting ready and open event streams end
+    pub shutdown: CancellationToken,
+}
+
+impl AppState {
+    // fails only on a broken sso config
+    pub fn new(
+        config: &Config,
+        tera: Tera,
+        pool: Pool<ConnectionManager<PgConnection>>,
+        shutdown: CancellationToken,
+    ) -> Result<Self, WebappError> {
+        // production refuses to start without one, see Config::validate
+        let secret = config.cookies.secret.clone().unwrap_or_else(|| {
+            warn!("no cookie secret configured, generating one, everyone is logged out on restart");
+            Alphanumeric.sample_string(&mut rand::rng(), 64)
+        });
+
+        let key = Key::from(secret.as_bytes());
+        let previous_keys = config
+            .cookies
+            .previous_secrets
+            .iter()
+            .map(|secret| Key::from(secret.as_bytes()))
+            .collect();
+
+        Ok(AppState(Arc::new(InnerState {
+            tera,
+            key,
+            previous_keys,
+            pool,
+            sso: SsoProviders::from_config(&config.sso)?,
+            login_throttle: LoginThrottle::default(),
+            trusted_proxies: config.server.trusted_proxies,
+            live: LiveHub::default(),
+            shutdown,
+        })))
+    }
+
+    // handlers reach the database through this, see db::interact
+    pub async fn db<T, F>(&self, f: F) -> Result<T, WebappError>
+    where
+        F: FnOnce(&mut PgConnection) -> Result<T, WebappError> + Send + 'static,
+        T: Send + 'static,
+    {
+        db::interact(&self.pool, f).await
+    }
 }
 
 // automatically get to InnerState
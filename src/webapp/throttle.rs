@@ -0,0 +1,152 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// failures are forgotten after this long without another one
+const RESET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+// in-memory exponential backoff on failed logins, keyed by username or ip
+pub struct Backoff {
+    free_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+struct Failures {
+    count: u32,
+    last_failure: Instant,
+}
+
+impl Backoff {
+    pub fn new(free_attempts: u32, base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            free_attempts,
+            base_delay,
+            max_delay,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // how long the key still has to wait before its next attempt, if at all
+    pub fn retry_after(&self, key: &str) -> Option<Duration> {
+        self.retry_after_at(key, Instant::now())
+    }
+
+    pub fn record_failure(&self, key: &str) {
+        self.record_failure_at(key, Instant::now());
+    }
+
+    pub fn record_success(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+
+    fn delay(&self, count: u32) -> Duration {
+        if count < self.free_attempts {
+            return Duration::ZERO;
+        }
+        let exponent = (count - self.free_attempts).min(16);
+        self.base_delay
+            .saturating_mul(2u32.pow(exponent))
+            .min(self.max_delay)
+    }
+
+    fn retry_after_at(&self, key: &str, now: Instant) -> Option<Duration> {
+        let failures = self.failures.lock().unwrap();
+        let entry = failures.get(key)?;
+        let ready_at = entry.last_failure + self.delay(entry.count);
+        (ready_at > now).then(|| ready_at - now)
+    }
+
+    fn record_failure_at(&self, key: &str, now: Instant) {
+        let mut failures = self.failures.lock().unwrap();
+
+        // keep the map from growing without bound
+        failures.retain(|_, entry| now.duration_since(entry.last_failure) < RESET_AFTER);
+
+        let entry = failures.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            last_failure: now,
+        });
+        entry.count += 1;
+        entry.last_failure = now;
+    }
+}
+
+// per-username and per-ip limits, ips get more room since users can share one
+pub struct LoginThrottle {
+    pub usernames: Backoff,
+    pub ips: Backoff,
+}
+
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        Self {
+            usernames: Backoff::new(3, Duration::from_secs(1), Duration::from_secs(5 * 60)),
+            ips: Backoff::new(20, Duration::from_secs(1), Duration::from_secs(15 * 60)),
+        }
+    }
+}
+
+impl LoginThrottle {
+    pub fn retry_after(&self, username: &str, ip: &str) -> Option<Duration> {
+        self.usernames
+            .retry_after(&username.to_lowercase())
+            .max(self.ips.retry_after(ip))
+    }
+
+    pub fn record_failure(&self, username: &str, ip: &str) {
+        self.usernames.record_failure(&username.to_lowercase());
+        self.ips.record_failure(ip);
+    }
+
+    // only the username is cleared, an ip spraying many accounts stays throttled
+    pub fn record_success(&self, username: &str) {
+        self.usernames.record_success(&username.to_lowercase());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::new(2, Duration::from_secs(1), Duration::from_secs(10));
+        let now = Instant::now();
+
+        // free attempts don't wait
+        backoff.record_failure_at("user", now);
+        assert_eq!(backoff.retry_after_at("user", now), None);
+        backoff.record_failure_at("user", now);
+        assert_eq!(
+            backoff.retry_after_at("user", now),
+            Some(Duration::from_secs(1))
+        );
+
+        // doubles per failure, up to the max
+        backoff.record_failure_at("user", now);
+        assert_eq!(
+            backoff.retry_after_at("user", now),
+            Some(Duration::from_secs(2))
+        );
+        for _ in 0..10 {
+            backoff.record_failure_at("user", now);
+        }
+        assert_eq!(
+            backoff.retry_after_at("user", now),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            backoff.retry_after_at("user", now + Duration::from_secs(10)),
+            None
+        );
+
+        // other keys are unaffected, success clears
+        assert_eq!(backoff.retry_after_at("other", now), None);
+        backoff.record_success("user");
+        assert_eq!(backoff.retry_after_at("user", now), None);
+    }
+}
@@ -0,0 +1,263 @@
+use super::WebappError;
+use crate::db::{
+    self,
+    models::webhook::{
+        Webhook, WebhookDelivery, claim_due_deliveries, record_delivery_attempt, webhook_signature,
+    },
+};
+use chrono::Utc;
+use diesel::{
+    PgConnection,
+    r2d2::{ConnectionManager, Pool},
+};
+use openidconnect::reqwest::{
+    self,
+    dns::{Addrs, Name, Resolve, Resolving},
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
+use url::{Host, Url};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+const BATCH_SIZE: i64 = 10;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// a claimed delivery stays invisible to other workers for this long, which has to cover every
+// request in the batch timing out one after the other, plus the queries in between
+const LEASE: Duration = Duration::from_secs(REQUEST_TIMEOUT.as_secs() * BATCH_SIZE as u64 + 60);
+
+// users pick the urls, so by default nothing inside the network the server runs in is reachable
+#[derive(Clone)]
+pub struct WebhookClient {
+    client: reqwest::Client,
+    allow_private_addresses: bool,
+}
+
+// `allow_private_addresses` is for local receivers in development and tests
+pub fn webhook_client(allow_private_addresses: bool) -> WebhookClient {
+    let builder = reqwest::ClientBuilder::new()
+        // Following redirects opens the client up to SSRF vulnerabilities.
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent(concat!(
+            "axum-boilerplate-webhooks/",
+            env!("CARGO_PKG_VERSION")
+        ));
+    let builder = match allow_private_addresses {
+        true => builder,
+        false => builder.dns_resolver(Arc::new(PublicResolver)),
+    };
+    WebhookClient {
+        client: builder.build().expect("HTTP Client should build"),
+        allow_private_addresses,
+    }
+}
+
+// checks the addresses a name resolves to, the ones actually connected to, so a public name
+// pointing at a private address is refused too
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public_address(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    // 0.0.0.0/8 this network, 100.64.0.0/10 carrier grade nat, 198.18.0.0/15 benchmarking and
+    // 240.0.0.0/4 reserved, which takes in the broadcast address
+    let reserved = a == 0
+        || (a == 100 && (b & 0b1100_0000) == 64)
+        || (a == 198 && (b & 0b1111_1110) == 18)
+        || a >= 240;
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        || reserved)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // ipv4 compatible ::a.b.c.d, and the nat64 prefixes 64:ff9b::/96 and 64:ff9b:1::/48 that a
+    // translator turns into any ipv4 address, private ones included
+    let embeds_ipv4 = segments[..6] == [0; 6]
+        || segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0]
+        || segments[..3] == [0x64, 0xff9b, 1];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        || embeds_ipv4)
+}
+
+// polls the webhook_deliveries queue until shutdown, finishing the batch in hand
+pub fn spawn_webhook_worker(
+    pool: Pool<ConnectionManager<PgConnection>>,
+    allow_private_addresses: bool,
+    shutdown: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let client = webhook_client(allow_private_addresses);
+        while !shutdown.is_cancelled() {
+            match deliver_due_webhooks(&client, &pool).await {
+                // there may be more waiting
+                Ok(sent) if sent as i64 == BATCH_SIZE => continue,
+                Ok(_) => {}
+                Err(e) => error!("webhook worker: {:#?}", e),
+            }
+            tokio::select! {
+                _ = shutdown.cancelled() => {}
+                _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            }
+        }
+    })
+}
+
+// sends one batch of due deliveries, returns how many were attempted. no connection is held
+// while the requests are out
+pub async fn deliver_due_webhooks(
+    client: &WebhookClient,
+    pool: &Pool<ConnectionManager<PgConnection>>,
+) -> Result<usize, WebappError> {
+    let lease = chrono::Duration::from_std(LEASE).expect("lease fits a chrono duration");
+    let due = db::interact(pool, move |conn| {
+        Ok::<_, WebappError>(claim_due_deliveries(BATCH_SIZE, lease, conn)?)
+    })
+    .await?;
+
+    for (delivery, webhook) in &due {
+        let outcome = send_delivery(client, webhook, delivery).await;
+        let delivery = delivery.clone();
+        let delivery = db::interact(pool, move |conn| {
+            Ok::<_, WebappError>(record_delivery_attempt(&delivery, outcome, conn)?)
+        })
+        .await?;
+        match &delivery.last_error {
+            None => info!("webhook delivery {} sent to {}", delivery.id, webhook.url),
+            Some(e) => warn!(
+                "webhook delivery {} to {} failed ({}): {}",
+                delivery.id, webhook.url, delivery.status, e
+            ),
+        }
+    }
+
+    Ok(due.len())
+}
+
+async fn send_delivery(
+    client: &WebhookClient,
+    webhook: &Webhook,
+    delivery: &WebhookDelivery,
+) -> Result<u16, String> {
+    let url = Url::parse(&webhook.url).map_err(|e| e.to_string())?;
+    // addresses in the url itself never reach the resolver
+    let literal = match url.host() {
+        Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+        _ => None,
+    };
+    if !client.allow_private_addresses
+        && let Some(ip) = literal
+        && !is_public_address(ip)
+    {
+        return Err(format!("{} is not a public address", ip));
+    }
+
+    let timestamp = Utc::now().timestamp();
+    let signature = webhook_signature(&webhook.secret, timestamp, &delivery.payload);
+
+    let response = client
+        .client
+        .post(url)
+        .header("content-type", "application/json")
+        .header("x-webhook-event", delivery.event.as_str())
+        .header("x-webhook-delivery", delivery.id.to_string())
+        .header("x-webhook-timestamp", timestamp.to_string())
+        .header("x-webhook-signature", signature)
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(response.status().as_u16())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lease_outlasts_batch() {
+        assert!(LEASE > REQUEST_TIMEOUT * BATCH_SIZE as u32);
+    }
+
+    #[test]
+    fn test_is_public_address() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "0.0.0.0",
+            "0.1.2.3",
+            "100.64.0.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::93.184.216.34",
+            "64:ff9b::127.0.0.1",
+            "64:ff9b::93.184.216.34",
+            "64:ff9b:1::a00:1",
+        ] {
+            assert!(!is_public_address(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in [
+            "93.184.216.34",
+            "100.128.0.1",
+            "198.17.255.255",
+            "198.20.0.1",
+            "223.255.255.255",
+            "2606:4700::1111",
+            "::ffff:93.184.216.34",
+        ] {
+            assert!(is_public_address(ip.parse().unwrap()), "{ip}");
+        }
+    }
+}
@@ -1,12 +1,96 @@
+use axum::{
+    Router,
+    body::Body,
+    extract::{Request, connect_info::MockConnectInfo},
+    http::{
+        HeaderMap, Method, StatusCode,
+        header::{AUTHORIZATION, CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE},
+    },
+    response::IntoResponse,
+    routing::post,
+};
 use axum_boilerplate::db::models::{
-    EmailAddress, Goal, NewGoal, NewUser, User,
-    goal::{GoalContext, GoalForm, create_new_goal},
-    user::{create_new_user, hash_password, verify_password},
+    EmailAddress, Goal, NewGoal, NewUser, NewUserIdentity, Role, User,
+    api_token::{
+        TokenScope, create_api_token, delete_api_token, get_api_tokens_for_user,
+        get_user_by_api_token, touch_api_token,
+    },
+    audit::{AuditAction, get_audit_log, record_audit},
+    goal::{GoalContext, GoalForm, create_new_goal, set_goal_completed},
+    identity::{
+        create_new_identity, delete_identity, get_identities_for_user, get_user_by_identity,
+    },
+    notification::{
+        NewNotification, NotificationKind, count_unread, get_notifications_for_user, mark_all_read,
+        mark_read, notify,
+    },
+    reminder::{
+        EVERY_DAY, ReminderKind, ReminderSchedule, create_reminder, delete_reminder,
+        get_reminders_for_goal, reschedule_reminders,
+    },
+    session::{
+        create_impersonation_session, create_session, delete_session_by_token,
+        delete_sessions_for_user, get_session_by_token, get_sessions_for_user,
+    },
+    totp::{
+        disable_totp, enable_totp, generate_totp_secret, totp_for, use_recovery_code,
+        use_totp_code, verify_totp_code,
+    },
+    user::{
+        create_new_user, delete_user, get_user_by_username, get_users_with_role, hash_password,
+        record_failed_login, require_password_reset, reset_failed_logins, search_users,
+        set_password, set_user_disabled, set_user_role, verify_password,
+    },
+    webhook::{
+        DeliveryStatus, MAX_ATTEMPTS, WebhookDelivery, WebhookEvent, create_webhook,
+        delete_webhook, enqueue_webhook_event, get_deliveries_for_webhook, retry_delivery,
+        webhook_signature,
+    },
+};
+use axum_boilerplate::{
+    config::{Config, DatabaseConfig},
+    db::{
+        get_connection_pool, has_pending_migrations, interact,
+        models::job::{JobStatus, claim_next_job, get_jobs, retry_job},
+        schema::{goal_reminders, goals, jobs, users, webhook_deliveries},
+    },
+    jobs::{
+        JobContext, JobDefinition, JobError, JobRegistry, enqueue, registry, reminders,
+        spawn_job_workers,
+    },
+    live::{LiveEvent, LiveHub, Topic, publish, spawn_listener},
+    mailer::{Email, LogMailer, MailError, Mailer},
+    webapp::{
+        self,
+        sso::{SsoIdentity, sso_user},
+        state::AppState,
+        webhooks,
+    },
 };
+use axum_extra::extract::{PrivateCookieJar, cookie::Cookie};
+use chrono::{Duration, NaiveDate, Utc};
 use database::run_migrations;
-use diesel::prelude::*;
+use diesel::{
+    prelude::*,
+    r2d2::{ConnectionManager, Pool},
+};
 use dotenvy::dotenv;
-use std::env;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU16, AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+use tera::Tera;
+use tokio_util::sync::CancellationToken;
+use tower::ServiceExt;
 use validator::{Validate, ValidateArgs};
 
 mod database;
@@ -17,6 +101,7 @@ fn get_user_01() -> NewUser {
     NewUser {
         username: "test-01".to_string(),
         email: Some(email_address),
+        password: Some("blahblahblah".to_string()),
         hashed_password: Some(hashed_password),
     }
 }
@@ -27,6 +112,7 @@ fn get_goal_01(user_id: i32) -> NewGoal {
         description: "Goal-01 Description".to_string(),
         notes: Some("Goal-01 notes".to_string()),
         user_id,
+        target_date: None,
     }
 }
 
@@ -35,6 +121,7 @@ fn get_goal_02_form() -> GoalForm {
         title: "Goal-02".to_string(),
         description: "Goal-02 Description".to_string(),
         notes: Some("Goal-02 notes".to_string()),
+        target_date: NaiveDate::from_ymd_opt(2030, 1, 31),
     }
 }
 
@@ -50,24 +137,50 @@ fn test_db_ops() {
     let mut conn = db.conn();
 
     run_migrations(&mut conn);
+    assert!(!has_pending_migrations(&mut conn).unwrap());
 
     let user = test_user(&mut conn);
     let goal = test_goal(&mut conn, &user);
     test_user_goal(&mut conn, &user, &goal);
     test_goal_form(&mut conn, &user);
+    test_user_identity(&mut conn, &user);
+    test_sso_existing_user(&mut conn, &user);
+    test_totp(&mut conn, &user);
+    test_login_lockout(&mut conn, &user);
+    test_user_role(&mut conn, &user);
+    test_user_sessions(&mut conn, &user);
+    test_admin_user_ops(&mut conn, &db_url, &user);
+    test_impersonation(&mut conn, &db_url, &user);
+    test_api_tokens(&mut conn, &user);
+    test_api_goals(&mut conn, &db_url, &user);
+    test_webhooks(&mut conn, &db_url, &user, &goal);
+    test_jobs(&mut conn, &db_url, &goal);
+    test_reminders(&mut conn, &db_url, &user, &goal);
+    test_notifications(&mut conn, &user, &goal);
+    test_live_events(&mut conn, &db_url, &user);
+    test_concurrent_requests(&mut conn, &db_url);
+    test_statement_timeout(&db_url);
 }
 
 fn test_user(conn: &mut diesel::PgConnection) -> User {
     println!("testing user");
     let new_user = get_user_01();
+    assert!(new_user.validate().is_ok());
+
+    // password policy is part of NewUser validation
+    let weak_user = NewUser {
+        password: Some("short".to_string()),
+        ..get_user_01()
+    };
+    assert!(weak_user.validate().is_err());
+
     let user = create_new_user(&new_user, conn)
         .unwrap_or_else(|err| panic!("error creating new user: {}", err));
 
     assert_eq!(user.username, new_user.username);
     assert_eq!(user.email, new_user.email);
     assert_eq!(user.hashed_password, new_user.hashed_password);
-    assert_eq!(
-        true,
+    assert!(
         verify_password(
             "blahblahblah",
             new_user.hashed_password.as_ref().unwrap().as_str()
@@ -98,7 +211,7 @@ fn test_user_goal(conn: &mut diesel::PgConnection, user: &User, goal: &Goal) {
         .load(conn)
         .unwrap();
 
-    assert_eq!(true, goals.contains(goal));
+    assert!(goals.contains(goal));
 }
 
 fn test_goal_form(conn: &mut PgConnection, user: &User) {
@@ -117,6 +230,7 @@ fn test_goal_form(conn: &mut PgConnection, user: &User) {
         description: goal_form.description,
         notes: goal_form.notes,
         user_id: user.id,
+        target_date: goal_form.target_date,
     };
     let goal = create_new_goal(&new_goal, conn)
         .unwrap_or_else(|err| panic!("error creating new goal: {}", err));
@@ -125,3 +239,1291 @@ fn test_goal_form(conn: &mut PgConnection, user: &User) {
     assert_eq!(goal.notes, new_goal.notes);
     assert_eq!(goal.user_id, new_goal.user_id);
 }
+
+fn test_user_identity(conn: &mut PgConnection, user: &User) {
+    println!("testing user identity");
+
+    let new_identity = NewUserIdentity {
+        user_id: user.id,
+        provider: "google".to_string(),
+        subject: "google-subject-01".to_string(),
+        email: Some("test-01@test.com".to_string()),
+    };
+    let identity = create_new_identity(&new_identity, conn)
+        .unwrap_or_else(|err| panic!("error creating new identity: {}", err));
+    assert_eq!(identity.provider, new_identity.provider);
+    assert_eq!(identity.subject, new_identity.subject);
+
+    // lookup is by (provider, subject), not email
+    let found = get_user_by_identity("google", "google-subject-01", conn).unwrap();
+    assert_eq!(found.as_ref(), Some(user));
+    let not_found = get_user_by_identity("microsoft", "google-subject-01", conn).unwrap();
+    assert_eq!(not_found, None);
+
+    // the same provider account can't be linked twice
+    assert!(create_new_identity(&new_identity, conn).is_err());
+
+    let identity_id = identity.id;
+    assert_eq!(get_identities_for_user(user, conn).unwrap(), vec![identity]);
+    assert_eq!(delete_identity(identity_id, user, conn).unwrap(), 1);
+    assert!(get_identities_for_user(user, conn).unwrap().is_empty());
+}
+
+// users from before user_identities existed have no identity yet and are matched by email
+fn test_sso_existing_user(conn: &mut PgConnection, user: &User) {
+    println!("testing sso login of an existing user");
+
+    assert!(get_identities_for_user(user, conn).unwrap().is_empty());
+
+    // an unverified google address can't claim the account
+    let google = SsoIdentity {
+        provider: "google".to_string(),
+        subject: "google-subject-02".to_string(),
+        email: Some("test-01@test.com".to_string()),
+        email_trusted: false,
+    };
+    assert_eq!(sso_user(&google, true, conn).unwrap(), None);
+    assert!(get_identities_for_user(user, conn).unwrap().is_empty());
+
+    // a single-tenant microsoft token usually has no email_verified, its directory is trusted
+    let microsoft = SsoIdentity {
+        provider: "microsoft".to_string(),
+        subject: "microsoft-subject-01".to_string(),
+        email: Some("test-01@test.com".to_string()),
+        email_trusted: true,
+    };
+    assert_eq!(
+        sso_user(&microsoft, false, conn).unwrap().as_ref(),
+        Some(user)
+    );
+    let identities = get_identities_for_user(user, conn).unwrap();
+    assert_eq!(identities.len(), 1);
+    assert_eq!(identities[0].subject, "microsoft-subject-01");
+
+    // from then on the identity is used, whatever the email says
+    let renamed = SsoIdentity {
+        email: Some("renamed@test.com".to_string()),
+        ..microsoft
+    };
+    assert_eq!(
+        sso_user(&renamed, false, conn).unwrap().as_ref(),
+        Some(user)
+    );
+
+    for identity in identities {
+        delete_identity(identity.id, user, conn).unwrap();
+    }
+}
+
+fn test_totp(conn: &mut PgConnection, user: &User) {
+    println!("testing totp");
+
+    let secret = generate_totp_secret();
+    let code = totp_for(&secret, &user.username)
+        .unwrap()
+        .generate_current()
+        .unwrap();
+    assert!(verify_totp_code(&secret, &user.username, &code).unwrap());
+    assert!(!verify_totp_code(&secret, &user.username, "000000x").unwrap());
+
+    let recovery_codes = enable_totp(user, &secret, conn).unwrap();
+    let enabled = get_user_by_username(&user.username, conn).unwrap();
+    assert_eq!(enabled.totp_secret, Some(secret));
+
+    // a code is accepted once, then replaying it is rejected
+    assert!(use_totp_code(&enabled, &code, conn).unwrap());
+    assert!(!use_totp_code(&enabled, &code, conn).unwrap());
+
+    // recovery codes work once, regardless of case and dashes
+    let recovery_code = recovery_codes[0].to_uppercase().replace('-', "");
+    assert!(use_recovery_code(user, &recovery_code, conn).unwrap());
+    assert!(!use_recovery_code(user, &recovery_codes[0], conn).unwrap());
+    assert!(!use_recovery_code(user, "not-a-code", conn).unwrap());
+
+    disable_totp(user, conn).unwrap();
+    let disabled = get_user_by_username(&user.username, conn).unwrap();
+    assert_eq!(disabled.totp_secret, None);
+    assert!(!use_recovery_code(user, &recovery_codes[1], conn).unwrap());
+}
+
+fn test_login_lockout(conn: &mut PgConnection, user: &User) {
+    println!("testing login lockout");
+
+    let mut locked = get_user_by_username(&user.username, conn).unwrap();
+    for _ in 0..9 {
+        locked = record_failed_login(&locked, conn).unwrap();
+        assert!(!locked.is_locked());
+    }
+    locked = record_failed_login(&locked, conn).unwrap();
+    assert_eq!(locked.failed_login_count, 10);
+    assert!(locked.is_locked());
+
+    reset_failed_logins(&locked, conn).unwrap();
+    let unlocked = get_user_by_username(&user.username, conn).unwrap();
+    assert_eq!(unlocked.failed_login_count, 0);
+    assert!(!unlocked.is_locked());
+
+    // the count is incremented in the database, not from a possibly stale copy
+    record_failed_login(&unlocked, conn).unwrap();
+    let counted = record_failed_login(&unlocked, conn).unwrap();
+    assert_eq!(counted.failed_login_count, 2);
+
+    // once a lock has run out the next failure starts the count over instead of locking again
+    let expired = diesel::update(users::table.find(user.id))
+        .set((
+            users::failed_login_count.eq(10),
+            users::locked_until.eq(Some(Utc::now() - Duration::minutes(1))),
+        ))
+        .returning(User::as_returning())
+        .get_result::<User>(conn)
+        .unwrap();
+    assert!(!expired.is_locked());
+    let restarted = record_failed_login(&expired, conn).unwrap();
+    assert_eq!(restarted.failed_login_count, 1);
+    assert!(!restarted.is_locked());
+    reset_failed_logins(&restarted, conn).unwrap();
+}
+
+fn test_user_role(conn: &mut PgConnection, user: &User) {
+    println!("testing user role");
+
+    assert_eq!(user.role, Role::User);
+
+    let admin = set_user_role(user, Role::Admin, conn).unwrap();
+    assert_eq!(admin.role, Role::Admin);
+    let admins = get_users_with_role(Role::Admin, conn).unwrap();
+    assert!(admins.iter().any(|admin| admin.id == user.id));
+
+    let demoted = set_user_role(&admin, Role::User, conn).unwrap();
+    assert_eq!(demoted.role, Role::User);
+}
+
+fn test_user_sessions(conn: &mut PgConnection, user: &User) {
+    println!("testing user sessions");
+
+    let (session, token) = create_session(user, Some("127.0.0.1".to_string()), None, conn).unwrap();
+    assert_ne!(session.token_hash, token);
+    let (found, found_user) = get_session_by_token(&token, conn).unwrap().unwrap();
+    assert_eq!(found.id, session.id);
+    assert_eq!(found_user.id, user.id);
+    assert!(get_session_by_token("not a token", conn).unwrap().is_none());
+
+    let (other, _) = create_session(user, None, None, conn).unwrap();
+    assert_eq!(get_sessions_for_user(user, conn).unwrap().len(), 2);
+    assert_eq!(
+        delete_sessions_for_user(user, Some(session.id), conn).unwrap(),
+        1
+    );
+    let sessions = get_sessions_for_user(user, conn).unwrap();
+    assert!(sessions.iter().all(|remaining| remaining.id != other.id));
+
+    assert_eq!(delete_session_by_token(&token, conn).unwrap(), 1);
+    assert!(get_sessions_for_user(user, conn).unwrap().is_empty());
+}
+
+fn test_admin_user_ops(conn: &mut PgConnection, db_url: &str, user: &User) {
+    println!("testing admin user ops");
+
+    let (found, total) = search_users(Some("TEST-0"), 1, 20, conn).unwrap();
+    assert_eq!(total, 1);
+    assert_eq!(found[0].id, user.id);
+    // like wildcards are matched literally
+    let (_, total) = search_users(Some("%"), 1, 20, conn).unwrap();
+    assert_eq!(total, 0);
+    let (_, total) = search_users(None, 1, 20, conn).unwrap();
+    assert_eq!(total, 1);
+
+    // disabling and forcing a reset both sign the user out
+    create_session(user, None, None, conn).unwrap();
+    let disabled = set_user_disabled(user, true, conn).unwrap();
+    assert!(disabled.is_disabled());
+    assert!(get_sessions_for_user(user, conn).unwrap().is_empty());
+    let enabled = set_user_disabled(&disabled, false, conn).unwrap();
+    assert!(!enabled.is_disabled());
+
+    create_session(user, None, None, conn).unwrap();
+    let reset = require_password_reset(user, conn).unwrap();
+    assert!(reset.password_reset_required);
+    assert!(get_sessions_for_user(user, conn).unwrap().is_empty());
+    let hashed_password = hash_password("new password".to_string()).unwrap();
+    let reset = set_password(&reset, &hashed_password, conn).unwrap();
+    assert!(!reset.password_reset_required);
+
+    // goals don't cascade, delete_user takes care of them
+    let other = create_new_user(
+        &NewUser {
+            username: "test-02".to_string(),
+            email: None,
+            password: None,
+            hashed_password: None,
+        },
+        conn,
+    )
+    .unwrap();
+    create_new_goal(&get_goal_01(other.id), conn).unwrap();
+
+    // admin forms are refused without the session's csrf token
+    let admin = set_user_role(user, Role::Admin, conn).unwrap();
+    let (_, token) = create_session(&admin, None, None, conn).unwrap();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (state, router) = test_router(db_url);
+    let cookies = session_cookies(&state, &admin, &token);
+    let uri = format!("/admin/users/{}/disable", other.id);
+    let response = runtime
+        .block_on(router.clone().oneshot(form_post(&uri, &cookies, "")))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let form = "csrf_token=not-the-token";
+    let response = runtime
+        .block_on(router.clone().oneshot(form_post(&uri, &cookies, form)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(!get_user_by_username("test-02", conn).unwrap().is_disabled());
+    let form = format!("csrf_token={}", csrf_token(&token));
+    let response = runtime
+        .block_on(router.clone().oneshot(form_post(&uri, &cookies, &form)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    assert!(get_user_by_username("test-02", conn).unwrap().is_disabled());
+    delete_sessions_for_user(&admin, None, conn).unwrap();
+    let user = set_user_role(&admin, Role::User, conn).unwrap();
+
+    // without the permissions the admin pages are forbidden
+    let (_, token) = create_session(&user, None, None, conn).unwrap();
+    let cookies = session_cookies(&state, &user, &token);
+    for uri in ["/admin", "/admin/users"] {
+        let request = Request::get(uri)
+            .header(COOKIE, &cookies)
+            .body(Body::empty())
+            .unwrap();
+        let response = runtime.block_on(router.clone().oneshot(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+    delete_sessions_for_user(&user, None, conn).unwrap();
+
+    delete_user(&other, conn).unwrap();
+    assert!(get_user_by_username("test-02", conn).is_none());
+}
+
+// the whole app over a small pool, requests go through oneshot instead of a listener
+fn test_router(db_url: &str) -> (AppState, Router) {
+    let config = Config {
+        database: DatabaseConfig {
+            url: db_url.to_string(),
+            pool_size: 4,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let tera = Tera::new(&config.server.templates).unwrap();
+    let pool = get_connection_pool(&config.database);
+    let state = AppState::new(&config, tera, pool, CancellationToken::new()).unwrap();
+    let router = webapp::router(&config, state.clone())
+        .layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+    (state, router)
+}
+
+// the encrypted cookies a browser sends for this session
+fn session_cookies(state: &AppState, user: &User, token: &str) -> String {
+    let jar = PrivateCookieJar::new(state.key.clone())
+        .add(Cookie::new("user", user.username.clone()))
+        .add(Cookie::new("session", token.to_string()));
+    jar.into_response()
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok()?.split(';').next().map(str::to_string))
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+// what the session's forms carry in their hidden csrf_token field
+fn csrf_token(token: &str) -> String {
+    format!(
+        "{:x}",
+        Sha256::new()
+            .chain_update("csrf:")
+            .chain_update(token)
+            .finalize()
+    )
+}
+
+fn form_post(uri: &str, cookies: &str, form: &str) -> Request {
+    Request::post(uri)
+        .header(COOKIE, cookies)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .body(Body::from(form.to_string()))
+        .unwrap()
+}
+
+fn test_impersonation(conn: &mut PgConnection, db_url: &str, admin: &User) {
+    println!("testing impersonation");
+
+    let user = create_new_user(
+        &NewUser {
+            username: "test-03".to_string(),
+            email: None,
+            password: None,
+            hashed_password: None,
+        },
+        conn,
+    )
+    .unwrap();
+
+    let (session, token) = create_impersonation_session(&user, admin, None, None, conn).unwrap();
+    assert_eq!(session.impersonator_id, Some(admin.id));
+    let (found, found_user) = get_session_by_token(&token, conn).unwrap().unwrap();
+    assert_eq!(found.impersonator_id, Some(admin.id));
+    assert_eq!(found_user.id, user.id);
+
+    record_audit(
+        admin,
+        AuditAction::ImpersonationStart,
+        Some(&user),
+        None,
+        conn,
+    )
+    .unwrap();
+    record_audit(
+        admin,
+        AuditAction::ImpersonationStop,
+        Some(&user),
+        None,
+        conn,
+    )
+    .unwrap();
+    let audit_log = get_audit_log(10, conn).unwrap();
+    assert_eq!(audit_log[0].action, "impersonation_stop");
+    assert_eq!(audit_log[1].action, "impersonation_start");
+
+    // the impersonating admin can't mint api tokens for the user, the user can
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (state, router) = test_router(db_url);
+    let form = format!("name=script&scope=write&csrf_token={}", csrf_token(&token));
+    let cookies = session_cookies(&state, &user, &token);
+    let response = runtime
+        .block_on(
+            router
+                .clone()
+                .oneshot(form_post("/profile/tokens", &cookies, &form)),
+        )
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(get_api_tokens_for_user(&user, conn).unwrap().is_empty());
+
+    // the user's own session still needs its own csrf token
+    let (_, own_token) = create_session(&user, None, None, conn).unwrap();
+    let cookies = session_cookies(&state, &user, &own_token);
+    let response = runtime
+        .block_on(
+            router
+                .clone()
+                .oneshot(form_post("/profile/tokens", &cookies, &form)),
+        )
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(get_api_tokens_for_user(&user, conn).unwrap().is_empty());
+
+    let form = format!(
+        "name=script&scope=write&csrf_token={}",
+        csrf_token(&own_token)
+    );
+    let response = runtime
+        .block_on(router.oneshot(form_post("/profile/tokens", &cookies, &form)))
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(get_api_tokens_for_user(&user, conn).unwrap().len(), 1);
+
+    // entries outlive the user, sessions don't
+    delete_user(&user, conn).unwrap();
+    assert!(get_session_by_token(&token, conn).unwrap().is_none());
+    let audit_log = get_audit_log(10, conn).unwrap();
+    assert_eq!(audit_log[0].target_id, None);
+    assert_eq!(audit_log[0].target_username.as_deref(), Some("test-03"));
+}
+
+fn test_api_tokens(conn: &mut PgConnection, user: &User) {
+    println!("testing api tokens");
+
+    let (api_token, token) =
+        create_api_token(user, "script", TokenScope::Read, None, conn).unwrap();
+    assert!(token.starts_with("abp_"));
+    assert_ne!(api_token.token_hash, token);
+    assert!(api_token.last_used_at.is_none());
+
+    let (found, found_user) = get_user_by_api_token(&token, conn).unwrap().unwrap();
+    assert_eq!(found.id, api_token.id);
+    assert_eq!(found.scope, TokenScope::Read);
+    assert_eq!(found_user.id, user.id);
+    assert!(get_user_by_api_token("abp_nope", conn).unwrap().is_none());
+
+    touch_api_token(&found, conn).unwrap();
+    let (touched, _) = get_user_by_api_token(&token, conn).unwrap().unwrap();
+    assert!(touched.last_used_at.is_some());
+
+    let expired_at = Utc::now() - Duration::days(1);
+    let (_, expired) =
+        create_api_token(user, "old", TokenScope::Write, Some(expired_at), conn).unwrap();
+    assert!(get_user_by_api_token(&expired, conn).unwrap().is_none());
+    assert_eq!(get_api_tokens_for_user(user, conn).unwrap().len(), 2);
+
+    assert_eq!(delete_api_token(api_token.id, user, conn).unwrap(), 1);
+    assert!(get_user_by_api_token(&token, conn).unwrap().is_none());
+}
+
+// a json request with a bearer token, answers with the status and the parsed body
+fn api_request(
+    runtime: &tokio::runtime::Runtime,
+    router: &Router,
+    token: &str,
+    method: Method,
+    uri: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .header(CONTENT_TYPE, "application/json")
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .unwrap();
+    runtime.block_on(async {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (
+            status,
+            serde_json::from_slice(&bytes).unwrap_or(Value::Null),
+        )
+    })
+}
+
+fn test_api_goals(conn: &mut PgConnection, db_url: &str, user: &User) {
+    println!("testing api goals");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let (_, router) = test_router(db_url);
+    let (_, token) = create_api_token(user, "api", TokenScope::Write, None, conn).unwrap();
+    let (_, read_token) = create_api_token(user, "api-read", TokenScope::Read, None, conn).unwrap();
+
+    // create
+    let request = Request::post("/api/v1/goals")
+        .header(AUTHORIZATION, format!("Bearer {}", token))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(
+            json!({"title": "Api-01", "description": "from the api", "notes": "50% there"})
+                .to_string(),
+        ))
+        .unwrap();
+    let response = runtime.block_on(router.clone().oneshot(request)).unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let location = response.headers()[LOCATION].to_str().unwrap().to_string();
+    let (status, created) = api_request(&runtime, &router, &token, Method::GET, &location, None);
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(created["title"], "Api-01");
+    assert_eq!(created["notes"], "50% there");
+    let id = created["id"].as_i64().unwrap();
+
+    let (status, second) = api_request(
+        &runtime,
+        &router,
+        &token,
+        Method::POST,
+        "/api/v1/goals",
+        Some(json!({"title": "Api-02", "description": "also from the api"})),
+    );
+    assert_eq!(status, StatusCode::CREATED);
+    let second_id = second["id"].as_i64().unwrap();
+
+    // titles are unique per user, same as the form
+    let (status, error) = api_request(
+        &runtime,
+        &router,
+        &token,
+        Method::POST,
+        "/api/v1/goals",
+        Some(json!({"title": "Api-01", "description": "again"})),
+    );
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(error["error"], "validation_failed");
+
+    // read tokens can list but not write
+    let (status, _) = api_request(
+        &runtime,
+        &router,
+        &read_token,
+        Method::POST,
+        "/api/v1/goals",
+        Some(json!({"title": "Api-03", "description": "nope"})),
+    );
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // search is case insensitive and takes % and _ literally
+    let search = |query: &str| {
+        let uri = format!("/api/v1/goals?{}", query);
+        let (status, list) = api_request(&runtime, &router, &read_token, Method::GET, &uri, None);
+        assert_eq!(status, StatusCode::OK);
+        let titles: Vec<String> = list["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|goal| goal["title"].as_str().unwrap().to_string())
+            .collect();
+        (list["total"].as_i64().unwrap(), titles)
+    };
+    assert_eq!(
+        search("q=api-"),
+        (2, vec!["Api-01".into(), "Api-02".into()])
+    );
+    assert_eq!(search("q=50%25"), (1, vec!["Api-01".into()]));
+    assert_eq!(search("q=_").0, 0);
+    assert_eq!(search("q=api-&has_notes=false"), (1, vec!["Api-02".into()]));
+    assert_eq!(
+        search("q=api-&limit=1&offset=1"),
+        (2, vec!["Api-02".into()])
+    );
+
+    // update, a null clears the notes and missing fields stay
+    let uri = format!("/api/v1/goals/{}", id);
+    let (status, updated) = api_request(
+        &runtime,
+        &router,
+        &token,
+        Method::PATCH,
+        &uri,
+        Some(json!({"description": "changed", "notes": null})),
+    );
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(updated["title"], "Api-01");
+    assert_eq!(updated["description"], "changed");
+    assert_eq!(updated["notes"], Value::Null);
+
+    // delete
+    for id in [id, second_id] {
+        let uri = format!("/api/v1/goals/{}", id);
+        let (status, _) = api_request(&runtime, &router, &token, Method::DELETE, &uri, None);
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, error) = api_request(&runtime, &router, &token, Method::GET, &uri, None);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error["error"], "not_found");
+    }
+    assert_eq!(search("q=api-").0, 0);
+
+    // the docs page only loads the swagger ui vendored under static/
+    let page = runtime.block_on(async {
+        let request = Request::get("/api/docs").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    });
+    assert!(!page.contains("https://"));
+    for asset in ["swagger-ui-bundle.js", "swagger-ui.css"] {
+        let uri = format!("/static/swagger-ui/{}", asset);
+        assert!(page.contains(&uri));
+        let request = Request::get(uri.as_str()).body(Body::empty()).unwrap();
+        let response = runtime.block_on(router.clone().oneshot(request)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+}
+
+// a local stand-in for the receiving end, answers with whatever `status` is set to
+fn test_webhooks(conn: &mut PgConnection, db_url: &str, user: &User, goal: &Goal) {
+    println!("testing webhooks");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(ConnectionManager::<PgConnection>::new(db_url))
+        .unwrap();
+    let received: Arc<Mutex<Vec<(HeaderMap, String)>>> = Arc::default();
+    let status = Arc::new(AtomicU16::new(500));
+    let listener = runtime
+        .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+        .unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let app = Router::new().route(
+        "/hook",
+        post({
+            let received = received.clone();
+            let status = status.clone();
+            move |headers: HeaderMap, body: String| async move {
+                received.lock().unwrap().push((headers, body));
+                StatusCode::from_u16(status.load(Ordering::SeqCst)).unwrap()
+            }
+        }),
+    );
+    runtime.spawn(async move { axum::serve(listener, app).await.unwrap() });
+    // the receiver is on loopback, which needs the opt-in
+    let client = webhooks::webhook_client(true);
+    let deliver = || {
+        runtime
+            .block_on(webhooks::deliver_due_webhooks(&client, &pool))
+            .unwrap()
+    };
+    let make_due = |delivery: &WebhookDelivery, conn: &mut PgConnection| {
+        diesel::update(delivery)
+            .set(webhook_deliveries::next_attempt_at.eq(Utc::now()))
+            .execute(conn)
+            .unwrap();
+    };
+
+    let (webhook, secret) = create_webhook(user, &url, conn).unwrap();
+    assert!(secret.starts_with("whsec_"));
+    assert_eq!(
+        enqueue_webhook_event(user.id, WebhookEvent::GoalUpdated, goal, conn).unwrap(),
+        1
+    );
+
+    // the first attempt fails and is pushed back
+    assert_eq!(deliver(), 1);
+    let delivery = get_deliveries_for_webhook(&webhook, 10, conn)
+        .unwrap()
+        .remove(0);
+    assert_eq!(delivery.status, DeliveryStatus::Pending);
+    assert_eq!(delivery.attempts, 1);
+    assert_eq!(delivery.response_status, Some(500));
+    assert!(delivery.next_attempt_at > Utc::now());
+    assert_eq!(deliver(), 0);
+
+    status.store(200, Ordering::SeqCst);
+    make_due(&delivery, conn);
+    assert_eq!(deliver(), 1);
+    let delivery = get_deliveries_for_webhook(&webhook, 10, conn)
+        .unwrap()
+        .remove(0);
+    assert_eq!(delivery.status, DeliveryStatus::Delivered);
+    assert_eq!(delivery.attempts, 2);
+    assert!(delivery.delivered_at.is_some());
+
+    // same body both times, signed with the webhook's secret
+    let (headers, body) = received.lock().unwrap().pop().unwrap();
+    let header = |name: &str| headers.get(name).unwrap().to_str().unwrap().to_string();
+    let timestamp: i64 = header("x-webhook-timestamp").parse().unwrap();
+    assert_eq!(body, delivery.payload);
+    assert_eq!(header("x-webhook-event"), "goal.updated");
+    assert_eq!(header("x-webhook-delivery"), delivery.id.to_string());
+    assert_eq!(
+        header("x-webhook-signature"),
+        webhook_signature(&secret, timestamp, &body)
+    );
+    assert!(body.contains(&format!("\"title\":\"{}\"", goal.title)));
+    assert_eq!(retry_delivery(delivery.id, &webhook, conn).unwrap(), 0);
+
+    // out of attempts, then retried by hand
+    status.store(410, Ordering::SeqCst);
+    enqueue_webhook_event(user.id, WebhookEvent::GoalDeleted, goal, conn).unwrap();
+    let delivery = get_deliveries_for_webhook(&webhook, 10, conn)
+        .unwrap()
+        .remove(0);
+    diesel::update(&delivery)
+        .set(webhook_deliveries::attempts.eq(MAX_ATTEMPTS - 1))
+        .execute(conn)
+        .unwrap();
+    assert_eq!(deliver(), 1);
+    let delivery = get_deliveries_for_webhook(&webhook, 10, conn)
+        .unwrap()
+        .remove(0);
+    assert_eq!(delivery.status, DeliveryStatus::Failed);
+    assert_eq!(delivery.last_error.as_deref(), Some("HTTP 410"));
+    assert_eq!(retry_delivery(delivery.id, &webhook, conn).unwrap(), 1);
+    let delivery = get_deliveries_for_webhook(&webhook, 10, conn)
+        .unwrap()
+        .remove(0);
+    assert_eq!(delivery.status, DeliveryStatus::Pending);
+    assert_eq!(delivery.attempts, 0);
+
+    // goal.completed goes out when the goal becomes done, not when it already was
+    let (_, router) = test_router(db_url);
+    let (_, token) = create_api_token(user, "hooks", TokenScope::Write, None, conn).unwrap();
+    let uri = format!("/api/v1/goals/{}", goal.id);
+    for _ in 0..2 {
+        let body = Some(json!({"completed": true}));
+        let (status, updated) = api_request(&runtime, &router, &token, Method::PATCH, &uri, body);
+        assert_eq!(status, StatusCode::OK);
+        assert!(updated["completed_at"].is_string());
+    }
+    let events: Vec<WebhookEvent> = get_deliveries_for_webhook(&webhook, 10, conn)
+        .unwrap()
+        .into_iter()
+        .map(|delivery| delivery.event)
+        .collect();
+    assert_eq!(
+        events[..3],
+        [
+            WebhookEvent::GoalUpdated,
+            WebhookEvent::GoalCompleted,
+            WebhookEvent::GoalUpdated
+        ]
+    );
+    assert_eq!(
+        events
+            .iter()
+            .filter(|event| **event == WebhookEvent::GoalCompleted)
+            .count(),
+        1
+    );
+    let reopened = set_goal_completed(goal, false, conn).unwrap().unwrap();
+    assert_eq!(reopened.completed_at, None);
+    assert!(set_goal_completed(goal, false, conn).unwrap().is_none());
+
+    assert_eq!(delete_webhook(webhook.id, user, conn).unwrap(), 1);
+    assert_eq!(deliver(), 0);
+
+    // without it neither a private address nor a name resolving to one is reached
+    let public_only = webhooks::webhook_client(false);
+    let localhost_url = url.replace("127.0.0.1", "localhost");
+    for url in [&url, &localhost_url] {
+        let (webhook, _) = create_webhook(user, url, conn).unwrap();
+        enqueue_webhook_event(user.id, WebhookEvent::GoalUpdated, goal, conn).unwrap();
+        let received_before = received.lock().unwrap().len();
+        let sent = runtime
+            .block_on(webhooks::deliver_due_webhooks(&public_only, &pool))
+            .unwrap();
+        assert_eq!(sent, 1);
+        let delivery = get_deliveries_for_webhook(&webhook, 10, conn)
+            .unwrap()
+            .remove(0);
+        assert_eq!(delivery.response_status, None);
+        assert!(delivery.last_error.is_some());
+        assert_eq!(received.lock().unwrap().len(), received_before);
+        assert_eq!(delete_webhook(webhook.id, user, conn).unwrap(), 1);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SetGoalNotes {
+    goal_id: i32,
+    notes: String,
+}
+
+impl JobDefinition for SetGoalNotes {
+    const KIND: &'static str = "set_goal_notes";
+
+    async fn run(self, context: JobContext) -> Result<(), JobError> {
+        let mut conn = context.pool.get()?;
+        diesel::update(goals::table.find(self.goal_id))
+            .set(goals::notes.eq(self.notes))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct AlwaysFails {
+    panic: bool,
+}
+
+impl JobDefinition for AlwaysFails {
+    const KIND: &'static str = "always_fails";
+    const MAX_ATTEMPTS: i32 = 2;
+
+    async fn run(self, _context: JobContext) -> Result<(), JobError> {
+        if self.panic {
+            panic!("boom");
+        }
+        Err(JobError::Failed("nope".to_string()))
+    }
+}
+
+fn test_jobs(conn: &mut PgConnection, db_url: &str, goal: &Goal) {
+    println!("testing jobs");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let pool = Pool::builder()
+        .max_size(2)
+        .build(ConnectionManager::<PgConnection>::new(db_url))
+        .unwrap();
+    let context = JobContext {
+        pool: pool.clone(),
+        mailer: Arc::new(LogMailer),
+    };
+    let registry = JobRegistry::default()
+        .register::<SetGoalNotes>()
+        .register::<AlwaysFails>();
+    let run_next = || runtime.block_on(registry.run_next_job(&context)).unwrap();
+    let make_due = |conn: &mut PgConnection| {
+        diesel::update(jobs::table)
+            .set(jobs::run_at.eq(Utc::now()))
+            .execute(conn)
+            .unwrap();
+    };
+    assert!(!run_next());
+
+    let notes = SetGoalNotes {
+        goal_id: goal.id,
+        notes: "from a job".to_string(),
+    };
+    let job = enqueue(&notes, conn).unwrap();
+    assert_eq!(job.kind, "set_goal_notes");
+    assert_eq!(job.status, JobStatus::Pending);
+    assert!(run_next());
+    let notes = goals::table
+        .find(goal.id)
+        .select(goals::notes)
+        .first::<Option<String>>(conn)
+        .unwrap();
+    assert_eq!(notes.as_deref(), Some("from a job"));
+    let done = get_jobs(Some(JobStatus::Done), 10, conn).unwrap();
+    assert_eq!(done.len(), 1);
+    assert!(done[0].finished_at.is_some());
+
+    // retried with backoff, then dead
+    let job = enqueue(&AlwaysFails { panic: false }, conn).unwrap();
+    assert_eq!(job.max_attempts, 2);
+    assert!(run_next());
+    assert!(!run_next());
+    make_due(conn);
+    assert!(run_next());
+    let dead = get_jobs(Some(JobStatus::Dead), 10, conn).unwrap();
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].attempts, 2);
+    assert_eq!(dead[0].last_error.as_deref(), Some("nope"));
+    assert_eq!(retry_job(done[0].id, conn).unwrap(), 0);
+    assert_eq!(retry_job(job.id, conn).unwrap(), 1);
+    let retried = get_jobs(Some(JobStatus::Pending), 10, conn).unwrap();
+    assert_eq!(retried[0].attempts, 0);
+
+    // a row locked by another worker is skipped, not waited on
+    conn.transaction(|conn| {
+        let claimed = claim_next_job(chrono::Duration::minutes(10), conn)?.unwrap();
+        assert_eq!(claimed.id, job.id);
+        assert!(claim_next_job(chrono::Duration::minutes(10), &mut pool.get().unwrap())?.is_none());
+        // rolled back, as if that worker never got to it
+        Err::<(), _>(diesel::result::Error::RollbackTransaction)
+    })
+    .unwrap_err();
+
+    // running past its lease means the worker died
+    diesel::update(jobs::table.find(job.id))
+        .set((
+            jobs::status.eq(JobStatus::Running),
+            jobs::locked_at.eq(Utc::now() - Duration::hours(1)),
+        ))
+        .execute(conn)
+        .unwrap();
+    let reclaimed = claim_next_job(chrono::Duration::minutes(10), conn)
+        .unwrap()
+        .unwrap();
+    assert_eq!(reclaimed.id, job.id);
+    assert_eq!(reclaimed.attempts, 1);
+    diesel::delete(jobs::table.find(job.id))
+        .execute(conn)
+        .unwrap();
+
+    // a panic fails the job, not the worker
+    enqueue(&AlwaysFails { panic: true }, conn).unwrap();
+    assert!(run_next());
+    let failed = get_jobs(Some(JobStatus::Pending), 10, conn).unwrap();
+    assert!(
+        failed[0]
+            .last_error
+            .as_deref()
+            .unwrap()
+            .contains("panicked")
+    );
+
+    assert!(matches!(
+        registry.enqueue_json("nope", serde_json::json!({}), conn),
+        Err(JobError::UnknownKindError(_))
+    ));
+    assert!(matches!(
+        registry.enqueue_json("set_goal_notes", serde_json::json!({"goal_id": 1}), conn),
+        Err(JobError::PayloadError(_))
+    ));
+
+    // workers stop polling once shutdown starts
+    let shutdown = CancellationToken::new();
+    runtime.block_on(async {
+        let workers = spawn_job_workers(Arc::new(registry), context, 2, shutdown.clone());
+        shutdown.cancel();
+        tokio::time::timeout(std::time::Duration::from_secs(10), join_all(workers))
+            .await
+            .unwrap();
+    });
+}
+
+#[derive(Default)]
+struct RecordingMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl Mailer for RecordingMailer {
+    fn send(&self, email: &Email) -> Result<(), MailError> {
+        self.sent.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+fn test_reminders(conn: &mut PgConnection, db_url: &str, user: &User, goal: &Goal) {
+    println!("testing reminders");
+
+    // only reminder jobs from here on
+    diesel::delete(jobs::table).execute(conn).unwrap();
+    let user = get_user_by_username(&user.username, conn).unwrap();
+    assert!(!user.is_disabled());
+    let address = user.email.clone().expect("test user has an email");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let pool = Pool::builder()
+        .max_size(2)
+        .build(ConnectionManager::<PgConnection>::new(db_url))
+        .unwrap();
+    let mailer = Arc::new(RecordingMailer::default());
+    let context = JobContext {
+        pool,
+        mailer: mailer.clone(),
+    };
+    let registry = registry();
+
+    let daily = ReminderSchedule {
+        kind: ReminderKind::Weekly,
+        weekdays: EVERY_DAY,
+        days_before: 0,
+        time_of_day: "09:00:00".parse().unwrap(),
+    };
+    let reminder = create_reminder(goal, &user, daily, conn).unwrap();
+    let next_run_at = reminder.next_run_at.unwrap();
+    assert!(next_run_at > Utc::now() && next_run_at <= Utc::now() + Duration::days(1));
+
+    // nothing due yet
+    assert_eq!(reminders::schedule_due_reminders(conn).unwrap(), 0);
+
+    diesel::update(&reminder)
+        .set(goal_reminders::next_run_at.eq(Some(Utc::now() - Duration::minutes(1))))
+        .execute(conn)
+        .unwrap();
+    assert_eq!(reminders::schedule_due_reminders(conn).unwrap(), 1);
+    // moved on, so the next tick doesn't queue it twice
+    assert_eq!(reminders::schedule_due_reminders(conn).unwrap(), 0);
+    let queued = get_jobs(Some(JobStatus::Pending), 10, conn).unwrap();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].kind, "send_goal_reminder");
+
+    assert!(runtime.block_on(registry.run_next_job(&context)).unwrap());
+    let sent = mailer.sent.lock().unwrap().clone();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].to, address.as_ref());
+    assert_eq!(sent[0].subject, format!("Reminder: {}", goal.title));
+    let reminders = get_reminders_for_goal(goal, conn).unwrap();
+    assert!(reminders[0].last_sent_at.is_some());
+    assert!(reminders[0].next_run_at.unwrap() > Utc::now());
+
+    // fires once a target date is set, and not again after it passed
+    let before_target = ReminderSchedule {
+        kind: ReminderKind::BeforeTarget,
+        weekdays: 0,
+        days_before: 3,
+        time_of_day: "08:00:00".parse().unwrap(),
+    };
+    let goal = diesel::update(goals::table.find(goal.id))
+        .set(goals::target_date.eq(None::<NaiveDate>))
+        .returning(Goal::as_returning())
+        .get_result(conn)
+        .unwrap();
+    let reminder = create_reminder(&goal, &user, before_target, conn).unwrap();
+    assert_eq!(reminder.next_run_at, None);
+    let target_date = (Utc::now() + Duration::days(10)).date_naive();
+    let goal = diesel::update(goals::table.find(goal.id))
+        .set(goals::target_date.eq(Some(target_date)))
+        .returning(Goal::as_returning())
+        .get_result(conn)
+        .unwrap();
+    reschedule_reminders(&goal, &user, conn).unwrap();
+    let rescheduled = get_reminders_for_goal(&goal, conn).unwrap();
+    let next_run_at = rescheduled[1].next_run_at.unwrap();
+    assert_eq!(next_run_at.date_naive(), target_date - Duration::days(3));
+
+    assert_eq!(delete_reminder(reminder.id, &goal, conn).unwrap(), 1);
+    assert_eq!(delete_reminder(reminder.id, &goal, conn).unwrap(), 0);
+}
+
+fn test_notifications(conn: &mut PgConnection, user: &User, goal: &Goal) {
+    println!("testing notifications");
+
+    // the reminder sent in test_reminders
+    let notifications = get_notifications_for_user(user, 10, conn).unwrap();
+    assert_eq!(notifications.len(), 1);
+    assert_eq!(notifications[0].kind, NotificationKind::Reminder);
+    assert_eq!(notifications[0].title, format!("Reminder: {}", goal.title));
+    assert_eq!(count_unread(user, conn).unwrap(), 1);
+
+    let mention = notify(
+        &NewNotification {
+            user_id: user.id,
+            kind: NotificationKind::Mention,
+            title: "You were mentioned",
+            body: "",
+            link: Some("/goals"),
+        },
+        conn,
+    )
+    .unwrap();
+    assert_eq!(count_unread(user, conn).unwrap(), 2);
+    // newest first
+    let notifications = get_notifications_for_user(user, 10, conn).unwrap();
+    assert_eq!(notifications[0].id, mention.id);
+
+    let read = mark_read(mention.id, user, conn).unwrap().unwrap();
+    let read_at = read.read_at.unwrap();
+    let again = mark_read(mention.id, user, conn).unwrap().unwrap();
+    assert_eq!(again.read_at, Some(read_at));
+    assert_eq!(count_unread(user, conn).unwrap(), 1);
+
+    // someone else's can't be marked
+    let other = create_new_user(
+        &NewUser {
+            username: "test-05".to_string(),
+            email: None,
+            password: None,
+            hashed_password: None,
+        },
+        conn,
+    )
+    .unwrap();
+    assert!(mark_read(mention.id, &other, conn).unwrap().is_none());
+    assert_eq!(mark_all_read(&other, conn).unwrap(), 0);
+
+    assert_eq!(mark_all_read(user, conn).unwrap(), 1);
+    assert_eq!(count_unread(user, conn).unwrap(), 0);
+    delete_user(&other, conn).unwrap();
+}
+
+fn test_live_events(conn: &mut PgConnection, db_url: &str, user: &User) {
+    println!("testing live events");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let hub = LiveHub::default();
+    let mut receiver = hub.subscribe();
+    let listener = spawn_listener(db_url.to_string(), &hub);
+    let mut next_event = |wait_ms| {
+        runtime.block_on(async {
+            tokio::time::timeout(std::time::Duration::from_millis(wait_ms), receiver.recv())
+                .await
+                .ok()
+                .map(|event| event.unwrap())
+        })
+    };
+
+    // the listener may not have run LISTEN yet, keep publishing until one gets through
+    let listening = (0..20).any(|_| {
+        publish(user.id, Topic::Goals, conn).unwrap();
+        next_event(500).is_some()
+    });
+    assert!(listening);
+    while next_event(500).is_some() {}
+
+    // nothing is sent for a transaction that rolls back
+    conn.transaction(|conn| {
+        publish(user.id, Topic::Goals, conn)?;
+        Err::<(), _>(diesel::result::Error::RollbackTransaction)
+    })
+    .unwrap_err();
+    publish(user.id, Topic::Notifications, conn).unwrap();
+    assert_eq!(
+        next_event(5000),
+        Some(LiveEvent {
+            user_id: user.id,
+            topic: Topic::Notifications,
+        })
+    );
+
+    // the listener lets go of its connection once nothing can hear it
+    drop(hub);
+    listener.join().unwrap();
+}
+
+// slow queries on a single threaded runtime, the way a busy worker sees them. run inline they
+// go one at a time and nothing else gets the thread, through interact they overlap and the
+// runtime stays free
+// the login form waits on a row lock that the test holds, while other requests keep being served
+fn test_concurrent_requests(conn: &mut PgConnection, db_url: &str) {
+    println!("testing concurrent requests");
+
+    const SLOW_REQUESTS: usize = 4;
+
+    let user = create_new_user(
+        &NewUser {
+            username: "test-load".to_string(),
+            email: None,
+            password: None,
+            hashed_password: Some(hash_password("blahblahblah".to_string()).unwrap()),
+        },
+        conn,
+    )
+    .unwrap();
+
+    // one runtime thread, so a handler that blocked it would hold up every other request
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (_, router) = test_router(db_url);
+
+    // a failed login counts the failure on the user's row, which waits until the lock is gone
+    let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let locker = std::thread::spawn({
+        let db_url = db_url.to_string();
+        let user_id = user.id;
+        move || {
+            let mut conn = PgConnection::establish(&db_url).unwrap();
+            conn.transaction(|conn| {
+                users::table
+                    .find(user_id)
+                    .select(users::id)
+                    .for_update()
+                    .get_result::<i32>(conn)?;
+                locked_tx.send(()).unwrap();
+                // let go eventually, so a regression fails instead of hanging
+                let _ = release_rx.recv_timeout(std::time::Duration::from_secs(10));
+                Ok::<_, diesel::result::Error>(())
+            })
+            .unwrap();
+        }
+    });
+    locked_rx.recv().unwrap();
+
+    runtime.block_on(async {
+        let slow: Vec<_> = (0..SLOW_REQUESTS)
+            .map(|_| {
+                let request = form_post("/login", "", "username=test-load&password=wrong");
+                tokio::spawn(router.clone().oneshot(request))
+            })
+            .collect();
+
+        // wait until every one of them is stuck in the database at the same time
+        let mut waiting = || {
+            diesel::select(diesel::dsl::sql::<diesel::sql_types::BigInt>(
+                "(SELECT count(*) FROM pg_stat_activity \
+                 WHERE datname = current_database() AND wait_event_type = 'Lock')",
+            ))
+            .get_result::<i64>(conn)
+            .unwrap()
+        };
+        let mut all_waiting = false;
+        for _ in 0..100 {
+            if waiting() == SLOW_REQUESTS as i64 {
+                all_waiting = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        assert!(all_waiting);
+
+        let started = Instant::now();
+        let response = router
+            .clone()
+            .oneshot(Request::get("/healthz").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+
+        release_tx.send(()).unwrap();
+        for response in join_all(slow).await {
+            assert_eq!(response.unwrap().unwrap().status(), StatusCode::OK);
+        }
+    });
+    locker.join().unwrap();
+
+    let user = get_user_by_username("test-load", conn).unwrap();
+    assert_eq!(user.failed_login_count, SLOW_REQUESTS as i32);
+    delete_user(&user, conn).unwrap();
+}
+
+fn test_statement_timeout(db_url: &str) {
+    println!("testing statement timeout");
+
+    // a query past the statement timeout is cancelled by postgres
+    let pool = get_connection_pool(&DatabaseConfig {
+        url: db_url.to_string(),
+        pool_size: 1,
+        connection_timeout: 5,
+        statement_timeout: 1,
+    });
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let cancelled = runtime.block_on(interact(&pool, |conn| {
+        diesel::sql_query("SELECT pg_sleep(2)").execute(conn)?;
+        Ok::<_, JobError>(())
+    }));
+    assert!(matches!(cancelled, Err(JobError::DieselResultError(_))));
+}
+
+// compares queries run inline on a single threaded runtime with the same queries through
+// interact, timings vary too much between machines to assert on, so this only prints them.
+// cargo test --test mod -- --ignored bench_interact --nocapture
+#[test]
+#[ignore]
+fn bench_interact() {
+    dotenv().unwrap();
+
+    const QUERIES: usize = 4;
+    const SLEEP: &str = "SELECT pg_sleep(0.5)";
+
+    let db_url = env::var("DATABASE_URL").expect("DATABASE_URL env var needed");
+    let pool = get_connection_pool(&DatabaseConfig {
+        url: db_url,
+        pool_size: QUERIES as u32,
+        connection_timeout: 5,
+        statement_timeout: 0,
+    });
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    // counts how often the runtime got round to it while the queries ran
+    fn under_load<F: Future<Output = ()>>(
+        runtime: &tokio::runtime::Runtime,
+        queries: Vec<F>,
+    ) -> (std::time::Duration, usize) {
+        runtime.block_on(async {
+            let ticks = Arc::new(AtomicUsize::new(0));
+            let ticker = tokio::spawn({
+                let ticks = ticks.clone();
+                async move {
+                    loop {
+                        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                        ticks.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            });
+            tokio::task::yield_now().await;
+            let started = Instant::now();
+            join_all(queries).await;
+            let elapsed = started.elapsed();
+            ticker.abort();
+            (elapsed, ticks.load(Ordering::SeqCst))
+        })
+    }
+
+    let blocking = (0..QUERIES)
+        .map(|_| async {
+            diesel::sql_query(SLEEP)
+                .execute(&mut pool.get().unwrap())
+                .unwrap();
+        })
+        .collect();
+    let (blocking_elapsed, blocking_ticks) = under_load(&runtime, blocking);
+
+    let interacting = (0..QUERIES)
+        .map(|_| async {
+            interact(&pool, |conn| {
+                diesel::sql_query(SLEEP).execute(conn)?;
+                Ok::<_, JobError>(())
+            })
+            .await
+            .unwrap();
+        })
+        .collect();
+    let (elapsed, ticks) = under_load(&runtime, interacting);
+    println!(
+        "{QUERIES} queries inline: {blocking_elapsed:?}, {blocking_ticks} ticks; \
+         through interact: {elapsed:?}, {ticks} ticks"
+    );
+}